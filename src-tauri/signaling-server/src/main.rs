@@ -0,0 +1,473 @@
+// Reference signaling server for linkmouse. Relays the WebRTC offer, answer
+// and candidates between a desktop and a browser that share the same link id.
+//
+// Protocol, all messages are JSON text frames:
+//   client -> server {"operation": "INITIALIZE", "id": "desktop_<id>"}  register
+//   client -> server {"operation": "CLOSE", "id": "-"}                  unregister and close
+//   client -> server {"recipient": "browser_<id>", "content": "..."}    relay
+//   server -> client <content>                                          relayed content as is
+//   server -> client {"key": "iceServers", "value": "[...]"}            after INITIALIZE
+//
+// A desktop can only send to the browser with the same id and the other way
+// around. An id is taken while its connection is open, a second INITIALIZE
+// for it is refused, so knowing the link id is not enough to take over a
+// connected desktop. Connections that do not initialize in time, or stop
+// answering pings, are closed and free their id.
+//
+// TLS is not handled here, put the server behind a reverse proxy for wss://.
+
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::{interval, Duration, Instant};
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+
+const DEFAULT_ICE_SERVERS: &str = r#"[{"urls": "stun:stun.l.google.com:19302"}]"#;
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+const PING_AFTER: Duration = Duration::from_secs(30); // Ping when nothing has been received for this long
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Options {
+    host: String,
+    port: u16,
+    ice_servers: String, // Normalized JSON array, sent as the iceServers value
+    idle_timeout: Duration,
+    desktop_prefix: String,
+    browser_prefix: String,
+}
+
+// The desktop expects every field to be present
+#[derive(Serialize, Deserialize)]
+struct IceServer {
+    urls: String,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    credential: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ClientMessage {
+    Operation { operation: String, id: String },
+    Relay { recipient: String, content: String },
+}
+
+type Clients = Arc<Mutex<HashMap<String, UnboundedSender<Message>>>>;
+
+fn parse_ice_servers(value: &str) -> Result<String, String> {
+    match serde_json::from_str::<Vec<IceServer>>(value) {
+        Ok(ice_servers) => Ok(serde_json::to_string(&ice_servers).unwrap()),
+        Err(e) => Err(format!("Invalid ICE servers: {}", e)),
+    }
+}
+
+// For the log, without the TURN usernames and credentials
+fn ice_server_urls(ice_servers: &str) -> String {
+    let ice_servers: Vec<IceServer> = serde_json::from_str(ice_servers).unwrap_or_default();
+    ice_servers.iter().map(|ice_server| ice_server.urls.as_str()).collect::<Vec<&str>>().join(", ")
+}
+
+// Each option can be given as "--name value", "--name=value" or with the
+// environment variable, the flag wins
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut values: HashMap<String, String> = HashMap::new();
+    for (name, env_name) in [
+        ("host", "LINKMOUSE_SIGNALING_HOST"),
+        ("port", "LINKMOUSE_SIGNALING_PORT"),
+        ("ice-servers", "LINKMOUSE_ICE_SERVERS"),
+        ("idle-timeout", "LINKMOUSE_IDLE_TIMEOUT"),
+        ("desktop-prefix", "LINKMOUSE_DESKTOP_PREFIX"),
+        ("browser-prefix", "LINKMOUSE_BROWSER_PREFIX"),
+    ] {
+        if let Ok(value) = env::var(env_name) {
+            values.insert(name.to_string(), value);
+        }
+    }
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => return Err(format!("Unexpected argument: {}", arg)),
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => match args.next() {
+                Some(value) => (flag.to_string(), value.clone()),
+                None => return Err(format!("Missing value for --{}", flag)),
+            },
+        };
+        values.insert(name, value);
+    }
+
+    let mut options = Options {
+        host: "0.0.0.0".to_string(),
+        port: 3001,
+        ice_servers: parse_ice_servers(DEFAULT_ICE_SERVERS)?,
+        idle_timeout: Duration::from_secs(90),
+        desktop_prefix: "desktop_".to_string(),
+        browser_prefix: "browser_".to_string(),
+    };
+    for (name, value) in values {
+        match name.as_str() {
+            "host" => options.host = value,
+            "port" => options.port = value.parse().map_err(|_| format!("Invalid port: {}", value))?,
+            "ice-servers" => options.ice_servers = parse_ice_servers(&value)?,
+            "idle-timeout" => match value.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => options.idle_timeout = Duration::from_secs(seconds),
+                _ => return Err(format!("Invalid idle timeout: {}", value)),
+            },
+            "desktop-prefix" => options.desktop_prefix = value,
+            "browser-prefix" => options.browser_prefix = value,
+            _ => return Err(format!("Unknown option: --{}", name)),
+        }
+    }
+    if options.desktop_prefix.is_empty() || options.browser_prefix.is_empty() || options.desktop_prefix == options.browser_prefix {
+        return Err("The desktop and browser prefixes must be non-empty and different".to_string());
+    }
+    Ok(options)
+}
+
+// desktop_<id> <-> browser_<id>, None if the id has neither prefix
+fn counterpart(id: &str, options: &Options) -> Option<String> {
+    if let Some(rest) = id.strip_prefix(&options.desktop_prefix) {
+        if !rest.is_empty() {
+            return Some(format!("{}{}", options.browser_prefix, rest));
+        }
+    }
+    if let Some(rest) = id.strip_prefix(&options.browser_prefix) {
+        if !rest.is_empty() {
+            return Some(format!("{}{}", options.desktop_prefix, rest));
+        }
+    }
+    None
+}
+
+// Returns false if the connection should be closed
+fn handle_text(text: &str, id: &mut Option<String>, tx: &UnboundedSender<Message>, clients: &Clients, options: &Options) -> bool {
+    let message: ClientMessage = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(e) => {
+            println!("Invalid message from {:?}: {}", id, e);
+            return true;
+        },
+    };
+
+    match message {
+        ClientMessage::Operation { operation, id: new_id } if operation == "INITIALIZE" => {
+            if id.is_some() {
+                println!("{:?} tried to initialize again", id);
+                return true;
+            }
+            if counterpart(&new_id, options).is_none() {
+                println!("Rejected id: {}", new_id);
+                return false;
+            }
+
+            {
+                let mut clients = clients.lock().unwrap();
+                // A closed connection that has not been removed yet can be replaced
+                if clients.get(&new_id).is_some_and(|registered| !registered.is_closed()) {
+                    println!("Refused {}, it is already connected", new_id);
+                    return false;
+                }
+                clients.insert(new_id.clone(), tx.clone());
+            }
+            println!("Initialized {}", new_id);
+            *id = Some(new_id);
+
+            let ice_servers = json!({
+                "key": "iceServers",
+                "value": options.ice_servers,
+            });
+            let _ = tx.send(Message::Text(ice_servers.to_string()));
+            true
+        },
+        ClientMessage::Operation { operation, .. } if operation == "CLOSE" => false,
+        ClientMessage::Operation { operation, .. } => {
+            println!("Unknown operation from {:?}: {}", id, operation);
+            true
+        },
+        ClientMessage::Relay { recipient, content } => {
+            let sender = match id {
+                Some(sender) => sender,
+                None => {
+                    println!("Relay before INITIALIZE, dropped");
+                    return true;
+                },
+            };
+            if counterpart(sender, options).as_deref() != Some(recipient.as_str()) {
+                println!("{} is not allowed to send to {}", sender, recipient);
+                return true;
+            }
+
+            let recipient_tx = clients.lock().unwrap().get(&recipient).cloned();
+            match recipient_tx {
+                Some(recipient_tx) => {
+                    let _ = recipient_tx.send(Message::Text(content));
+                },
+                None => println!("{} is not connected, dropped message from {}", recipient, sender),
+            }
+            true
+        },
+    }
+}
+
+async fn handle_connection(stream: TcpStream, addr: SocketAddr, clients: Clients, options: Arc<Options>) {
+    let websocket = match accept_async(stream).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            println!("Websocket handshake with {} failed: {}", addr, e);
+            return;
+        },
+    };
+    let (mut write, mut read) = websocket.split();
+
+    // Other connections relay through this channel
+    let (tx, mut rx) = unbounded_channel::<Message>();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            let is_close = matches!(message, Message::Close(_));
+            if write.send(message).await.is_err() || is_close {
+                break;
+            }
+        }
+        let _ = write.close().await;
+    });
+
+    let mut id: Option<String> = None;
+    let mut last_received = Instant::now();
+    let mut check = interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            message = read.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    _ => break,
+                };
+                last_received = Instant::now();
+                match message {
+                    Message::Text(text) if !handle_text(&text, &mut id, &tx, &clients, &options) => break,
+                    Message::Close(_) => break,
+                    _ => (), // Pings are answered by tungstenite
+                }
+            }
+            _ = check.tick() => {
+                let timeout = match id {
+                    Some(_) => options.idle_timeout,
+                    None => INITIALIZE_TIMEOUT,
+                };
+                if last_received.elapsed() > timeout {
+                    println!("Closing idle connection {} ({:?})", addr, id);
+                    break;
+                }
+                if last_received.elapsed() > PING_AFTER {
+                    let _ = tx.send(Message::Ping(vec![]));
+                }
+            }
+        }
+    }
+
+    if let Some(id) = &id {
+        let mut clients = clients.lock().unwrap();
+        // Might have been replaced by a newer connection already
+        if clients.get(id).is_some_and(|registered| registered.same_channel(&tx)) {
+            clients.remove(id);
+        }
+        println!("Closed {}", id);
+    }
+    let _ = tx.send(Message::Close(None));
+    drop(tx);
+    let _ = writer.await;
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        },
+    };
+
+    let address = format!("{}:{}", options.host, options.port);
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Could not listen on {}: {}", address, e);
+            std::process::exit(1);
+        },
+    };
+    println!("Listening on ws://{}", address);
+    println!("ICE servers: {}", ice_server_urls(&options.ice_servers));
+
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    tokio::spawn(handle_connection(stream, addr, clients.clone(), options.clone()));
+                },
+                Err(e) => println!("Could not accept: {}", e),
+            },
+            _ = tokio::signal::ctrl_c() => {
+                println!("Stopping");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    fn options() -> Options {
+        parse_options(&[]).unwrap()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    struct Client {
+        id: Option<String>,
+        tx: UnboundedSender<Message>,
+        rx: UnboundedReceiver<Message>,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            let (tx, rx) = unbounded_channel();
+            Client { id: None, tx, rx }
+        }
+
+        fn send(&mut self, text: &str, clients: &Clients, options: &Options) -> bool {
+            handle_text(text, &mut self.id, &self.tx, clients, options)
+        }
+
+        fn received(&mut self) -> Vec<Message> {
+            let mut messages = vec![];
+            while let Ok(message) = self.rx.try_recv() {
+                messages.push(message);
+            }
+            messages
+        }
+    }
+
+    fn initialize(id: &str) -> String {
+        json!({ "operation": "INITIALIZE", "id": id }).to_string()
+    }
+
+    fn relay(recipient: &str, content: &str) -> String {
+        json!({ "recipient": recipient, "content": content }).to_string()
+    }
+
+    #[test]
+    fn parses_flags() {
+        let options = parse_options(&args(&["--port", "4000", "--desktop-prefix=d-", "--browser-prefix", "b-", "--idle-timeout=5"])).unwrap();
+        assert_eq!(options.port, 4000);
+        assert_eq!(options.desktop_prefix, "d-");
+        assert_eq!(options.browser_prefix, "b-");
+        assert_eq!(options.idle_timeout, Duration::from_secs(5));
+
+        for invalid in [
+            &["--port", "http"][..],
+            &["--port"],
+            &["port=1"],
+            &["--idle-timeout=0"],
+            &["--colour=red"],
+            &["--browser-prefix=desktop_"],
+            &["--desktop-prefix="],
+            &["--ice-servers", "[{\"url\": \"stun:example.com\"}]"],
+        ] {
+            assert!(parse_options(&args(invalid)).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn logs_only_the_ice_server_urls() {
+        let options = parse_options(&args(&["--ice-servers", r#"[{"urls": "turn:example.com", "username": "u", "credential": "secret"}]"#])).unwrap();
+        assert!(options.ice_servers.contains("secret"));
+        assert_eq!(ice_server_urls(&options.ice_servers), "turn:example.com");
+    }
+
+    #[test]
+    fn routes_by_prefix() {
+        let options = options();
+        assert_eq!(counterpart("desktop_abc", &options).as_deref(), Some("browser_abc"));
+        assert_eq!(counterpart("browser_abc", &options).as_deref(), Some("desktop_abc"));
+        assert_eq!(counterpart("desktop_", &options), None);
+        assert_eq!(counterpart("phone_abc", &options), None);
+
+        let options = parse_options(&args(&["--desktop-prefix=d-", "--browser-prefix=b-"])).unwrap();
+        assert_eq!(counterpart("d-abc", &options).as_deref(), Some("b-abc"));
+        assert_eq!(counterpart("desktop_abc", &options), None);
+    }
+
+    #[test]
+    fn relays_between_the_pair_only() {
+        let (clients, options): (Clients, Options) = (Arc::new(Mutex::new(HashMap::new())), options());
+        let mut desktop = Client::new();
+        let mut browser = Client::new();
+        let mut other_browser = Client::new();
+
+        // Nothing is relayed before INITIALIZE
+        assert!(browser.send(&relay("desktop_a", "early"), &clients, &options));
+        assert!(browser.send(&initialize("browser_a"), &clients, &options));
+        assert!(desktop.send(&initialize("desktop_a"), &clients, &options));
+        assert!(other_browser.send(&initialize("browser_b"), &clients, &options));
+        for client in [&mut desktop, &mut browser, &mut other_browser] {
+            match client.received().as_slice() {
+                [Message::Text(text)] => assert!(text.contains("iceServers"), "{}", text),
+                other => panic!("{:?}", other),
+            }
+        }
+
+        assert!(browser.send(&relay("desktop_a", "offer"), &clients, &options));
+        assert_eq!(desktop.received(), vec![Message::Text("offer".to_string())]);
+
+        // Not its counterpart
+        assert!(other_browser.send(&relay("desktop_a", "offer"), &clients, &options));
+        assert!(desktop.send(&relay("browser_b", "answer"), &clients, &options));
+        assert_eq!(desktop.received(), vec![]);
+        assert_eq!(other_browser.received(), vec![]);
+    }
+
+    #[test]
+    fn refuses_an_id_that_is_connected() {
+        let (clients, options): (Clients, Options) = (Arc::new(Mutex::new(HashMap::new())), options());
+        let mut desktop = Client::new();
+        let mut impostor = Client::new();
+        let mut browser = Client::new();
+        assert!(desktop.send(&initialize("desktop_a"), &clients, &options));
+        assert!(browser.send(&initialize("browser_a"), &clients, &options));
+        desktop.received();
+
+        assert!(!impostor.send(&initialize("desktop_a"), &clients, &options));
+        assert_eq!(impostor.id, None);
+        assert!(browser.send(&relay("desktop_a", "offer"), &clients, &options));
+        assert_eq!(desktop.received(), vec![Message::Text("offer".to_string())]);
+        assert_eq!(impostor.received(), vec![]);
+
+        // Once the connection is gone, e.g. the desktop reconnects
+        drop(desktop.rx);
+        let mut reconnected = Client::new();
+        assert!(reconnected.send(&initialize("desktop_a"), &clients, &options));
+        reconnected.received();
+        assert!(browser.send(&relay("desktop_a", "offer"), &clients, &options));
+        assert_eq!(reconnected.received(), vec![Message::Text("offer".to_string())]);
+    }
+}
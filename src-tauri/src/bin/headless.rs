@@ -0,0 +1,307 @@
+// Runs main_process without the Tauri window, for servers and kiosk machines.
+// The link and the state changes (messages_to_fe) are printed to stdout.
+//
+//   linkmouse-headless [--id <id>] [--permissions <permissions>] [--auto-accept] [--pin]
+//                      [--clipboard-sync]
+//                      [--input-backend <rdev|uinput>] [--predictor <predictor>]
+//                      [--record <path>]
+//                      [--signaling-url <url>] ...
+//
+// The other config flags (see config.rs) are accepted too. Without
+// --auto-accept, connections are accepted by typing "y" on stdin.
+
+#[macro_use]
+extern crate lazy_static;
+
+use std::env;
+use std::io::BufRead;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[cfg(feature = "desktop")]
+use rdev::end_rdev;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use linkmouse::main_process::{main_process, answer_connection_request, auto_accept, set_auto_accept, set_clipboard_sync, get_pin, set_pin_enabled, set_default_permissions, set_motion_predictor, AUTO_ACCEPT_ENV};
+use linkmouse::main_process::predictor::{PredictorKind, PREDICTOR_ENV};
+use linkmouse::main_process::mouse_recording::{start_recording, RECORD_MOUSE_ENV};
+use linkmouse::main_process::config::{load_config, CONFIG_FLAGS};
+use linkmouse::main_process::input_sink::{select_input_backend, DEFAULT_INPUT_BACKEND, INPUT_BACKEND_ENV};
+use linkmouse::main_process::link_id::random_id;
+use linkmouse::main_process::settings_store::{load_settings, get_settings, initial_id, remember_id};
+use linkmouse::main_process::messages_to_fe::FrontendEvent;
+use linkmouse::main_process::permissions::{Permissions, PERMISSIONS_ENV};
+use linkmouse::main_process::clipboard_sync::CLIPBOARD_SYNC_ENV;
+
+struct Options {
+    id: Option<String>,
+    permissions: Option<String>,
+    auto_accept: bool,
+    pin: bool,
+    clipboard_sync: bool,
+    input_backend: Option<String>,
+    predictor: Option<String>,
+    record: Option<String>, // Mouse recording for linkmouse-replay
+    config_args: Vec<String>, // Passed on to load_config
+}
+
+struct Connection {
+    send_stop_2: std::sync::mpsc::Sender<bool>,
+    send_stop_3: tokio::sync::mpsc::Sender<()>,
+    recv_finished: std::sync::mpsc::Receiver<bool>,
+}
+
+lazy_static! {
+    static ref EVENTS_TX: Arc<Mutex<Option<UnboundedSender<FrontendEvent>>>> = Arc::new(Mutex::new(None));
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        id: None,
+        permissions: None,
+        auto_accept: false,
+        pin: false,
+        clipboard_sync: false,
+        input_backend: None,
+        predictor: None,
+        record: None,
+        config_args: vec![],
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let mut value = || match inline_value.clone().or_else(|| args.next().cloned()) {
+            Some(value) => Ok(value),
+            None => Err(format!("Missing value for {}", flag)),
+        };
+
+        match flag {
+            "--id" => options.id = Some(value()?),
+            "--permissions" => options.permissions = Some(value()?),
+            "--input-backend" => options.input_backend = Some(value()?),
+            "--predictor" => options.predictor = Some(value()?),
+            "--record" => options.record = Some(value()?),
+            "--auto-accept" => options.auto_accept = true,
+            "--pin" => options.pin = true,
+            "--clipboard-sync" => options.clipboard_sync = true,
+            _ if flag.strip_prefix("--").map_or(false, |key| CONFIG_FLAGS.contains(&key)) => {
+                options.config_args.push(arg.clone());
+                if inline_value.is_none() {
+                    options.config_args.push(value()?);
+                }
+            },
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+fn send_event_to_front_end(event: FrontendEvent) {
+    println!("STATE {}", event.name());
+    if let Some(events_tx) = EVENTS_TX.lock().unwrap().as_ref() {
+        let _ = events_tx.send(event);
+    }
+}
+
+fn start_connection(random_id: String) -> Connection {
+    let (send_stop_2, recv_stop_2) = channel();
+    let (send_stop_3, recv_stop_3) = tokio::sync::mpsc::channel::<()>(1);
+    let (send_finished, recv_finished) = channel();
+
+    thread::spawn(move || {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                main_process(
+                    random_id,
+                    recv_stop_2,
+                    recv_stop_3,
+                    send_finished,
+                    send_event_to_front_end,
+                ).await;
+            });
+        println!("MAIN PROCESS FINISHED");
+    });
+
+    Connection { send_stop_2, send_stop_3, recv_finished }
+}
+
+fn stop_connection(connection: Connection) {
+    if let Err(e) = connection.send_stop_2.send(true) {
+        println!("Could not send stop 2 {}", e);
+    }
+    if let Err(e) = connection.send_stop_3.try_send(()) {
+        println!("Could not send stop 3 {}", e);
+    }
+    println!("Waiting for main_process to finish");
+    let _res = connection.recv_finished.recv(); // result value does not matter here
+    println!("...Finished");
+}
+
+// Lines typed on stdin, for answering connection requests
+fn read_stdin_lines() -> UnboundedReceiver<String> {
+    let (lines_tx, lines_rx) = unbounded_channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => if lines_tx.send(line).is_err() { break },
+                Err(_) => break,
+            }
+        }
+    });
+    lines_rx
+}
+
+#[cfg(unix)]
+async fn terminate() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = sigterm.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn print_link(id: &str) {
+    let config = linkmouse::main_process::config::get_config();
+    println!("LINK {}", config.link(id));
+    if let Some(pin) = get_pin() {
+        println!("PIN {}", pin);
+    }
+}
+
+async fn run(options: Options) {
+    let (events_tx, mut events_rx) = unbounded_channel();
+    *EVENTS_TX.lock().unwrap() = Some(events_tx);
+    let mut stdin_lines = read_stdin_lines();
+    let terminate = terminate();
+    tokio::pin!(terminate);
+
+    let mut id = options.id.clone().unwrap_or_else(initial_id);
+    loop {
+        print_link(&id);
+        let connection = start_connection(id.clone());
+
+        let restart = loop {
+            tokio::select! {
+                Some(event) = events_rx.recv() => {
+                    match event {
+                        FrontendEvent::SessionLocked => break true,
+                        FrontendEvent::SignalingError { reason } => println!("Connection attempt failed: {}", reason),
+                        FrontendEvent::UserApprovalPending { browserInfo: browser_info } => {
+                            println!("Connection request from {} {} (linkmouse {}), accept? [y/N]", browser_info.browserName, browser_info.osName, browser_info.linkmouseVersion);
+                        },
+                        FrontendEvent::UserConnected { connectionType: Some(connection_type), .. } => println!("Connected ({:?})", connection_type),
+                        FrontendEvent::UserDisconnected { sessionDurationMs: Some(duration) } => println!("Disconnected after {} s", duration / 1000),
+                        _ => (),
+                    }
+                }
+                Some(line) = stdin_lines.recv() => {
+                    let accept = line.trim().eq_ignore_ascii_case("y");
+                    answer_connection_request(accept, send_event_to_front_end);
+                }
+                _ = &mut terminate => break false,
+            }
+        };
+
+        stop_connection(connection);
+        if !restart {
+            break;
+        }
+        // Too many wrong PIN answers, the old link must not work anymore
+        if options.id.is_some() {
+            println!("Session locked, not restarting with the fixed --id");
+            break;
+        }
+        id = random_id();
+        remember_id(&id);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        },
+    };
+
+    if let Err(e) = load_settings() {
+        println!("{}, using the default settings", e);
+    }
+    if let Err(e) = load_config(&options.config_args) {
+        println!("{}", e);
+        std::process::exit(2);
+    }
+    let backend = options.input_backend.clone()
+        .or_else(|| env::var(INPUT_BACKEND_ENV).ok())
+        .unwrap_or_else(|| DEFAULT_INPUT_BACKEND.to_string());
+    if let Err(e) = select_input_backend(&backend) {
+        println!("{}", e);
+        std::process::exit(2);
+    }
+    println!("Using input backend: {}", backend);
+    if options.auto_accept || env::var(AUTO_ACCEPT_ENV).map_or(false, |value| value == "1" || value == "true") {
+        set_auto_accept(true);
+    }
+    if let Some(permissions) = get_settings().permissions {
+        set_default_permissions(permissions);
+    }
+    if let Some(permissions) = options.permissions.clone().or_else(|| env::var(PERMISSIONS_ENV).ok()) {
+        match Permissions::parse(&permissions) {
+            Ok(permissions) => set_default_permissions(permissions),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(2);
+            },
+        }
+    }
+    if let Some(predictor) = options.predictor.clone().or_else(|| env::var(PREDICTOR_ENV).ok()) {
+        if let Err(e) = PredictorKind::parse(&predictor).and_then(set_motion_predictor) {
+            println!("{}", e);
+            std::process::exit(2);
+        }
+    }
+    if let Some(path) = options.record.clone().or_else(|| env::var(RECORD_MOUSE_ENV).ok()) {
+        if let Err(e) = start_recording(std::path::Path::new(&path)) {
+            println!("{}", e);
+            std::process::exit(2);
+        }
+    }
+    if options.pin {
+        set_pin_enabled(true);
+    }
+    if options.clipboard_sync || env::var(CLIPBOARD_SYNC_ENV).map_or(false, |value| value == "1" || value == "true") {
+        if !cfg!(feature = "desktop") {
+            println!("Clipboard sync needs the desktop feature");
+            std::process::exit(2);
+        }
+        set_clipboard_sync(true);
+    }
+    if !auto_accept() {
+        println!("Connections have to be accepted on stdin, use --auto-accept for unattended machines");
+    }
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run(options));
+
+    #[cfg(feature = "desktop")]
+    end_rdev();
+}
@@ -0,0 +1,134 @@
+// Replays a mouse recording through the smoothing and prints the metrics
+// (see main_process/replay.rs), to compare predictors and constants offline.
+// Recordings are made with LINKMOUSE_RECORD_MOUSE=<path> (or --record with
+// linkmouse-headless).
+//
+//   linkmouse-replay <recording> [--predictor <predictor>]... [--too-slow <ratio>] [--too-fast <ratio>]
+//
+// Without --predictor every predictor is replayed with its defaults.
+
+use std::env;
+use std::path::PathBuf;
+
+use linkmouse::main_process::mouse_recording::read_recording;
+use linkmouse::main_process::predictor::PredictorKind;
+use linkmouse::main_process::replay::{replay, ReplayOptions};
+
+const ALL_PREDICTORS: [&str; 5] = ["half-step", "none", "linear", "one-euro", "interpolate"];
+
+struct Options {
+    recording: PathBuf,
+    predictors: Vec<String>,
+    too_slow: Option<f64>,
+    too_fast: Option<f64>,
+}
+
+fn parse_ratio(flag: &str, value: String) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if ratio.is_finite() && ratio > 0.0 => Ok(ratio),
+        _ => Err(format!("Invalid value for {}: {}", flag, value)),
+    }
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut recording = None;
+    let mut options = Options {
+        recording: PathBuf::new(),
+        predictors: vec![],
+        too_slow: None,
+        too_fast: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let mut value = || match inline_value.clone().or_else(|| args.next().cloned()) {
+            Some(value) => Ok(value),
+            None => Err(format!("Missing value for {}", flag)),
+        };
+
+        match flag {
+            "--predictor" => options.predictors.push(value()?),
+            "--too-slow" => options.too_slow = Some(parse_ratio(flag, value()?)?),
+            "--too-fast" => options.too_fast = Some(parse_ratio(flag, value()?)?),
+            _ if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+            _ if recording.is_none() => recording = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    options.recording = match recording {
+        Some(recording) => recording,
+        None => return Err("Usage: linkmouse-replay <recording> [--predictor <predictor>]... [--too-slow <ratio>] [--too-fast <ratio>]".to_string()),
+    };
+    if options.predictors.is_empty() {
+        options.predictors = ALL_PREDICTORS.iter().map(|name| name.to_string()).collect();
+    }
+    Ok(options)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        },
+    };
+    let recording = match read_recording(&options.recording) {
+        Ok(recording) => recording,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    let mut results = vec![];
+    for predictor in &options.predictors {
+        let mut replay_options = match PredictorKind::parse(predictor) {
+            Ok(predictor) => ReplayOptions { predictor, ..ReplayOptions::default() },
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(2);
+            },
+        };
+        if let Some(too_slow) = options.too_slow {
+            replay_options.too_slow = too_slow;
+        }
+        if let Some(too_fast) = options.too_fast {
+            replay_options.too_fast = too_fast;
+        }
+        match replay(&recording, &replay_options) {
+            Ok(report) => results.push((predictor, report)),
+            Err(e) => {
+                println!("{}: {}", predictor, e);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    if let Some((_, report)) = results.first() {
+        println!("{} moves in {} movements, {:.1} moves/s from the browser", report.moves_in, report.bursts, report.input_rate);
+    }
+    println!(
+        "{:<16} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "predictor", "error", "max error", "overshoot", "max over", "jitter ms", "moves/s", "end error",
+    );
+    for (predictor, report) in &results {
+        println!(
+            "{:<16} {:>10.2} {:>10.2} {:>10.2} {:>10.2} {:>10.2} {:>10.1} {:>10.2}",
+            predictor,
+            report.mean_error,
+            report.max_error,
+            report.mean_overshoot,
+            report.max_overshoot,
+            report.jitter_ms,
+            report.output_rate,
+            report.end_error,
+        );
+    }
+}
@@ -4,46 +4,28 @@
 #[macro_use]
 extern crate lazy_static;
 
-use serde::Serialize;
 use tauri::{App, Manager, AppHandle/* , CustomMenuItem, SystemTray, SystemTrayMenu */};
 //use tauri_plugin_positioner::{WindowExt, Position};
-use std::{sync::{mpsc::{channel}, Arc, Mutex}, thread::JoinHandle};
+use std::{sync::{mpsc::{channel}, Arc, Mutex}, thread::JoinHandle, env};
 use rdev::{end_rdev};
-use rand::Rng;
-use rand::rngs::OsRng;
 
-mod main_process;
-use crate::main_process::main_process;
+use linkmouse::main_process::{main_process, answer_connection_request, auto_accept, set_auto_accept, AUTO_ACCEPT_ENV, clipboard_sync, set_clipboard_sync, get_connection_stats, get_pin, set_pin_enabled, get_permissions, set_permissions, set_default_permissions, get_key_rules, set_key_rules, get_acceleration_profile, set_acceleration_profile, get_motion_predictor, set_motion_predictor};
+use linkmouse::main_process::predictor::{PredictorKind, PREDICTOR_ENV};
+use linkmouse::main_process::mouse_recording::{start_recording, RECORD_MOUSE_ENV};
+use linkmouse::main_process::acceleration::{set_profile, AccelerationProfile};
+use linkmouse::main_process::permissions::{Permissions, PERMISSIONS_ENV};
+use linkmouse::main_process::messages_to_fe::FrontendEvent;
+use linkmouse::main_process::stats::ConnectionStats;
+use linkmouse::main_process::clipboard_sync::CLIPBOARD_SYNC_ENV;
+use linkmouse::main_process::input_sink::{select_input_backend, INPUT_BACKEND_ENV};
+use linkmouse::main_process::link_id::random_id;
+use linkmouse::main_process::config::{load_config, get_config, Config};
+use linkmouse::main_process::settings_store::{load_settings, get_settings, update_settings, initial_id, remember_id, StoredSettings};
 
 
 /* use std::sync::Mutex; */
 use std::{thread};
 
-const ID_SECTION_LEN: i32 = 6;
-const ID_SECTION_AMOUNT: i32 = 4; // 4;
-
-fn random_lowercase_letter_or_digit() -> char {
-    let mut rng = OsRng;
-    // 01ol dropped due to being easy to mix
-    let chars: Vec<char> = "abcdefghijkmnpqrstuvwxyz23456789".chars().collect();
-    //let chars: Vec<char> = "a".chars().collect();
-    let index = rng.gen_range(0..chars.len());
-    chars.get(index).unwrap().clone()
-}
-
-fn random_id() -> String {
-    let mut id = String::new();
-
-    for _ in 0..ID_SECTION_AMOUNT {
-        for _ in 0..ID_SECTION_LEN {
-            id.push(random_lowercase_letter_or_digit());
-        }
-        id.push('-');
-    }
-    id.pop();
-
-    return id;
-}
 
 struct StopInformation {
     send_stop_2: Option<std::sync::mpsc::Sender<bool>>,
@@ -55,7 +37,7 @@ lazy_static! {
     static ref RANDOM_ID: Arc<Mutex<String>> = Arc::new(Mutex::new(random_id()));
     static ref STOP_INFORMATION: Arc<Mutex<StopInformation>> = Arc::new(Mutex::new(StopInformation { send_stop_2: None, send_stop_3: None, recv_finished: None }));
     static ref APP_HANDLE: Arc<Mutex<Option<AppHandle>>> = Arc::new(Mutex::new(None));
-    static ref LATEST_MY_EVENT: Arc<Mutex<MyEvent>> = Arc::new(Mutex::new(MyEvent { name: "CONNECTING SERVER".to_string() }));
+    static ref LATEST_MY_EVENT: Arc<Mutex<FrontendEvent>> = Arc::new(Mutex::new(FrontendEvent::ConnectingServer));
 }
 
 /* mod datachannel;
@@ -64,11 +46,6 @@ use crate::datachannel::create_data_channel;
 mod background_loop;
 use crate::background_loop::start_background_loop; */
 
-#[derive(Clone, Serialize)]
-struct MyEvent {
-    name: String,
-}
-
 #[tauri::command]
 fn get_random_id() -> String {
     return RANDOM_ID.lock().unwrap().to_string();
@@ -85,32 +62,144 @@ fn change_random_id() {
     {
         let mut id = RANDOM_ID.lock().unwrap();
         *id = random_id();
+        remember_id(&id);
     }
     restart_connection();
 }
 
+#[tauri::command]
+fn get_current_connection_stats() -> Option<ConnectionStats> {
+    get_connection_stats()
+}
+
+#[tauri::command]
+fn answer_connection(accept: bool) {
+    answer_connection_request(accept, send_event_to_front_end);
+}
+
+#[tauri::command]
+fn get_auto_accept() -> bool {
+    auto_accept()
+}
+
+#[tauri::command]
+fn change_auto_accept(auto_accept: bool) {
+    set_auto_accept(auto_accept);
+}
+
+#[tauri::command]
+fn get_clipboard_sync() -> bool {
+    clipboard_sync()
+}
+
+#[tauri::command]
+fn change_clipboard_sync(enabled: bool) {
+    set_clipboard_sync(enabled);
+}
+
+#[tauri::command]
+fn get_current_pin() -> Option<String> {
+    get_pin()
+}
+
+#[tauri::command]
+fn change_pin_enabled(enabled: bool) {
+    set_pin_enabled(enabled);
+}
+
+#[tauri::command]
+fn get_session_permissions() -> Permissions {
+    get_permissions()
+}
+
+#[tauri::command]
+fn change_session_permissions(permissions: Permissions) {
+    set_permissions(permissions);
+}
+
+#[tauri::command]
+fn get_blocked_keys() -> String {
+    get_key_rules()
+}
+
+#[tauri::command]
+fn change_blocked_keys(rules: String) -> Result<(), String> {
+    set_key_rules(&rules)
+}
+
+#[tauri::command]
+fn get_current_config() -> Config {
+    get_config()
+}
+
+#[tauri::command]
+fn get_mouse_acceleration() -> AccelerationProfile {
+    get_acceleration_profile()
+}
+
+#[tauri::command]
+fn change_mouse_acceleration(profile: AccelerationProfile) -> Result<(), String> {
+    set_acceleration_profile(profile)
+}
+
+#[tauri::command]
+fn get_mouse_predictor() -> PredictorKind {
+    get_motion_predictor()
+}
+
+#[tauri::command]
+fn change_mouse_predictor(predictor: PredictorKind) -> Result<(), String> {
+    set_motion_predictor(predictor)
+}
+
+#[tauri::command]
+fn get_stored_settings() -> StoredSettings {
+    get_settings()
+}
+
+#[tauri::command]
+fn change_stored_settings(settings: StoredSettings) -> Result<(), String> {
+    // An invalid profile is not saved
+    if let Some(profile) = settings.acceleration.clone() {
+        set_profile(profile)?;
+    }
+    update_settings(|stored| stored.apply_window_settings(settings))?;
+    remember_id(&RANDOM_ID.lock().unwrap());
+
+    // The signaling URL from the settings, unless env or CLI override it
+    let signaling_url = get_config().signalingUrl;
+    let args: Vec<String> = env::args().skip(1).collect();
+    if load_config(&args)?.signalingUrl != signaling_url {
+        restart_connection();
+    }
+    Ok(())
+}
+
 #[tauri::command]
 fn get_latest_my_event() {
-    let name;
+    let event;
     {
-        name = LATEST_MY_EVENT.lock().unwrap().clone().name;   
+        event = LATEST_MY_EVENT.lock().unwrap().clone();
     }
-    send_event_to_front_end(name.to_string());
+    send_event_to_front_end(event);
 }
 
-fn send_event_to_front_end(name: String) {
-    let my_event = MyEvent { name };
+fn send_event_to_front_end(event: FrontendEvent) {
+    if event == FrontendEvent::SessionLocked {
+        // Cannot restart from the main process thread, it would wait for itself to finish
+        thread::spawn(change_random_id);
+    }
 
     {
         let mut e = LATEST_MY_EVENT.lock().unwrap();
-        *e = my_event.clone();
+        *e = event.clone();
     }
 
 
     if let Some(app_handle) = APP_HANDLE.lock().unwrap().clone() {
-        app_handle.emit_all("my_event", my_event.clone()).unwrap();
+        app_handle.emit_all("my_event", event.clone()).unwrap();
     } else {
-        println!("Could not emit: {}", my_event.clone().name);
+        println!("Could not emit: {}", event.name());
     }
 }
 
@@ -196,6 +285,51 @@ fn stop_connection() {
 }
 
 fn main() {
+    if let Err(e) = load_settings() {
+        println!("{}, using the default settings", e);
+    }
+    *RANDOM_ID.lock().unwrap() = initial_id();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match load_config(&args) {
+        Ok(config) => println!("Using signaling server {}, link {}", config.signalingUrl, config.link("<id>")),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        },
+    }
+    if let Ok(backend) = env::var(INPUT_BACKEND_ENV) {
+        match select_input_backend(&backend) {
+            Ok(()) => println!("Using input backend: {}", backend),
+            Err(e) => println!("{}, using rdev", e),
+        }
+    }
+    // For unattended machines, nobody is there to accept the connection
+    if env::var(AUTO_ACCEPT_ENV).map_or(false, |value| value == "1" || value == "true") {
+        set_auto_accept(true);
+    }
+    if env::var(CLIPBOARD_SYNC_ENV).map_or(false, |value| value == "1" || value == "true") {
+        set_clipboard_sync(true);
+    }
+    if let Some(permissions) = get_settings().permissions {
+        set_default_permissions(permissions);
+    }
+    if let Ok(permissions) = env::var(PERMISSIONS_ENV) {
+        match Permissions::parse(&permissions) {
+            Ok(permissions) => set_default_permissions(permissions),
+            Err(e) => println!("{}, allowing everything", e),
+        }
+    }
+    if let Ok(predictor) = env::var(PREDICTOR_ENV) {
+        if let Err(e) = PredictorKind::parse(&predictor).and_then(set_motion_predictor) {
+            println!("{}, using the default predictor", e);
+        }
+    }
+    if let Ok(path) = env::var(RECORD_MOUSE_ENV) {
+        if let Err(e) = start_recording(std::path::Path::new(&path)) {
+            println!("{}", e);
+        }
+    }
+
     start_connection();
 
     /* let open = CustomMenuItem::new("open".to_string(), "Open");
@@ -216,6 +350,25 @@ fn main() {
             restart_connection,
             change_random_id,
             get_latest_my_event,
+            get_current_connection_stats,
+            answer_connection,
+            get_auto_accept,
+            change_auto_accept,
+            get_clipboard_sync,
+            change_clipboard_sync,
+            get_current_pin,
+            change_pin_enabled,
+            get_session_permissions,
+            change_session_permissions,
+            get_blocked_keys,
+            change_blocked_keys,
+            get_current_config,
+            get_stored_settings,
+            change_stored_settings,
+            get_mouse_acceleration,
+            change_mouse_acceleration,
+            get_mouse_predictor,
+            change_mouse_predictor,
         ])
         .build(tauri::generate_context!())
         .expect("error while building tauri application")
@@ -0,0 +1,205 @@
+// Pointer acceleration for the relative moves from the browser, used when the
+// browser has mouseAcceleration on. The factor depends on the pointer speed in
+// browser pixels per millisecond:
+//
+//   flat      always 1
+//   linear    1 + gain * speed, at most limit
+//   adaptive  1 until ADAPTIVE_THRESHOLD, then rises by ADAPTIVE_INCLINE up to
+//             ADAPTIVE_LIMIT (like the libinput adaptive profile)
+//   custom    [speed, factor] points, linearly interpolated, flat outside
+
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+
+const ADAPTIVE_THRESHOLD: f64 = 0.4;
+const ADAPTIVE_INCLINE: f64 = 1.1;
+const ADAPTIVE_LIMIT: f64 = 2.0;
+const SPEED_SMOOTHING: f64 = 0.5; // Weight of the previous speed, network timing is jittery
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AccelerationProfile {
+    Flat,
+    Linear { gain: f64, limit: f64 },
+    #[default]
+    Adaptive,
+    Custom { points: Vec<[f64; 2]> },
+}
+
+impl AccelerationProfile {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            AccelerationProfile::Linear { gain, limit } => {
+                if !gain.is_finite() || *gain < 0.0 || !limit.is_finite() || *limit < 1.0 {
+                    return Err("Linear acceleration needs gain >= 0 and limit >= 1".to_string());
+                }
+            },
+            AccelerationProfile::Custom { points } => {
+                if points.is_empty() {
+                    return Err("Custom acceleration needs at least one point".to_string());
+                }
+                if points.iter().any(|[speed, factor]| !speed.is_finite() || *speed < 0.0 || !factor.is_finite() || *factor <= 0.0) {
+                    return Err("Custom acceleration points need speed >= 0 and factor > 0".to_string());
+                }
+                if points.windows(2).any(|pair| pair[0][0] >= pair[1][0]) {
+                    return Err("Custom acceleration points must be in increasing speed order".to_string());
+                }
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    pub fn factor(&self, speed: f64) -> f64 {
+        match self {
+            AccelerationProfile::Flat => 1.0,
+            AccelerationProfile::Linear { gain, limit } => (1.0 + gain * speed).min(*limit),
+            AccelerationProfile::Adaptive => {
+                if speed < ADAPTIVE_THRESHOLD {
+                    1.0
+                } else {
+                    (1.0 + ADAPTIVE_INCLINE * (speed - ADAPTIVE_THRESHOLD)).min(ADAPTIVE_LIMIT)
+                }
+            },
+            AccelerationProfile::Custom { points } => {
+                let first = points[0];
+                let last = points[points.len() - 1];
+                if speed <= first[0] {
+                    return first[1];
+                }
+                if speed >= last[0] {
+                    return last[1];
+                }
+                let pair = points.windows(2).find(|pair| speed < pair[1][0]).unwrap();
+                let [speed_0, factor_0] = pair[0];
+                let [speed_1, factor_1] = pair[1];
+                factor_0 + (factor_1 - factor_0) * (speed - speed_0) / (speed_1 - speed_0)
+            },
+        }
+    }
+}
+
+lazy_static! {
+    static ref PROFILE: Arc<Mutex<AccelerationProfile>> = Arc::new(Mutex::new(AccelerationProfile::default()));
+}
+
+pub fn get_profile() -> AccelerationProfile {
+    PROFILE.lock().unwrap().clone()
+}
+
+pub fn set_profile(profile: AccelerationProfile) -> Result<(), String> {
+    profile.validate()?;
+    *PROFILE.lock().unwrap() = profile;
+    Ok(())
+}
+
+// The factor for a move of (x, y) browser pixels that came interval_nanos
+// after the previous one. Bursts after a lag would look very fast, so the
+// interval is never taken to be shorter than the average one. smoothed_speed
+// is kept by the session, None when the mouse has stopped.
+pub fn acceleration_factor(x: i32, y: i32, interval_nanos: Option<u128>, average_interval_nanos: u128, smoothed_speed: &mut Option<f64>) -> f64 {
+    let interval_nanos = match interval_nanos {
+        Some(interval_nanos) => interval_nanos.max(average_interval_nanos),
+        None => average_interval_nanos,
+    };
+    if interval_nanos == 0 {
+        return 1.0;
+    }
+
+    let distance = ((x as f64).powi(2) + (y as f64).powi(2)).sqrt();
+    let speed = distance / (interval_nanos as f64 / 1_000_000.0);
+    let speed = match *smoothed_speed {
+        Some(previous) => previous * SPEED_SMOOTHING + speed * (1.0 - SPEED_SMOOTHING),
+        None => speed,
+    };
+    *smoothed_speed = Some(speed);
+
+    get_profile().factor(speed)
+}
+
+// Whole pixels of value * factor plus the remainder of the previous moves.
+// The remainder is always in [0, 1), fract() would be negative for negative
+// moves.
+pub fn scale(value: i32, factor: f64, remainder: &mut f64) -> i32 {
+    let scaled = (value as f64).mul_add(factor, *remainder);
+    let pixels = scaled.floor() as i32;
+    *remainder = scaled - pixels as f64;
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u128 = 1_000_000;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn profiles_have_their_gain_at_known_speeds() {
+        assert_close(AccelerationProfile::Flat.factor(0.0), 1.0);
+        assert_close(AccelerationProfile::Flat.factor(5.0), 1.0);
+
+        let linear = AccelerationProfile::Linear { gain: 0.5, limit: 2.0 };
+        assert_close(linear.factor(0.0), 1.0);
+        assert_close(linear.factor(1.0), 1.5);
+        assert_close(linear.factor(4.0), 2.0); // Limit
+
+        let adaptive = AccelerationProfile::Adaptive;
+        assert_close(adaptive.factor(0.2), 1.0);
+        assert_close(adaptive.factor(ADAPTIVE_THRESHOLD), 1.0);
+        assert_close(adaptive.factor(0.9), 1.0 + ADAPTIVE_INCLINE * 0.5);
+        assert_close(adaptive.factor(5.0), ADAPTIVE_LIMIT);
+
+        let custom = AccelerationProfile::Custom { points: vec![[0.5, 1.0], [1.0, 2.0], [2.0, 4.0]] };
+        assert_close(custom.factor(0.0), 1.0); // Flat outside
+        assert_close(custom.factor(0.75), 1.5);
+        assert_close(custom.factor(1.5), 3.0);
+        assert_close(custom.factor(10.0), 4.0);
+    }
+
+    #[test]
+    fn invalid_profiles_are_rejected() {
+        assert!(AccelerationProfile::Linear { gain: -1.0, limit: 2.0 }.validate().is_err());
+        assert!(AccelerationProfile::Linear { gain: 1.0, limit: 0.5 }.validate().is_err());
+        assert!(AccelerationProfile::Custom { points: vec![] }.validate().is_err());
+        assert!(AccelerationProfile::Custom { points: vec![[1.0, 1.0], [0.5, 2.0]] }.validate().is_err());
+        assert!(AccelerationProfile::Custom { points: vec![[0.0, 1.0], [1.0, 2.0]] }.validate().is_ok());
+    }
+
+    #[test]
+    fn speed_uses_at_least_the_average_interval() {
+        // 16 px, a burst after a lag counts as one average interval
+        let mut smoothed_speed = None;
+        let factor = acceleration_factor(16, 0, Some(4 * MS), 16 * MS, &mut smoothed_speed);
+        assert_close(smoothed_speed.unwrap(), 1.0);
+        assert_close(factor, AccelerationProfile::default().factor(1.0));
+
+        // Half of the new speed, half of the previous
+        acceleration_factor(0, 48, Some(16 * MS), 16 * MS, &mut smoothed_speed);
+        assert_close(smoothed_speed.unwrap(), 2.0);
+    }
+
+    #[test]
+    fn negative_remainders_do_not_drift() {
+        let mut remainder = 0.0;
+        let mut total = 0;
+        for _ in 0..1000 {
+            total += scale(-1, 0.3, &mut remainder);
+            assert!((0.0..1.0).contains(&remainder));
+        }
+        assert_eq!(total, -300);
+
+        // Back and forth ends where it started
+        let mut remainder = 0.0;
+        let mut total = 0;
+        for i in 0..1000 {
+            let value = if i % 2 == 0 { 3 } else { -3 };
+            total += scale(value, 0.7, &mut remainder);
+        }
+        assert_eq!(total, 0);
+    }
+}
@@ -0,0 +1,48 @@
+// A new browser has to be accepted by the local user before its input is
+// injected. Until then the input messages are dropped. With auto accept on,
+// every browser is accepted immediately (for unattended machines).
+//
+// The state of the connected browser is kept in its Session.
+
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ApprovalState {
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+lazy_static! {
+    static ref AUTO_ACCEPT: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+}
+
+impl ApprovalState {
+    // For each new data channel
+    pub fn initial() -> Self {
+        match auto_accept() {
+            true => ApprovalState::Accepted,
+            false => ApprovalState::Pending,
+        }
+    }
+
+    // Returns false if there was nothing pending to answer
+    pub fn answer(&mut self, accept: bool) -> bool {
+        if *self != ApprovalState::Pending {
+            return false;
+        }
+        *self = match accept {
+            true => ApprovalState::Accepted,
+            false => ApprovalState::Rejected,
+        };
+        true
+    }
+}
+
+pub fn auto_accept() -> bool {
+    *AUTO_ACCEPT.lock().unwrap()
+}
+
+pub fn set_auto_accept(auto_accept: bool) {
+    *AUTO_ACCEPT.lock().unwrap() = auto_accept;
+}
@@ -0,0 +1,468 @@
+// Clipboard contents other than plain text: PNG images, HTML and URI lists.
+// They are sent in `clipboardchunk` messages, base64 in CHUNK_BYTES pieces,
+// when both sides announce richClipboard in desktopinfo/browserinfo. Plain
+// text keeps using paste/copycut/clipboard.
+//
+//   clipboardchunk,<transfer>,<index>,<count>,<action>,<mime>,<base64>
+//
+// action is what to do once every chunk is in: "paste" (write and press the
+// paste shortcut), "sync" (write, see clipboard_sync.rs) or "copycut" (the
+// answer to copy/cut, only to the browser).
+//
+// A URI list from the browser is written as text: the files are not on this
+// machine.
+//
+// The clipboard is only there with the desktop feature. Without it reading
+// and writing fail, and richClipboard is not announced.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+#[cfg(feature = "desktop")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "desktop")]
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "desktop")]
+use arboard::{Clipboard, ImageData};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::main_process::protocol::Message;
+
+pub const MIME_TEXT: &str = "text/plain";
+pub const MIME_HTML: &str = "text/html";
+pub const MIME_PNG: &str = "image/png";
+pub const MIME_URI_LIST: &str = "text/uri-list";
+
+const CHUNK_BYTES: usize = 12 * 1024; // 16 KiB in base64, safe for every browser
+pub const CLIPBOARD_MAX_BYTES: usize = 8 * 1024 * 1024; // One transfer, before base64
+const IMAGE_MAX_BYTES: usize = 8 * CLIPBOARD_MAX_BYTES; // Decoded RGBA, a small PNG can declare a huge image
+const MAX_PENDING_TRANSFERS: usize = 4;
+
+static NEXT_TRANSFER: AtomicU32 = AtomicU32::new(1);
+
+#[cfg(feature = "desktop")]
+lazy_static! {
+    // Kept open, on X11 and Wayland what was written is gone once the last
+    // Clipboard is dropped
+    static ref CLIPBOARD: Arc<Mutex<Option<Clipboard>>> = Arc::new(Mutex::new(None));
+}
+
+#[cfg(not(feature = "desktop"))]
+const NO_CLIPBOARD: &str = "no clipboard, built without the desktop feature";
+
+#[cfg(feature = "desktop")]
+fn with_clipboard<T>(f: impl FnOnce(&mut Clipboard) -> Result<T, String>) -> Result<T, String> {
+    let mut clipboard = CLIPBOARD.lock().unwrap();
+    if clipboard.is_none() {
+        *clipboard = Some(Clipboard::new().map_err(|e| e.to_string())?);
+    }
+    f(clipboard.as_mut().unwrap())
+}
+
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub enum ClipboardContent {
+    Text(String),
+    Html(String),
+    Image { width: usize, height: usize, rgba: Vec<u8> },
+    UriList(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClipboardAction {
+    Paste,
+    Sync,
+    CopyCut,
+}
+
+impl ClipboardAction {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "paste" => Ok(ClipboardAction::Paste),
+            "sync" => Ok(ClipboardAction::Sync),
+            "copycut" => Ok(ClipboardAction::CopyCut),
+            _ => Err(format!("clipboardchunk: unknown action {}", value)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClipboardAction::Paste => "paste",
+            ClipboardAction::Sync => "sync",
+            ClipboardAction::CopyCut => "copycut",
+        }
+    }
+}
+
+impl ClipboardContent {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ClipboardContent::Text(_) => MIME_TEXT,
+            ClipboardContent::Html(_) => MIME_HTML,
+            ClipboardContent::Image { .. } => MIME_PNG,
+            ClipboardContent::UriList(_) => MIME_URI_LIST,
+        }
+    }
+
+    // To tell apart contents without keeping them
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        match self {
+            ClipboardContent::Text(text) | ClipboardContent::Html(text) => Ok(text.as_bytes().to_vec()),
+            ClipboardContent::Image { width, height, rgba } => encode_png(*width, *height, rgba),
+            // https://www.rfc-editor.org/rfc/rfc2483#section-5
+            ClipboardContent::UriList(uris) => Ok(uris.join("\r\n").into_bytes()),
+        }
+    }
+
+    fn decode(mime: &str, data: Vec<u8>) -> Result<Self, String> {
+        let text = |data: Vec<u8>| String::from_utf8(data).map_err(|_| format!("{}: not valid UTF-8", mime));
+        match mime {
+            MIME_TEXT => Ok(ClipboardContent::Text(text(data)?)),
+            MIME_HTML => Ok(ClipboardContent::Html(text(data)?)),
+            MIME_PNG => decode_png(&data),
+            MIME_URI_LIST => Ok(ClipboardContent::UriList(
+                text(data)?.lines().filter(|line| !line.is_empty() && !line.starts_with('#')).map(|line| line.to_string()).collect(),
+            )),
+            _ => Err(format!("clipboardchunk: unsupported type {}", mime)),
+        }
+    }
+}
+
+fn encode_png(width: usize, height: usize, rgba: &[u8]) -> Result<Vec<u8>, String> {
+    let mut png_data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut png_data, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| format!("could not encode PNG: {}", e))?;
+        writer.write_image_data(rgba).map_err(|e| format!("could not encode PNG: {}", e))?;
+    }
+    Ok(png_data)
+}
+
+fn decode_png(data: &[u8]) -> Result<ClipboardContent, String> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|e| format!("invalid PNG: {}", e))?;
+    let (width, height) = (reader.info().width as usize, reader.info().height as usize);
+    match width.checked_mul(height).and_then(|pixels| pixels.checked_mul(4)) {
+        Some(bytes) if bytes <= IMAGE_MAX_BYTES => {}
+        _ => return Err(format!("image/png: {}x{} is over {} bytes", width, height, IMAGE_MAX_BYTES)),
+    }
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|e| format!("invalid PNG: {}", e))?;
+    buffer.truncate(info.buffer_size());
+
+    // The clipboard takes RGBA only
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => buffer,
+        png::ColorType::Rgb => buffer.chunks(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => buffer.chunks(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => buffer.iter().flat_map(|g| [*g, *g, *g, 255]).collect(),
+        png::ColorType::Indexed => return Err("invalid PNG: palette was not expanded".to_string()),
+    };
+    Ok(ClipboardContent::Image { width: info.width as usize, height: info.height as usize, rgba })
+}
+
+#[cfg(feature = "desktop")]
+fn file_uri(path: PathBuf) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    match path.starts_with('/') {
+        true => format!("file://{}", path),
+        false => format!("file:///{}", path), // Windows drive letter
+    }
+}
+
+// The richest format on the clipboard
+#[cfg(feature = "desktop")]
+pub fn read_clipboard() -> Result<ClipboardContent, String> {
+    with_clipboard(|clipboard| {
+        if let Ok(image) = clipboard.get_image() {
+            return Ok(ClipboardContent::Image { width: image.width, height: image.height, rgba: image.bytes.into_owned() });
+        }
+        if let Ok(files) = clipboard.get().file_list() {
+            if !files.is_empty() {
+                return Ok(ClipboardContent::UriList(files.into_iter().map(file_uri).collect()));
+            }
+        }
+        if let Ok(html) = clipboard.get().html() {
+            return Ok(ClipboardContent::Html(html));
+        }
+        clipboard.get_text().map(ClipboardContent::Text).map_err(|e| e.to_string())
+    })
+}
+
+// Text only, also the plain text version of HTML
+#[cfg(feature = "desktop")]
+pub fn read_clipboard_text() -> Result<String, String> {
+    with_clipboard(|clipboard| clipboard.get_text().map_err(|e| e.to_string()))
+}
+
+#[cfg(feature = "desktop")]
+pub fn write_clipboard(content: &ClipboardContent) -> Result<(), String> {
+    with_clipboard(|clipboard| write(clipboard, content))
+}
+
+#[cfg(not(feature = "desktop"))]
+pub fn read_clipboard() -> Result<ClipboardContent, String> {
+    Err(NO_CLIPBOARD.to_string())
+}
+
+#[cfg(not(feature = "desktop"))]
+pub fn read_clipboard_text() -> Result<String, String> {
+    Err(NO_CLIPBOARD.to_string())
+}
+
+#[cfg(not(feature = "desktop"))]
+pub fn write_clipboard(_content: &ClipboardContent) -> Result<(), String> {
+    Err(NO_CLIPBOARD.to_string())
+}
+
+#[cfg(feature = "desktop")]
+fn write(clipboard: &mut Clipboard, content: &ClipboardContent) -> Result<(), String> {
+    let result = match content {
+        ClipboardContent::Text(text) => clipboard.set_text(text.as_str()),
+        ClipboardContent::Html(html) => clipboard.set_html(html.as_str(), None),
+        ClipboardContent::Image { width, height, rgba } => clipboard.set_image(ImageData {
+            width: *width,
+            height: *height,
+            bytes: rgba.as_slice().into(),
+        }),
+        ClipboardContent::UriList(uris) => clipboard.set_text(uris.join("\n")),
+    };
+    result.map_err(|e| format!("could not write the clipboard: {}", e))
+}
+
+// The clipboardchunk messages for the browser
+pub fn chunk(content: &ClipboardContent, action: ClipboardAction) -> Result<Vec<Message>, String> {
+    let data = content.encode()?;
+    if data.len() > CLIPBOARD_MAX_BYTES {
+        return Err(format!("{} is {} bytes, over {}", content.mime(), data.len(), CLIPBOARD_MAX_BYTES));
+    }
+
+    let mut chunks: Vec<&[u8]> = data.chunks(CHUNK_BYTES).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]); // Empty contents are one empty chunk
+    }
+    let count = chunks.len() as u32;
+    let transfer = NEXT_TRANSFER.fetch_add(1, Ordering::Relaxed);
+    Ok(chunks.into_iter().enumerate().map(|(index, chunk)| Message::ClipboardChunk {
+        transfer,
+        index: index as u32,
+        count,
+        action: action.name().to_string(),
+        mime: content.mime().to_string(),
+        data: BASE64.encode(chunk),
+    }).collect())
+}
+
+struct Transfer {
+    action: ClipboardAction,
+    mime: String,
+    chunks: Vec<Option<Vec<u8>>>,
+    bytes: usize,
+}
+
+// Transfers from the browser until all of their chunks are in, one per session
+pub struct ClipboardTransfers {
+    transfers: HashMap<u32, Transfer>,
+}
+
+impl ClipboardTransfers {
+    pub fn new() -> Self {
+        ClipboardTransfers { transfers: HashMap::new() }
+    }
+
+    // Some once the transfer is complete
+    pub fn add(&mut self, transfer: u32, index: u32, count: u32, action: &str, mime: &str, data: &str) -> Result<Option<(ClipboardAction, ClipboardContent)>, String> {
+        let action = ClipboardAction::parse(action)?;
+        if action == ClipboardAction::CopyCut {
+            return Err("clipboardchunk: copycut is only sent to the browser".to_string());
+        }
+        if count == 0 || index >= count || count as usize > CLIPBOARD_MAX_BYTES / CHUNK_BYTES + 1 {
+            return Err(format!("clipboardchunk: invalid chunk {} of {}", index, count));
+        }
+        let data = BASE64.decode(data).map_err(|e| format!("clipboardchunk: invalid base64: {}", e))?;
+
+        if !self.transfers.contains_key(&transfer) && self.transfers.len() >= MAX_PENDING_TRANSFERS {
+            // Forget the oldest, the browser has given up on it
+            if let Some(oldest) = self.transfers.keys().min().copied() {
+                self.transfers.remove(&oldest);
+            }
+        }
+        let pending = self.transfers.entry(transfer).or_insert_with(|| Transfer {
+            action,
+            mime: mime.to_string(),
+            chunks: vec![None; count as usize],
+            bytes: 0,
+        });
+        if pending.action != action || pending.mime != mime || pending.chunks.len() != count as usize {
+            self.transfers.remove(&transfer);
+            return Err(format!("clipboardchunk: chunk {} does not match transfer {}", index, transfer));
+        }
+
+        if let Some(previous) = &pending.chunks[index as usize] {
+            pending.bytes -= previous.len(); // Sent again
+        }
+        pending.bytes += data.len();
+        if pending.bytes > CLIPBOARD_MAX_BYTES {
+            self.transfers.remove(&transfer);
+            return Err(format!("clipboardchunk: transfer {} is over {} bytes", transfer, CLIPBOARD_MAX_BYTES));
+        }
+        pending.chunks[index as usize] = Some(data);
+        if pending.chunks.iter().any(|chunk| chunk.is_none()) {
+            return Ok(None);
+        }
+
+        let pending = self.transfers.remove(&transfer).unwrap();
+        let data = pending.chunks.into_iter().flatten().flatten().collect();
+        Ok(Some((pending.action, ClipboardContent::decode(&pending.mime, data)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fields of clipboardchunk messages, to feed them back in
+    fn fields(messages: Vec<Message>) -> Vec<(u32, u32, u32, String, String, String)> {
+        messages.into_iter().map(|message| match message {
+            Message::ClipboardChunk { transfer, index, count, action, mime, data } => (transfer, index, count, action, mime, data),
+            other => panic!("not a clipboardchunk: {:?}", other),
+        }).collect()
+    }
+
+    fn add(transfers: &mut ClipboardTransfers, chunk: &(u32, u32, u32, String, String, String)) -> Result<Option<(ClipboardAction, ClipboardContent)>, String> {
+        let (transfer, index, count, action, mime, data) = chunk;
+        transfers.add(*transfer, *index, *count, action, mime, data)
+    }
+
+    fn round_trip(content: ClipboardContent) -> ClipboardContent {
+        let chunks = fields(chunk(&content, ClipboardAction::Sync).unwrap());
+        let mut transfers = ClipboardTransfers::new();
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert_eq!(add(&mut transfers, chunk), Ok(None));
+        }
+        let (action, received) = add(&mut transfers, last).unwrap().unwrap();
+        assert_eq!(action, ClipboardAction::Sync);
+        received
+    }
+
+    // A PNG with a header and a stub of image data, for dimensions nothing
+    // could hold
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let mut png_data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png_data, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_chunk(png::chunk::IDAT, &[0x78, 0x9c]).unwrap();
+        drop(writer);
+        png_data
+    }
+
+    #[test]
+    fn contents_survive_chunking() {
+        let text = "x".repeat(3 * CHUNK_BYTES + 5);
+        assert_eq!(fields(chunk(&ClipboardContent::Text(text.clone()), ClipboardAction::Paste).unwrap()).len(), 4);
+        assert_eq!(round_trip(ClipboardContent::Text(text.clone())), ClipboardContent::Text(text));
+        assert_eq!(round_trip(ClipboardContent::Text(String::new())), ClipboardContent::Text(String::new()));
+        assert_eq!(round_trip(ClipboardContent::Html("<b>bold</b>".to_string())), ClipboardContent::Html("<b>bold</b>".to_string()));
+
+        let uris = vec!["file:///tmp/a.txt".to_string(), "https://example.com/".to_string()];
+        assert_eq!(round_trip(ClipboardContent::UriList(uris.clone())), ClipboardContent::UriList(uris));
+
+        let rgba: Vec<u8> = (0..2 * 3 * 4).map(|i| i as u8).collect();
+        let image = ClipboardContent::Image { width: 2, height: 3, rgba };
+        assert_eq!(round_trip(image.clone()), image);
+    }
+
+    #[test]
+    fn chunks_can_come_in_any_order_and_twice() {
+        let text = "y".repeat(2 * CHUNK_BYTES + 1);
+        let chunks = fields(chunk(&ClipboardContent::Text(text.clone()), ClipboardAction::Paste).unwrap());
+        let mut transfers = ClipboardTransfers::new();
+        assert_eq!(add(&mut transfers, &chunks[2]), Ok(None));
+        assert_eq!(add(&mut transfers, &chunks[0]), Ok(None));
+        assert_eq!(add(&mut transfers, &chunks[0]), Ok(None));
+        assert_eq!(add(&mut transfers, &chunks[1]), Ok(Some((ClipboardAction::Paste, ClipboardContent::Text(text)))));
+        // Done with, a late copy starts over
+        assert_eq!(add(&mut transfers, &chunks[1]), Ok(None));
+    }
+
+    #[test]
+    fn chunks_must_match_their_transfer() {
+        let mut transfers = ClipboardTransfers::new();
+        let data = BASE64.encode("abc");
+        assert_eq!(transfers.add(1, 0, 2, "paste", MIME_TEXT, &data), Ok(None));
+        assert!(transfers.add(1, 1, 2, "paste", MIME_HTML, &data).is_err());
+        // The transfer is dropped, the next chunk starts a new one
+        assert_eq!(transfers.add(1, 1, 2, "paste", MIME_TEXT, &data), Ok(None));
+        assert!(transfers.add(1, 0, 3, "paste", MIME_TEXT, &data).is_err());
+        assert_eq!(transfers.add(1, 0, 2, "paste", MIME_TEXT, &data), Ok(None));
+        assert!(transfers.add(1, 1, 2, "sync", MIME_TEXT, &data).is_err());
+
+        assert!(transfers.add(2, 0, 0, "paste", MIME_TEXT, &data).is_err());
+        assert!(transfers.add(2, 2, 2, "paste", MIME_TEXT, &data).is_err());
+        assert!(transfers.add(2, 0, 1, "copycut", MIME_TEXT, &data).is_err());
+        assert!(transfers.add(2, 0, 1, "cut", MIME_TEXT, &data).is_err());
+        assert!(transfers.add(2, 0, 1, "paste", MIME_TEXT, "not base64!").is_err());
+        assert!(transfers.add(2, 0, 1, "paste", "application/pdf", &data).is_err());
+        assert!(transfers.add(2, 0, 1, "paste", MIME_TEXT, &BASE64.encode([0xff, 0xfe])).is_err());
+    }
+
+    #[test]
+    fn oversized_transfers_are_refused() {
+        assert!(chunk(&ClipboardContent::Text("z".repeat(CLIPBOARD_MAX_BYTES + 1)), ClipboardAction::Paste).is_err());
+        assert!(chunk(&ClipboardContent::Text("z".repeat(CLIPBOARD_MAX_BYTES)), ClipboardAction::Paste).is_ok());
+
+        let mut transfers = ClipboardTransfers::new();
+        let too_many = (CLIPBOARD_MAX_BYTES / CHUNK_BYTES + 2) as u32;
+        assert!(transfers.add(1, 0, too_many, "paste", MIME_TEXT, "").is_err());
+
+        // Chunks bigger than CHUNK_BYTES add up past the limit
+        let count = (CLIPBOARD_MAX_BYTES / CHUNK_BYTES + 1) as u32;
+        let big = BASE64.encode(vec![b'a'; CLIPBOARD_MAX_BYTES / 2]);
+        assert_eq!(transfers.add(1, 0, count, "paste", MIME_TEXT, &big), Ok(None));
+        // Sent again it replaces the first copy
+        assert_eq!(transfers.add(1, 0, count, "paste", MIME_TEXT, &big), Ok(None));
+        assert_eq!(transfers.add(1, 1, count, "paste", MIME_TEXT, &big), Ok(None));
+        assert!(transfers.add(1, 2, count, "paste", MIME_TEXT, &BASE64.encode("a")).is_err());
+    }
+
+    #[test]
+    fn huge_png_dimensions_are_refused() {
+        let png_data = png_header(100_000, 100_000);
+        assert!(png_data.len() < 100);
+        let error = ClipboardContent::decode(MIME_PNG, png_data).unwrap_err();
+        assert!(error.contains("100000x100000"), "{}", error);
+        assert!(ClipboardContent::decode(MIME_PNG, png_header(u32::MAX >> 1, u32::MAX >> 1)).is_err());
+    }
+
+    #[test]
+    fn the_oldest_pending_transfer_is_forgotten() {
+        let mut transfers = ClipboardTransfers::new();
+        let data = BASE64.encode("abc");
+        for transfer in 1..=MAX_PENDING_TRANSFERS as u32 + 1 {
+            assert_eq!(transfers.add(transfer, 0, 2, "paste", MIME_TEXT, &data), Ok(None));
+        }
+        assert_eq!(transfers.transfers.len(), MAX_PENDING_TRANSFERS);
+        assert!(!transfers.transfers.contains_key(&1));
+        // Transfer 1 starts over, transfer 2 is still there
+        assert_eq!(transfers.add(1, 1, 2, "paste", MIME_TEXT, &data), Ok(None));
+        assert!(!transfers.transfers.contains_key(&2));
+        assert_eq!(
+            transfers.add(3, 1, 2, "paste", MIME_TEXT, &data),
+            Ok(Some((ClipboardAction::Paste, ClipboardContent::Text("abcabc".to_string())))),
+        );
+    }
+}
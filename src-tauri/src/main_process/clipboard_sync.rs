@@ -0,0 +1,91 @@
+// Automatic clipboard sync, opt-in on the desktop. While on, the desktop
+// clipboard is polled and changes are sent to the browser, and clipboard
+// updates from the browser are written to the desktop clipboard without
+// pressing the paste shortcut. Reading needs clipboardRead, writing
+// clipboardWrite.
+//
+// Text goes in `clipboard`, other formats in `clipboardchunk` with action
+// "sync" to a browser with richClipboard (see clipboard_formats.rs).
+//
+// The clipboard_contents of the Session are what both sides have already seen, so contents that came
+// from the browser are not sent back to it (and paste/copycut count too).
+// Text over CLIPBOARD_SYNC_MAX_BYTES is not synced either way, other formats
+// are limited to CLIPBOARD_MAX_BYTES.
+
+use std::sync::{Arc, Mutex};
+
+use crate::main_process::clipboard_formats::{read_clipboard, write_clipboard, ClipboardContent};
+use crate::main_process::session::Session;
+
+pub const CLIPBOARD_SYNC_ENV: &str = "LINKMOUSE_CLIPBOARD_SYNC";
+pub const CLIPBOARD_POLL_MS: u64 = 500;
+pub const CLIPBOARD_SYNC_MAX_BYTES: usize = 64 * 1024; // Text in one message, well under the data channel limit
+
+lazy_static! {
+    static ref CLIPBOARD_SYNC: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+}
+
+pub fn is_enabled() -> bool {
+    *CLIPBOARD_SYNC.lock().unwrap()
+}
+
+pub fn set_enabled(enabled: bool) {
+    *CLIPBOARD_SYNC.lock().unwrap() = enabled;
+}
+
+// Called for each new data channel, the current clipboard is not pushed to a
+// browser that just connected
+pub fn reset(session: &Session) {
+    *session.clipboard_contents.lock().unwrap() = read_clipboard().ok().map(|content| content.fingerprint());
+}
+
+// The contents are on both sides now
+pub fn remember(session: &Session, content: &ClipboardContent) {
+    *session.clipboard_contents.lock().unwrap() = Some(content.fingerprint());
+}
+
+// The desktop clipboard if it has changed since the last call. Only text
+// unless the browser has richClipboard.
+pub fn changed_contents(session: &Session, rich: bool) -> Option<ClipboardContent> {
+    let content = read_clipboard().ok()?; // Empty, or nothing readable
+    let fingerprint = content.fingerprint();
+    {
+        let mut last_contents = session.clipboard_contents.lock().unwrap();
+        if *last_contents == Some(fingerprint) {
+            return None;
+        }
+        *last_contents = Some(fingerprint);
+    }
+
+    match &content {
+        ClipboardContent::Text(text) if text.len() > CLIPBOARD_SYNC_MAX_BYTES => {
+            println!("Clipboard not synced, {} bytes is over {}", text.len(), CLIPBOARD_SYNC_MAX_BYTES);
+            None
+        },
+        ClipboardContent::Text(_) => Some(content),
+        _ if rich => Some(content),
+        _ => {
+            println!("Clipboard not synced, the browser does not take {}", content.mime());
+            None
+        },
+    }
+}
+
+// From the browser, `clipboard`
+pub fn apply_text(session: &Session, text: String) -> Result<(), String> {
+    if text.len() > CLIPBOARD_SYNC_MAX_BYTES {
+        return Err(format!("clipboard: {} bytes is over {}", text.len(), CLIPBOARD_SYNC_MAX_BYTES));
+    }
+    apply(session, &ClipboardContent::Text(text))
+}
+
+// From the browser, `clipboard` or a complete `clipboardchunk` transfer
+pub fn apply(session: &Session, content: &ClipboardContent) -> Result<(), String> {
+    if !is_enabled() {
+        return Err("clipboard: sync is off on the desktop".to_string());
+    }
+
+    // Remembered first, the watcher could see the new contents in between
+    remember(session, content);
+    write_clipboard(content).map_err(|e| format!("clipboard: {}", e))
+}
@@ -0,0 +1,207 @@
+// Where to connect and which link to share, so that the app can be used with
+// a self-hosted signaling server and web page.
+//
+// Each value is taken from the first of: CLI flag, environment variable,
+// settings.json (signalingUrl only, see settings_store.rs), config file,
+// default. The config file is <config dir>/linkmouse/config.json
+// (e.g. ~/.config/linkmouse/config.json), or the path given with --config or
+// LINKMOUSE_CONFIG. Every field in the file is optional:
+//
+//   {
+//     "signalingUrl": "wss://signaling.example.com:443",
+//     "desktopPrefix": "desktop_",
+//     "browserPrefix": "browser_",
+//     "linkBase": "https://kvm.example.com/"
+//   }
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+
+use crate::main_process::settings_store::get_settings;
+
+pub const CONFIG_ENV: &str = "LINKMOUSE_CONFIG";
+pub const SIGNALING_URL_ENV: &str = "LINKMOUSE_SIGNALING_URL";
+pub const DESKTOP_PREFIX_ENV: &str = "LINKMOUSE_DESKTOP_PREFIX";
+pub const BROWSER_PREFIX_ENV: &str = "LINKMOUSE_BROWSER_PREFIX";
+pub const LINK_BASE_ENV: &str = "LINKMOUSE_LINK_BASE";
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub signalingUrl: String,
+    pub desktopPrefix: String, // The desktop registers as <desktopPrefix><id>
+    pub browserPrefix: String, // and sends to <browserPrefix><id>
+    pub linkBase: String, // The shared link is <linkBase><id>
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            //signalingUrl: "ws://localhost:3001".to_string(),
+            signalingUrl: "wss://browserkvm-backend.onrender.com:443".to_string(),
+            desktopPrefix: "desktop_".to_string(),
+            browserPrefix: "browser_".to_string(),
+            linkBase: "https://linkmou.se/".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn desktop_name(&self, random_id: &str) -> String {
+        format!("{}{}", self.desktopPrefix, random_id)
+    }
+
+    pub fn browser_name(&self, random_id: &str) -> String {
+        format!("{}{}", self.browserPrefix, random_id)
+    }
+
+    pub fn link(&self, random_id: &str) -> String {
+        format!("{}{}", self.linkBase, random_id)
+    }
+
+    fn set(&mut self, key: &str, value: String) -> Result<(), String> {
+        match key {
+            "signaling-url" => self.signalingUrl = value,
+            "desktop-prefix" => self.desktopPrefix = value,
+            "browser-prefix" => self.browserPrefix = value,
+            "link-base" => self.linkBase = value,
+            _ => return Err(format!("Unknown option: --{}", key)),
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: Arc<Mutex<Config>> = Arc::new(Mutex::new(Config::default()));
+}
+
+pub fn get_config() -> Config {
+    CONFIG.lock().unwrap().clone()
+}
+
+pub fn set_config(config: Config) {
+    *CONFIG.lock().unwrap() = config;
+}
+
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("linkmouse").join("config.json"))
+}
+
+// The flags read by load_config, each takes a value
+pub const CONFIG_FLAGS: [&str; 5] = ["config", "signaling-url", "desktop-prefix", "browser-prefix", "link-base"];
+
+// Splits "--key value" and "--key=value" flags for CONFIG_FLAGS. Other
+// arguments are ignored and do not take the next one as a value, the OS or
+// the webview may pass flags of their own.
+fn parse_flags(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut flags = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => continue,
+        };
+        let (key, inline_value) = match flag.split_once('=') {
+            Some((key, value)) => (key, Some(value.to_string())),
+            None => (flag, None),
+        };
+        if !CONFIG_FLAGS.contains(&key) {
+            continue;
+        }
+        match inline_value.or_else(|| args.next().cloned()) {
+            Some(value) => flags.push((key.to_string(), value)),
+            None => return Err(format!("Missing value for --{}", key)),
+        }
+    }
+    Ok(flags)
+}
+
+fn read_config_file(path: &PathBuf) -> Result<Option<Config>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Could not read {}: {}", path.display(), e)),
+    };
+    match serde_json::from_str(&text) {
+        Ok(config) => Ok(Some(config)),
+        Err(e) => Err(format!("Invalid config file {}: {}", path.display(), e)),
+    }
+}
+
+// Builds the config from the CLI arguments (without the program name), the
+// environment and the config file, and makes it the current one
+pub fn load_config(args: &[String]) -> Result<Config, String> {
+    let flags = parse_flags(args)?;
+
+    let path = match flags.iter().find(|(key, _)| key == "config") {
+        Some((_, path)) => Some(PathBuf::from(path)),
+        None => match env::var(CONFIG_ENV) {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => default_config_path(),
+        },
+    };
+    let mut config = match &path {
+        Some(path) => read_config_file(path)?.unwrap_or_default(),
+        None => Config::default(),
+    };
+    if let Some(signaling_url) = get_settings().signalingUrl {
+        config.signalingUrl = signaling_url;
+    }
+
+    for (env_name, key) in [
+        (SIGNALING_URL_ENV, "signaling-url"),
+        (DESKTOP_PREFIX_ENV, "desktop-prefix"),
+        (BROWSER_PREFIX_ENV, "browser-prefix"),
+        (LINK_BASE_ENV, "link-base"),
+    ] {
+        if let Ok(value) = env::var(env_name) {
+            config.set(key, value)?;
+        }
+    }
+    for (key, value) in flags.into_iter().filter(|(key, _)| key != "config") {
+        config.set(&key, value)?;
+    }
+
+    set_config(config.clone());
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn flag(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn parses_known_flags() {
+        let flags = parse_flags(&args(&["--signaling-url", "wss://a.example", "--link-base=https://b.example/", "--config", "c.json"]));
+        assert_eq!(flags, Ok(vec![
+            flag("signaling-url", "wss://a.example"),
+            flag("link-base", "https://b.example/"),
+            flag("config", "c.json"),
+        ]));
+    }
+
+    #[test]
+    fn skips_unknown_flags_without_their_value() {
+        let flags = parse_flags(&args(&["--no-sandbox", "--desktop-prefix", "d_", "-psn_0_123", "--verbose"]));
+        assert_eq!(flags, Ok(vec![flag("desktop-prefix", "d_")]));
+        assert_eq!(parse_flags(&args(&["--unknown=1", "--browser-prefix=b_"])), Ok(vec![flag("browser-prefix", "b_")]));
+    }
+
+    #[test]
+    fn known_flag_needs_a_value() {
+        assert_eq!(parse_flags(&args(&["--link-base"])), Err("Missing value for --link-base".to_string()));
+    }
+}
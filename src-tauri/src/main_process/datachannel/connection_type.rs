@@ -0,0 +1,53 @@
+use serde::{Serialize, Deserialize};
+use ts_rs::TS;
+use webrtc::ice::candidate::{CandidatePairState, CandidateType};
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::stats::{ICECandidatePairStats, ICECandidateStats, StatsReport, StatsReportType};
+
+// How the selected ICE candidate pair reaches the browser, in the order of
+// how much goes in between: the most indirect side of the pair wins.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, TS)]
+#[serde(rename_all = "lowercase")]
+#[ts(export, export_to = "../src/bindings/IceConnectionType.ts")]
+pub enum IceConnectionType {
+    Host, // Same network
+    Prflx, // Learned from the browser during the checks
+    Srflx, // Through NAT, address from STUN
+    Relay, // Through TURN
+}
+
+fn from_candidate_type(candidate_type: CandidateType) -> Option<IceConnectionType> {
+    match candidate_type {
+        CandidateType::Host => Some(IceConnectionType::Host),
+        CandidateType::PeerReflexive => Some(IceConnectionType::Prflx),
+        CandidateType::ServerReflexive => Some(IceConnectionType::Srflx),
+        CandidateType::Relay => Some(IceConnectionType::Relay),
+        CandidateType::Unspecified => None,
+    }
+}
+
+// None until a candidate pair has been nominated
+pub fn selected_pair(stats: &StatsReport) -> Option<&ICECandidatePairStats> {
+    stats.reports.values().find_map(|report| match report {
+        StatsReportType::CandidatePair(pair) if pair.nominated && pair.state == CandidatePairState::Succeeded => Some(pair),
+        _ => None,
+    })
+}
+
+// Local or remote
+pub fn candidate<'a>(stats: &'a StatsReport, id: &str) -> Option<&'a ICECandidateStats> {
+    match stats.reports.get(id) {
+        Some(StatsReportType::LocalCandidate(candidate)) | Some(StatsReportType::RemoteCandidate(candidate)) => Some(candidate),
+        _ => None,
+    }
+}
+
+pub fn connection_type(stats: &StatsReport) -> Option<IceConnectionType> {
+    let pair = selected_pair(stats)?;
+    let candidate_type = |id: &str| candidate(stats, id).and_then(|candidate| from_candidate_type(candidate.candidate_type));
+    candidate_type(&pair.local_candidate_id).max(candidate_type(&pair.remote_candidate_id))
+}
+
+pub async fn selected_connection_type(peer_connection: &RTCPeerConnection) -> Option<IceConnectionType> {
+    connection_type(&peer_connection.get_stats().await)
+}
@@ -5,12 +5,13 @@ use lazy_static::__Deref;
 use serde::{Serialize, Deserialize};
 /* use std::fmt::format;
 use std::io::Write; */
-use std::sync::mpsc::{SyncSender, Receiver};
+use std::sync::mpsc::Receiver;
 use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
 use serde_json::{json, Value};
-use tokio::time::{sleep, Duration};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::APIBuilder;
@@ -22,18 +23,26 @@ use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
-use webrtc::peer_connection::RTCPeerConnection;
 use std::clone::Clone;
-use copypasta::{ClipboardContext, ClipboardProvider};
 
 mod websocket;
+pub mod signaling_error;
+pub mod connection_type;
 use crate::main_process::datachannel::websocket::{WebSocket, CLOSE, CLOSE_IMMEDIATE};
-
-use crate::main_process::messages_to_fe::{CONNECTING_SERVER, SERVER_CONNECTED_WAITING_USER, USER_CONNECTING, USER_CONNECTED, USER_DISCONNECTED};
+use crate::main_process::datachannel::signaling_error::SignalingError;
+use crate::main_process::datachannel::connection_type::selected_connection_type;
+
+use crate::main_process::messages_to_fe::FrontendEvent;
+use crate::main_process::approval::ApprovalState;
+use crate::main_process::held_input::release_held_input;
+use crate::main_process::protocol::{Message, ParseError, MOUSEMOVE_NAME};
+use crate::main_process::stats::{self, STATS_INTERVAL_MS};
+use crate::main_process::clipboard_sync::{self, CLIPBOARD_POLL_MS};
+use crate::main_process::clipboard_formats::{chunk, read_clipboard, read_clipboard_text, ClipboardAction, ClipboardContent};
 use crate::main_process::shared_settings::DESKTOP_INFO;
+use crate::main_process::config::get_config;
+use crate::main_process::session::{current_session, end_session, set_current_session, Session};
 
-//const URL: &str = "ws://localhost:3001";
-const URL: &str = "wss://browserkvm-backend.onrender.com:443";
 const SLEEP_ADD_MS: u64 = 500;
 const SLEEP_MAX_MS: u64 = 5000;
 //const PING_INTERVAL: u64 = 70;
@@ -51,39 +60,115 @@ struct MyRTCIceServer {
     pub credential: String,
 }
 
-lazy_static! {
-    static ref PEER_CONNECTION_MUTEX: Arc<Mutex<Option<Arc<RTCPeerConnection>>>> =
-        Arc::new(Mutex::new(None));
-    static ref PENDING_CANDIDATES: Arc<Mutex<Vec<RTCIceCandidate>>> = Arc::new(Mutex::new(vec![]));
-    static ref TX: Arc<Mutex<Option<SyncSender<String>>>> = Arc::new(Mutex::new(None));
-    static ref RX_STOP_3: Arc<std::sync::Mutex<Option<tokio::sync::mpsc::Receiver<()>>>> = Arc::new(std::sync::Mutex::new(None));
-    static ref ICE_SERVERS: Arc<Mutex<Option<Vec<RTCIceServer>>>> = Arc::new(Mutex::new(None));
-}
-
-pub struct MouseOffset {
-    pub x: i32,
-    pub y: i32,
-}
-
 pub struct PostSleepData {
     pub name: String,
-    pub mouse_offset: MouseOffset,
     pub is_right: bool,
     pub side_position: f64,
     pub is_too_fast: bool,
+    pub response: Option<Message>,
 }
 
-fn handle_copy_cut() -> String{
-    let mut ctx = ClipboardContext::new().unwrap();
-    return format!("copycut,{}", ctx.get_contents().unwrap());
+// For sending from outside of the data channel callbacks, e.g. from Tauri commands
+pub fn is_connected() -> bool {
+    current_session().is_some_and(|session| session.is_connected())
 }
 
-async fn signal_candidate(c: &RTCIceCandidate) -> Result<()> {
+pub fn send_to_browser(message: Message) {
+    match current_session() {
+        Some(session) => session.send_to_browser(message),
+        None => println!("Could not send {}, no session", message.name()),
+    }
+}
+
+// Collects the stats and sends them to the browser until the data channel closes
+fn spawn_connection_stats(session: Weak<Session>, d: Arc<RTCDataChannel>) {
+    tokio::spawn(async move {
+        loop {
+            sleep(Duration::from_millis(STATS_INTERVAL_MS)).await;
+            let session = match session.upgrade() {
+                Some(session) if session.is_connected() => session,
+                _ => break,
+            };
+            let stats = stats::collect(&session).await;
+            // The addresses are in there, only for a browser that was let in
+            if session.is_let_in() {
+                if let Err(e) = d.send_text(Message::ConnectionStats(stats).serialize()).await {
+                    println!("Sending failed: {}", e);
+                }
+            }
+        }
+    });
+}
+
+// copycut, or clipboardchunk messages for other formats if the browser takes them
+fn handle_copy_cut(session: &Session) -> Vec<Message> {
+    if session.browser_info.lock().unwrap().richClipboard {
+        match read_clipboard() {
+            Ok(ClipboardContent::Text(_)) | Err(_) => (),
+            Ok(content) => match chunk(&content, ClipboardAction::CopyCut) {
+                Ok(messages) => {
+                    clipboard_sync::remember(session, &content);
+                    return messages;
+                },
+                Err(e) => println!("Copying as text instead: {}", e),
+            },
+        }
+    }
+
+    match read_clipboard_text() {
+        Ok(text) => {
+            clipboard_sync::remember(session, &ClipboardContent::Text(text.clone()));
+            vec![Message::CopyCut { text }]
+        },
+        Err(e) => {
+            println!("Could not read the clipboard: {}", e);
+            vec![Message::Error { reason: format!("copy: could not read the clipboard: {}", e) }]
+        },
+    }
+}
+
+// Sends the desktop clipboard to the browser when it changes, while sync is on
+fn spawn_clipboard_sync(session: Weak<Session>, d: Arc<RTCDataChannel>) {
+    tokio::spawn(async move {
+        loop {
+            sleep(Duration::from_millis(CLIPBOARD_POLL_MS)).await;
+            let session = match session.upgrade() {
+                Some(session) if session.is_connected() => session,
+                _ => break,
+            };
+            if !(clipboard_sync::is_enabled() && session.permissions.lock().unwrap().clipboardRead && session.is_let_in()) {
+                continue;
+            }
+
+            // The clipboard APIs block, e.g. X11 waits for the owner
+            let rich = session.browser_info.lock().unwrap().richClipboard;
+            let messages = match tokio::task::spawn_blocking(move || clipboard_sync::changed_contents(&session, rich)).await {
+                Ok(Some(ClipboardContent::Text(text))) => vec![Message::Clipboard { text }],
+                Ok(Some(content)) => match chunk(&content, ClipboardAction::Sync) {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        println!("Clipboard not synced: {}", e);
+                        continue;
+                    },
+                },
+                _ => continue,
+            };
+            for message in messages {
+                if let Err(e) = d.send_text(message.serialize()).await {
+                    println!("Sending failed: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+async fn signal_candidate(session: &Session, c: &RTCIceCandidate) -> Result<(), SignalingError> {
     match c.to_json() {
         Ok(j) => {
             let payload = match serde_json::to_string(&j) {
                 Ok(p) => p,
-                Err(err) => panic!("{}", err),
+                Err(err) => return Err(SignalingError::Candidate(err.to_string())),
             };
 
             let signaling_message = &json!(SignalingMessage {
@@ -91,44 +176,146 @@ async fn signal_candidate(c: &RTCIceCandidate) -> Result<()> {
                 value: payload,
             });
 
-            let tx = {
-                let tx = TX.lock().await;
-                tx.clone()
-            };
+            if let Err(e) = session.signal(signaling_message.to_string()).await {
+                println!("Could not send candidate: {}", e);
+            }
+        },
+        Err(err) => return Err(SignalingError::Candidate(err.to_string())),
+    };
 
-            match tx {
-                Some(tx) => match tx.send(signaling_message.to_string()) {
-                    Ok(_) => (),
-                    Err(_) => println!("Could not send candidate 1"),
-                },
-                None => println!("Could not send candidate 2"),
-            }    
+    Ok(())
+}
+
+async fn handle_signaling_message<H>(session: &Session, signaling_message: SignalingMessage, send_event_to_front_end: H) -> Result<(), SignalingError>
+    where
+        H: FnOnce(FrontendEvent) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
+{
+    if signaling_message.key == "iceServers" {
+        println!("ICE SERVERS: {}", signaling_message.value);
+        // Remove the "protection"
+        //let ice_servers_string = signaling_message.value; //.replace('7', "!").replace('0', "7").replace('!', "0"); // '!' is a temporary value
+        let temp_str = &signaling_message.value;
+
+        let temp_value = match serde_json::from_str::<Vec<MyRTCIceServer>>(&temp_str) {
+            Ok(temp_value) => temp_value,
+            Err(err) => return Err(SignalingError::InvalidIceServers(err.to_string())),
+        };
+        println!("temp {:?}", temp_value);
+        let ice_servers : Vec<RTCIceServer> = temp_value.iter().map(|s| {
+            RTCIceServer {
+                urls: vec![s.urls.to_string()],
+                username: s.username.to_string(),
+                credential: s.credential.to_string(),
+                ..Default::default()
+            }
+        }).collect();
+
+        *session.ice_servers.lock().await = Some(ice_servers);
+
+        return Ok(()); // pc not ready yet, must return here
+    }
+
+    let pc = match session.peer_connection.lock().await.clone() {
+        Some(pc) => pc,
+        None => {
+            println!("No peer connection yet for {}", signaling_message.key);
+            return Ok(());
         },
-        Err(_) => println!("Could not send candidate 3"),
     };
 
+
+    if signaling_message.key == "RTCSessionDescription" {
+        println!("SDP");
+        send_event_to_front_end(FrontendEvent::UserConnecting);
+        let sdp_str = &signaling_message.value;
+
+        let sdp = match serde_json::from_str::<RTCSessionDescription>(&sdp_str) {
+            Ok(s) => s,
+            Err(err) => return Err(SignalingError::InvalidSessionDescription(err.to_string())),
+        };
+
+        if let Err(err) = pc.set_remote_description(sdp).await {
+            return Err(SignalingError::RemoteDescription(err.to_string()));
+        }
+
+        // Create an answer to send to the other process
+        let answer = match pc.create_answer(None).await {
+            Ok(a) => a,
+            Err(err) => return Err(SignalingError::CreateAnswer(err.to_string())),
+        };
+
+        // Send our answer to the HTTP server listening in the other process
+        let payload = match serde_json::to_string(&answer) {
+            Ok(p) => p,
+            Err(err) => return Err(SignalingError::CreateAnswer(err.to_string())),
+        };
+
+
+        let signaling_message = &json!(SignalingMessage {
+            key: "RTCSessionDescription".to_string(),
+            value: payload,
+        });
+
+        if let Err(e) = session.signal(signaling_message.to_string()).await {
+            println!("Could not send RTCSessionDescription: {}", e);
+        }
+        // TODO Return here if any failures
+
+        // Sets the LocalDescription, and starts our UDP listeners
+        if let Err(err) = pc.set_local_description(answer).await {
+            return Err(SignalingError::LocalDescription(err.to_string()));
+        }
+
+        {
+            let cs = session.pending_candidates.lock().await;
+            for c in &*cs {
+                signal_candidate(session, c).await?;
+            }
+        }
+    } else if signaling_message.key == "RTCIceCandidate" {
+        println!("CANDIDATE");
+        let candidate_str = &signaling_message.value;
+
+        let candidate = match serde_json::from_str::<RTCIceCandidateInit>(&candidate_str) {
+            Ok(s) => s,
+            Err(err) => return Err(SignalingError::InvalidCandidate(err.to_string())),
+        };
+
+        if let Err(err) = pc
+            .add_ice_candidate(candidate)
+            .await
+        {
+            println!("Could not add_ice_candidate: {}", err);
+        }  
+    } else {
+        println!("Unknown SignalingMessage.key: {}", signaling_message.key);
+    }
     Ok(())
 }
 
+fn report_signaling_error<H>(error: SignalingError, send_event_to_front_end: H)
+    where
+        H: FnOnce(FrontendEvent) -> (),
+{
+    println!("Connection attempt failed, reconnecting: {}", error);
+    send_event_to_front_end(FrontendEvent::SignalingError { reason: error.to_string() });
+}
+
 //#[tokio::main]
 pub async fn process_datachannel_messages<F, G, H>(
     random_id: String,
     on_message_immmediate: F,
     on_message_post_sleep: G,
     recv_stop_2: Receiver<bool>,
-    recv_stop_3: tokio::sync::mpsc::Receiver<()>,
+    mut recv_stop_3: tokio::sync::mpsc::Receiver<()>,
     /* recv_stop_4: Receiver<bool>, */
     send_event_to_front_end: H,
 )
     where
-        F: FnOnce(String) -> (Option<u128>, PostSleepData) + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
-        G: FnOnce(PostSleepData) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
-        H: FnOnce(String) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
+        F: FnOnce(&Arc<Session>, Result<Message, ParseError>) -> (Option<u128>, PostSleepData) + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
+        G: FnOnce(&Session, &PostSleepData) -> Option<u128> + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
+        H: FnOnce(FrontendEvent) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
 {
-    {
-        let mut rx3 = RX_STOP_3.lock().unwrap();
-        *rx3 = Some(recv_stop_3);
-    }
 
     //let background_loop_handler = thread::spawn(|| {
     let mut tries: u64 = 0;
@@ -147,166 +334,97 @@ pub async fn process_datachannel_messages<F, G, H>(
         .await;
         tries += 1;
 
-        let mut websocket = WebSocket::new(URL);
+        let session = Session::new();
+        set_current_session(Some(session.clone()));
+        let config = get_config();
+        let mut websocket = WebSocket::new(&config.signalingUrl);
 
-        println!("websocket: connecting...");
-        send_event_to_front_end(CONNECTING_SERVER.to_string());
-        if let Err(_) = websocket.connect(format!("desktop_{}", random_id)).await {
+        println!("websocket: connecting to {}...", config.signalingUrl);
+        send_event_to_front_end(FrontendEvent::ConnectingServer);
+        if let Err(_) = websocket.connect(config.desktop_name(&random_id)).await {
+            end_session(&session);
             continue;
         };
         tries = 0;
         println!("websocket: ...connected");
-        send_event_to_front_end(SERVER_CONNECTED_WAITING_USER.to_string());
-
-        let on_ws_receive = move | msg: String | async move {
-            println!("websocket: received: {}", msg);
-
-            let signaling_message: SignalingMessage = match serde_json::from_str(&msg) {
-                Ok(signaling_message) => signaling_message,
-                Err(e) => {
-                    println!("Could not serialize websocket message: {}", e);
-                    return;
-                },
-            };
-
-            if signaling_message.key == "iceServers" {
-                println!("ICE SERVERS: {}", signaling_message.value);
-                // Remove the "protection"
-                //let ice_servers_string = signaling_message.value; //.replace('7', "!").replace('0', "7").replace('!', "0"); // '!' is a temporary value
-                let temp_str = &signaling_message.value;
-                
-                let temp_value = serde_json::from_str::<Vec<MyRTCIceServer>>(&temp_str).unwrap();
-                println!("temp {:?}", temp_value);
-                let ice_servers : Vec<RTCIceServer> = temp_value.iter().map(|s| {
-                    RTCIceServer {
-                        urls: vec![s.urls.to_string()],
-                        username: s.username.to_string(),
-                        credential: s.credential.to_string(),
-                        ..Default::default()
-                    }
-                }).collect();
-
-                {
-                    let mut ice_servers_global = ICE_SERVERS.lock().await;
-                    *ice_servers_global = Some(ice_servers);
-                }
-            
-                return; // pc not ready yet, must return here
-            }
-            
-            let pc = {
-                let pcm = PEER_CONNECTION_MUTEX.lock().await;
-                pcm.clone().unwrap()
-            };
-            
-            
-            if signaling_message.key == "RTCSessionDescription" {
-                println!("SDP");
-                send_event_to_front_end(USER_CONNECTING.to_string());
-                let sdp_str = &signaling_message.value;
-
-                let sdp = match serde_json::from_str::<RTCSessionDescription>(&sdp_str) {
-                    Ok(s) => s,
-                    Err(err) => panic!("{}", err),
-                };
-    
-                if let Err(err) = pc.set_remote_description(sdp).await {
-                    panic!("{}", err);
-                }
-    
-                // Create an answer to send to the other process
-                let answer = match pc.create_answer(None).await {
-                    Ok(a) => a,
-                    Err(err) => panic!("{}", err),
-                };
-    
-                // Send our answer to the HTTP server listening in the other process
-                let payload = match serde_json::to_string(&answer) {
-                    Ok(p) => p,
-                    Err(err) => panic!("{}", err),
+        send_event_to_front_end(FrontendEvent::ServerConnectedWaitingUser);
+
+        let session_ws = Arc::downgrade(&session);
+        let on_ws_receive = move | msg: String | {
+            let session_ws = session_ws.clone();
+            async move {
+                let session = match session_ws.upgrade() {
+                    Some(session) => session,
+                    None => return, // The connection attempt has ended
                 };
-    
-    
-                let tx = {
-                    let tx = TX.lock().await;
-                    tx.clone()
-                };
-            
-                let signaling_message = &json!(SignalingMessage {
-                    key: "RTCSessionDescription".to_string(),
-                    value: payload,
-                });
-    
-                match tx {
-                    Some(tx) => match tx.send(signaling_message.to_string()) {
-                        Ok(_) => (),
-                        Err(_) => println!("Could not send RTCSessionDescription 1"),
-                    },
-                    None => println!("Could not send RTCSessionDescription 2"),
-                }    
-                // TODO Return here if any failures
+                println!("websocket: received: {}", msg);
 
-                // Sets the LocalDescription, and starts our UDP listeners
-                if let Err(err) = pc.set_local_description(answer).await {
-                    panic!("{}", err);
-                }
-    
-                {
-                    let cs = PENDING_CANDIDATES.lock().await;
-                    for c in &*cs {
-                        if let Err(err) = signal_candidate(c).await {
-                            panic!("{}", err);
-                        }
-                    }
-                }
-            } else if signaling_message.key == "RTCIceCandidate" {
-                println!("CANDIDATE");
-                let candidate_str = &signaling_message.value;
-
-                let candidate = match serde_json::from_str::<RTCIceCandidateInit>(&candidate_str) {
-                    Ok(s) => s,
-                    Err(err) => panic!("{}", err),
+                let signaling_message: SignalingMessage = match serde_json::from_str(&msg) {
+                    Ok(signaling_message) => signaling_message,
+                    Err(e) => {
+                        println!("Could not serialize websocket message: {}", e);
+                        return;
+                    },
                 };
 
-                if let Err(err) = pc
-                    .add_ice_candidate(candidate)
-                    .await
-                {
-                    println!("Could not add_ice_candidate: {}", err);
-                }  
-            } else {
-                println!("Unknown SignalingMessage.key: {}", signaling_message.key);
-            }
-        }.boxed();
+                if let Err(error) = handle_signaling_message(&session, signaling_message, send_event_to_front_end).await {
+                    session.fail(error);
+                }
+            }.boxed()
+        };
 
-        let (handle, tx) = websocket::start_send_receive_thread(websocket, &format!("browser_{}", random_id).to_string(), on_ws_receive, send_event_to_front_end).await;
+        let (handle, tx) = websocket::start_send_receive_thread(websocket, &config.browser_name(&random_id), on_ws_receive, send_event_to_front_end).await;
         
-        loop {
+        let error = loop {
             {
-                if let Some(_ice_servers) = ICE_SERVERS.lock().await.clone() {
-                    break;
+                if let Some(_ice_servers) = session.ice_servers.lock().await.clone() {
+                    break None;
                 }
             }
             println!("Not yet...");
             // TODO allow close even when not connected to ws...
-            sleep(Duration::from_millis(1000)).await;
+            tokio::select! {
+                _ = sleep(Duration::from_millis(1000)) => (),
+                Some(error) = session.next_error() => break Some(error),
+            }
+        };
+        if let Some(error) = error {
+            report_signaling_error(error, send_event_to_front_end);
+            if tx.send(CLOSE_IMMEDIATE.to_string()).is_err() {
+                println!("Could not send CLOSE");
+            }
+            if let Err(e) = handle.await {
+                println!("Handle await error {}", e);
+            }
+            end_session(&session);
+            continue;
         }
         println!("READY");
         
-        {
-            let mut tx2 = TX.lock().await;
-            *tx2 = Some(tx);
-        }
+        *session.signaling_tx.lock().await = Some(tx);
 
-        let result = connect_datachannel_and_process_messages(
+        let result = match connect_datachannel_and_process_messages(
+            session.clone(),
+            &mut recv_stop_3,
             on_message_immmediate,
             on_message_post_sleep,
             send_event_to_front_end,
-        ).await.unwrap();
+        ).await {
+            Ok(result) => result,
+            Err(e) => {
+                report_signaling_error(SignalingError::PeerConnection(e.to_string()), send_event_to_front_end);
+                // The websocket thread would wait for this forever
+                if let Err(e) = session.signal(CLOSE_IMMEDIATE.to_string()).await {
+                    println!("Could not send CLOSE: {}", e);
+                }
+                "DISCONNECT".to_string()
+            },
+        };
 
         if let Err(e) = handle.await {
             println!("Handle await error {}", e);
         }
+        end_session(&session);
 
         if result == "CTRLC".to_string() {
             println!("breaking");
@@ -322,14 +440,16 @@ pub async fn process_datachannel_messages<F, G, H>(
 }
 
 async fn connect_datachannel_and_process_messages<F, G, H>(
+    session: Arc<Session>,
+    recv_stop_3: &mut tokio::sync::mpsc::Receiver<()>,
     on_message_immmediate: F,
     on_message_post_sleep: G,
     send_event_to_front_end: H,
 ) -> Result<String>
 where
-    F: FnOnce(String) -> (Option<u128>, PostSleepData) + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
-    G: FnOnce(PostSleepData) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
-    H: FnOnce(String) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
+    F: FnOnce(&Arc<Session>, Result<Message, ParseError>) -> (Option<u128>, PostSleepData) + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
+    G: FnOnce(&Session, &PostSleepData) -> Option<u128> + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
+    H: FnOnce(FrontendEvent) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
 {
     /* let debug = matches.is_present("debug");
     if debug {
@@ -349,7 +469,7 @@ where
             .init();
     } */
 
-    let ice_servers = ICE_SERVERS.lock().await.clone().unwrap();
+    let ice_servers = session.ice_servers.lock().await.clone().unwrap();
     println!("ICE_SERVERS 2 {:?}", ice_servers);
 
     println!("NORMAL : {:?}", vec![RTCIceServer {
@@ -383,22 +503,24 @@ where
 
     // When an ICE candidate is available send to the other Pion instance
     // the other Pion instance will add this candidate by calling AddICECandidate
+    // The callbacks are owned by the peer connection, which the session owns,
+    // so they only hold a Weak to the session
     let pc = Arc::downgrade(&peer_connection);
-    let pending_candidates2 = Arc::clone(&PENDING_CANDIDATES);
+    let session_candidate: Weak<Session> = Arc::downgrade(&session);
     peer_connection.on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
         println!("on_ice_candidate");
 
         let pc2 = pc.clone();
-        let pending_candidates3 = Arc::clone(&pending_candidates2);
+        let session_candidate2 = session_candidate.clone();
         Box::pin(async move {
-            if let Some(c) = c {
+            if let (Some(c), Some(session)) = (c, session_candidate2.upgrade()) {
                 if let Some(pc) = pc2.upgrade() {
                     let desc = pc.remote_description().await;
                     if desc.is_none() {
-                        let mut cs = pending_candidates3.lock().await;
+                        let mut cs = session.pending_candidates.lock().await;
                         cs.push(c);
-                    } else if let Err(err) = signal_candidate(&c).await {
-                        panic!("{}", err);
+                    } else if let Err(err) = signal_candidate(&session, &c).await {
+                        session.fail(err);
                     }
                 }
             }
@@ -415,17 +537,13 @@ where
         },
     ));
 
-    {
-        let mut pcm = PEER_CONNECTION_MUTEX.lock().await;
-        *pcm = Some(Arc::clone(&peer_connection));
-    }
+    *session.peer_connection.lock().await = Some(Arc::clone(&peer_connection));
 
     let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<()>(1);
 
-    let tx2 = {
-        let tx = TX.lock().await;
-        tx.clone()
-    };
+    let tx2 = session.signaling_tx.lock().await.clone();
+    let pc_state = Arc::downgrade(&peer_connection);
+    let session_state = Arc::downgrade(&session);
 
     // Set the handler for Peer connection state
     // This will notify you when the peer has connected/disconnected
@@ -449,17 +567,49 @@ where
                 None => println!("Could not send CLOSE 2"),
             }
         }
+
+        if s == RTCPeerConnectionState::Connected {
+            // For USER_CONNECTED, the data channel opens after this. Not
+            // awaited here, the peer connection is still changing state.
+            let pc2 = pc_state.clone();
+            let session_state2 = session_state.clone();
+            tokio::spawn(async move {
+                if let (Some(pc), Some(session)) = (pc2.upgrade(), session_state2.upgrade()) {
+                    let connection_type = selected_connection_type(&pc).await;
+                    println!("Connection type: {:?}", connection_type);
+                    *session.connection_type.lock().unwrap() = connection_type;
+                }
+            });
+        }
         Box::pin(async {})
     }));
 
     let (done_tx2, mut done_rx2) = tokio::sync::mpsc::channel::<()>(1);
 
+    let (disconnect_tx, mut disconnect_rx) = tokio::sync::mpsc::channel::<()>(1);
+    *session.disconnect_tx.lock().unwrap() = Some(disconnect_tx);
+    let runtime_handle = tokio::runtime::Handle::current();
+    let session_data_channel = Arc::downgrade(&session);
+
     // Register data channel creation handling
-    peer_connection.on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
+    peer_connection.on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| -> Pin<Box<dyn Future<Output = ()> + Send>> {
         let d_label = d.label().to_owned();
         let d_id = d.id();
         println!("New DataChannel {d_label} {d_id}");
-        send_event_to_front_end(USER_CONNECTED.to_string());
+        let session_messages = session_data_channel.clone();
+        let session_close = session_data_channel.clone();
+        let session_stats = session_data_channel.clone();
+        let session = match session_data_channel.upgrade() {
+            Some(session) => session,
+            None => return Box::pin(async {}), // The connection has ended
+        };
+        *session.data_channel.lock().unwrap() = Some((runtime_handle.clone(), d.clone()));
+        let (approval_state, pin_challenge) = session.start_browser();
+        let permissions = *session.permissions.lock().unwrap();
+        match approval_state {
+            ApprovalState::Accepted => send_event_to_front_end(session.user_connected()),
+            _ => send_event_to_front_end(FrontendEvent::UserApprovalPending { browserInfo: session.browser_info.lock().unwrap().clone() }),
+        }
 
         let done_tx2_clone = done_tx2.clone();
 
@@ -480,13 +630,37 @@ where
                 Box::pin(async move {
                     {   
                         let desktop_info = DESKTOP_INFO.lock().unwrap().clone();
-                        let desktop_info_json = serde_json::to_string(&desktop_info).unwrap();
 
-                        if let Err(e) = d2.send_text(format!("desktopinfo,{}", desktop_info_json).to_string()).await {
+                        if let Err(e) = d2.send_text(Message::DesktopInfo(desktop_info).serialize()).await {
+                            println!("Sending failed: {}", e);
+                        };
+                    }
+
+                    let approval_message = match approval_state {
+                        ApprovalState::Accepted => Message::ApprovalAccepted,
+                        _ => Message::ApprovalPending,
+                    };
+                    if let Err(e) = d2.send_text(approval_message.serialize()).await {
+                        println!("Sending failed: {}", e);
+                    };
+
+                    if let Err(e) = d2.send_text(Message::Permissions(permissions).serialize()).await {
+                        println!("Sending failed: {}", e);
+                    };
+
+                    if let Some(challenge) = pin_challenge {
+                        if let Err(e) = d2.send_text(Message::PinChallenge { challenge }.serialize()).await {
                             println!("Sending failed: {}", e);
                         };
                     }
 
+                    if let Err(e) = d2.send_text(Message::ClipboardSync { enabled: clipboard_sync::is_enabled() }.serialize()).await {
+                        println!("Sending failed: {}", e);
+                    };
+
+                    spawn_clipboard_sync(session_stats.clone(), d2.clone());
+                    spawn_connection_stats(session_stats, d2.clone());
+
 
                     /* let mut result = Result::<usize>::Ok(0);
                     while result.is_ok() {
@@ -506,59 +680,85 @@ where
 
             let d_clone = d.clone();
             // Register text message handling
-            d.on_message(Box::new(move |msg: DataChannelMessage| {
-                let msg_str = String::from_utf8(msg.data.to_vec()).unwrap();
+            d.on_message(Box::new(move |msg: DataChannelMessage| -> Pin<Box<dyn Future<Output = ()> + Send>> {
+                let session = match session_messages.upgrade() {
+                    Some(session) => session,
+                    None => return Box::pin(async {}), // The connection has ended
+                };
+                let message = if msg.is_string {
+                    match std::str::from_utf8(&msg.data) {
+                        Ok(msg_str) => Message::parse(msg_str),
+                        Err(_) => Err(ParseError::InvalidUtf8),
+                    }
+                } else if session.browser_info.lock().unwrap().binaryProtocol {
+                    Message::parse_binary(&msg.data)
+                } else {
+                    Err(ParseError::BinaryNotNegotiated)
+                };
                 //println!("Message from DataChannel '{d_label}': '{msg_str}'");
 
-                let (sleep_amount, post_sleep_data) = on_message_immmediate(msg_str.into());
+                let started = Instant::now();
+                let (sleep_amount, post_sleep_data) = on_message_immmediate(&session, message);
+                session.input_stats.lock().unwrap().count_event(post_sleep_data.name == MOUSEMOVE_NAME);
 
                 let d_clone2 = d_clone.clone();
 
                 Box::pin(async move {
+                    if let Some(response) = &post_sleep_data.response {
+                        if let Err(e) = d_clone2.send_text(response.serialize()).await {
+                            println!("Sending failed: {}", e);
+                        };
+                    }
+
                     if post_sleep_data.is_right {
-                        if let Err(e) = d_clone2.send_text(format!("mouseright,{}", post_sleep_data.side_position).to_string()).await {
+                        if let Err(e) = d_clone2.send_text(Message::MouseRight { side_position: post_sleep_data.side_position }.serialize()).await {
                             println!("Sending failed: {}", e);
                         };
                     }
 
                     if post_sleep_data.is_too_fast {
-                        if let Err(e) = d_clone2.send_text("toofast".to_string()).await {
+                        if let Err(e) = d_clone2.send_text(Message::TooFast.serialize()).await {
                             println!("Sending failed: {}", e);
                         };
                     }
 
                     if let Some(sleep_amount) = sleep_amount {
-                        if post_sleep_data.name == "mousemove" && post_sleep_data.mouse_offset.x == 0 && post_sleep_data.mouse_offset.y == 0 {
-                            //println!("Zero move sleep skipped")
-                        } else {
-                            sleep(Duration::from_nanos(sleep_amount.try_into().unwrap())).await;
-                        }
+                        sleep(Duration::from_nanos(sleep_amount.try_into().unwrap())).await;
                     }
                     if post_sleep_data.name == "copy" || post_sleep_data.name == "cut"{
-                        let response = handle_copy_cut();
-                        if let Err(e) = d_clone2.send_text(response).await {
-                            print!("Could not send clipboard data: {}", e);
+                        for response in handle_copy_cut(&session) {
+                            if let Err(e) = d_clone2.send_text(response.serialize()).await {
+                                print!("Could not send clipboard data: {}", e);
+                                break;
+                            }
                         }
                     }
-                    on_message_post_sleep(post_sleep_data);
+                    // Mouse moves can have several steps
+                    let mut next_sleep = on_message_post_sleep(&session, &post_sleep_data);
+                    while let Some(sleep_amount) = next_sleep {
+                        sleep(Duration::from_nanos(sleep_amount.try_into().unwrap())).await;
+                        next_sleep = on_message_post_sleep(&session, &post_sleep_data);
+                    }
+                    session.input_stats.lock().unwrap().count_handled(started.elapsed());
                 })
             }));
 
             d.on_close(Box::new(move || {
                 println!("DC CLOSE");
                 let _ = done_tx2_clone.try_send(());
-                send_event_to_front_end(USER_DISCONNECTED.to_string());
+                let event = match session_close.upgrade() {
+                    Some(session) => {
+                        release_held_input(&session, "data channel closed");
+                        session.user_disconnected()
+                    },
+                    None => FrontendEvent::UserDisconnected { sessionDurationMs: None },
+                };
+                send_event_to_front_end(event);
                 Box::pin(async{})
             }));
         })
     }));
 
-    let mut a = {
-        let rx3_mutex = RX_STOP_3.lock();
-        rx3_mutex.unwrap()
-    };
-    let b = a.as_mut().unwrap();
-
     println!("Press ctrl-c to stop");
     let result = tokio::select! {
         _ = done_rx.recv() => {
@@ -569,31 +769,40 @@ where
             println!("received done signal! 2");
             "DISCONNECT"
         }
-        _ = b.recv() => {
+        _ = recv_stop_3.recv() => {
             println!("received done signal! 3");
             "DISCONNECT"
         }
+        _ = disconnect_rx.recv() => {
+            println!("received disconnect signal");
+            "DISCONNECT"
+        }
+        Some(error) = session.next_error() => {
+            report_signaling_error(error, send_event_to_front_end);
+            "DISCONNECT"
+        }
         _ = tokio::signal::ctrl_c() => {
             println!("CTRLC");
             "CTRLC"
         }
     };
 
-    let tx = {
-        let tx = TX.lock().await;
-        tx.clone()
-    };
-    
-    match tx {
-        Some(tx) => match tx.send(CLOSE_IMMEDIATE.to_string()) {
-            Ok(_) => (),
-            Err(_) => println!("Could not send CLOSE"),
-        },
-        None => println!("Could not send CLOSE"),
+    if let Err(e) = session.signal(CLOSE_IMMEDIATE.to_string()).await {
+        println!("Could not send CLOSE: {}", e);
     }
 
+    // The data channel does not always get on_close, e.g. when the peer connection fails
+    release_held_input(&session, "peer connection closing");
+    {
+        *session.data_channel.lock().unwrap() = None;
+        *session.disconnect_tx.lock().unwrap() = None;
+    }
+    *session.peer_connection.lock().await = None;
+
     println!("closing peer");
-    peer_connection.close().await?;
+    if let Err(e) = peer_connection.close().await {
+        println!("Could not close peer: {}", e);
+    }
     println!("closed peer");
 
     Ok(result.to_string())
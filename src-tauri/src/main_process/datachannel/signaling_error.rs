@@ -0,0 +1,31 @@
+use std::fmt;
+
+// Failures while negotiating the WebRTC connection through the signaling
+// server. They can be caused by the server or by the browser, so they end the
+// connection attempt and a new one is started, instead of panicking.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignalingError {
+    InvalidIceServers(String),
+    InvalidSessionDescription(String),
+    RemoteDescription(String),
+    CreateAnswer(String),
+    LocalDescription(String),
+    InvalidCandidate(String),
+    Candidate(String), // Our own candidate could not be sent
+    PeerConnection(String), // Creating the peer connection failed
+}
+
+impl fmt::Display for SignalingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignalingError::InvalidIceServers(error) => write!(f, "invalid ICE servers from the signaling server: {}", error),
+            SignalingError::InvalidSessionDescription(error) => write!(f, "invalid session description from the browser: {}", error),
+            SignalingError::RemoteDescription(error) => write!(f, "could not set the remote description: {}", error),
+            SignalingError::CreateAnswer(error) => write!(f, "could not create an answer: {}", error),
+            SignalingError::LocalDescription(error) => write!(f, "could not set the local description: {}", error),
+            SignalingError::InvalidCandidate(error) => write!(f, "invalid ICE candidate from the browser: {}", error),
+            SignalingError::Candidate(error) => write!(f, "could not send an ICE candidate: {}", error),
+            SignalingError::PeerConnection(error) => write!(f, "could not create the peer connection: {}", error),
+        }
+    }
+}
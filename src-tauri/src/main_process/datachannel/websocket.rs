@@ -14,7 +14,7 @@ use tungstenite::Error;
 use tungstenite::Message;
 use tokio::time::{sleep, Duration};
 
-use crate::main_process::messages_to_fe::SERVER_DISCONNECTED;
+use crate::main_process::messages_to_fe::FrontendEvent;
 
 pub struct WebSocket {
     write: Option<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>,
@@ -164,8 +164,8 @@ pub async fn start_send_receive_thread<C, H>(
     send_event_to_front_end: H,
 ) -> (tokio::task::JoinHandle<()>, SyncSender<std::string::String>)
 where
-    C: FnOnce(String) -> BoxFuture<'static, ()> + 'static + std::marker::Copy + std::marker::Send,
-    H: FnOnce(String) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
+    C: Fn(String) -> BoxFuture<'static, ()> + 'static + std::marker::Send,
+    H: FnOnce(FrontendEvent) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
     // BoxFuture tip from here: https://www.bitfalter.com/async-closures
 {
 
@@ -230,7 +230,7 @@ where
                 msg = read_message(&mut websocket) => {
                     if msg.disconnected {
                         println!("DISCONNECTEDDDDDDD");
-                        send_event_to_front_end(SERVER_DISCONNECTED.to_string());
+                        send_event_to_front_end(FrontendEvent::ServerDisconnected);
                         wait(2 * WEBSOCKET_MESSAGE_CHECK_DELAY).await;
                     } else {
                         match msg.msg {
@@ -0,0 +1,70 @@
+// Keeps track of the keys and mouse buttons the browser is holding down, so
+// they can be released if the browser goes away without sending keyup/mouseup.
+// Otherwise e.g. Ctrl would stay pressed on the desktop after a disconnect.
+
+use std::collections::HashSet;
+
+use rdev::EventType;
+
+use crate::main_process::session::Session;
+use crate::main_process::{CODE_TO_RDEV_BUTTON, CODE_TO_RDEV_KEY};
+
+// One per session
+pub struct HeldInput {
+    keys: HashSet<String>,
+    buttons: HashSet<i32>,
+}
+
+impl HeldInput {
+    pub fn new() -> Self {
+        HeldInput { keys: HashSet::new(), buttons: HashSet::new() }
+    }
+
+    pub fn key_pressed(&mut self, code: &str) {
+        self.keys.insert(code.to_string());
+    }
+
+    pub fn key_released(&mut self, code: &str) {
+        self.keys.remove(code);
+    }
+
+    pub fn button_pressed(&mut self, button: i32) {
+        self.buttons.insert(button);
+    }
+
+    pub fn button_released(&mut self, button: i32) {
+        self.buttons.remove(&button);
+    }
+
+    pub fn is_key_held(&self, code: &str) -> bool {
+        self.keys.contains(code)
+    }
+
+    pub fn held_keys(&self) -> Vec<String> {
+        self.keys.iter().cloned().collect()
+    }
+}
+
+pub fn release_held_input(session: &Session, reason: &str) {
+    let (keys, buttons): (Vec<String>, Vec<i32>) = {
+        let mut held_input = session.held_input.lock().unwrap();
+        (held_input.keys.drain().collect(), held_input.buttons.drain().collect())
+    };
+
+    if keys.is_empty() && buttons.is_empty() {
+        return;
+    }
+    println!("Releasing held keys {:?} and buttons {:?} ({})", keys, buttons, reason);
+
+    let sink = session.input_sink();
+    for code in keys {
+        if let Some(key) = CODE_TO_RDEV_KEY.get(code.as_str()) {
+            sink.send(&EventType::KeyRelease(*key));
+        }
+    }
+    for button in buttons {
+        if let Some(button) = CODE_TO_RDEV_BUTTON.get(&button) {
+            sink.send(&EventType::ButtonRelease(*button));
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// Everything injected into the OS goes through an InputSink.
+//
+// RdevSink is the default. On Linux, UinputSink injects through /dev/uinput
+// instead, which also works on Wayland and without a display server.
+// RdevSink needs the desktop feature (on with gui), without it uinput is the
+// default and nothing is injected until it is selected.
+// RecordingSink keeps the injected events in memory, so the message
+// handling can be run on machines without a display.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+#[cfg(feature = "desktop")]
+use enigo::{Enigo, Keyboard, Settings};
+use rdev::EventType;
+#[cfg(feature = "desktop")]
+use rdev::{SimulateError, simulate};
+
+#[cfg(target_os = "linux")]
+pub mod uinput;
+
+// Selects the backend at startup, "rdev" or "uinput"
+pub const INPUT_BACKEND_ENV: &str = "LINKMOUSE_INPUT_BACKEND";
+#[cfg(feature = "desktop")]
+pub const DEFAULT_INPUT_BACKEND: &str = "rdev";
+#[cfg(not(feature = "desktop"))]
+pub const DEFAULT_INPUT_BACKEND: &str = "uinput";
+
+pub trait InputSink: Send + Sync {
+    fn send(&self, event_type: &EventType);
+    fn mouse_move_relative(&self, x: i32, y: i32);
+    fn scroll_lines(&self, x: f64, y: f64);
+    fn scroll_pixels(&self, x: f64, y: f64);
+    // Characters regardless of the keyboard layout, see text_input.rs.
+    // Not every backend can do this.
+    fn text(&self, text: &str) -> Result<(), String>;
+}
+
+#[cfg(feature = "desktop")]
+pub struct RdevSink;
+
+#[cfg(feature = "desktop")]
+impl InputSink for RdevSink {
+    fn send(&self, event_type: &EventType) {
+        match simulate(event_type) {
+            Ok(()) => (),
+            Err(SimulateError) => {
+                println!("We could not send {:?}", event_type);
+            }
+        }
+    }
+
+    fn mouse_move_relative(&self, x: i32, y: i32) {
+        rdev::mouse_move_relative(x, y, false);
+    }
+
+    fn scroll_lines(&self, x: f64, y: f64) {
+        rdev::scroll_lines(x, y);
+    }
+
+    fn scroll_pixels(&self, x: f64, y: f64) {
+        rdev::scroll_pixels(x, y);
+    }
+
+    // rdev only presses keys. enigo sends Unicode key events on Windows and
+    // macOS, and remaps a spare keycode to each character on X11.
+    fn text(&self, text: &str) -> Result<(), String> {
+        Enigo::new(&Settings::default())
+            .map_err(|e| e.to_string())
+            .and_then(|mut enigo| enigo.text(text).map_err(|e| e.to_string()))
+            .map_err(|e| format!("textinput: {}", e))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InjectedEvent {
+    Event(EventType),
+    MouseMoveRelative { x: i32, y: i32 },
+    ScrollLines { x: f64, y: f64 },
+    ScrollPixels { x: f64, y: f64 },
+    Text(String),
+}
+
+pub struct RecordingSink {
+    events: Mutex<Vec<InjectedEvent>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        RecordingSink { events: Mutex::new(vec![]) }
+    }
+
+    pub fn events(&self) -> Vec<InjectedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    // Returns the recorded events and starts a new recording
+    pub fn take_events(&self) -> Vec<InjectedEvent> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+
+    fn record(&self, event: InjectedEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl InputSink for RecordingSink {
+    fn send(&self, event_type: &EventType) {
+        self.record(InjectedEvent::Event(*event_type));
+    }
+
+    fn mouse_move_relative(&self, x: i32, y: i32) {
+        self.record(InjectedEvent::MouseMoveRelative { x, y });
+    }
+
+    fn scroll_lines(&self, x: f64, y: f64) {
+        self.record(InjectedEvent::ScrollLines { x, y });
+    }
+
+    fn scroll_pixels(&self, x: f64, y: f64) {
+        self.record(InjectedEvent::ScrollPixels { x, y });
+    }
+
+    fn text(&self, text: &str) -> Result<(), String> {
+        self.record(InjectedEvent::Text(text.to_string()));
+        Ok(())
+    }
+}
+
+// Until a backend is selected, without the desktop feature
+#[cfg(not(feature = "desktop"))]
+struct NoSink;
+
+#[cfg(not(feature = "desktop"))]
+impl InputSink for NoSink {
+    fn send(&self, _event_type: &EventType) {}
+    fn mouse_move_relative(&self, _x: i32, _y: i32) {}
+    fn scroll_lines(&self, _x: f64, _y: f64) {}
+    fn scroll_pixels(&self, _x: f64, _y: f64) {}
+    fn text(&self, _text: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "desktop")]
+fn default_input_sink() -> Arc<dyn InputSink> {
+    Arc::new(RdevSink)
+}
+
+#[cfg(not(feature = "desktop"))]
+fn default_input_sink() -> Arc<dyn InputSink> {
+    Arc::new(NoSink)
+}
+
+lazy_static! {
+    static ref INPUT_SINK: Arc<RwLock<Arc<dyn InputSink>>> = Arc::new(RwLock::new(default_input_sink()));
+}
+
+pub fn input_sink() -> Arc<dyn InputSink> {
+    INPUT_SINK.read().unwrap().clone()
+}
+
+// Should be called before main_process, changing the sink mid-session
+// could leave keys pressed on the previous one
+pub fn set_input_sink(sink: Arc<dyn InputSink>) {
+    *INPUT_SINK.write().unwrap() = sink;
+}
+
+pub fn select_input_backend(name: &str) -> Result<(), String> {
+    let sink: Arc<dyn InputSink> = match name {
+        #[cfg(feature = "desktop")]
+        "rdev" => Arc::new(RdevSink),
+        #[cfg(not(feature = "desktop"))]
+        "rdev" => return Err("The rdev input backend needs the desktop feature".to_string()),
+        #[cfg(target_os = "linux")]
+        "uinput" => match uinput::UinputSink::new() {
+            Ok(sink) => Arc::new(sink),
+            Err(e) => return Err(format!("Could not create uinput devices: {}", e)),
+        },
+        _ => return Err(format!("Unknown input backend: {}", name)),
+    };
+    set_input_sink(sink);
+    Ok(())
+}
@@ -0,0 +1,363 @@
+// Injects input through /dev/uinput, which works on X11, Wayland and
+// without any display server. The user needs write access to /dev/uinput,
+// for example through the `input` group or a udev rule.
+//
+// Two virtual devices are created: a keyboard and a mouse with a
+// high-resolution wheel. They are classified better by libinput than one
+// device that claims to be both.
+//
+// uinput only has keycodes, and which character a keycode types depends on
+// the layout of the desktop, so text cannot be typed: `textinput`,
+// compositions and character key mode return an error (see text_input.rs).
+
+use std::io;
+use std::sync::Mutex;
+
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, InputEvent, RelativeAxisType};
+use rdev::{Button, EventType, Key};
+
+use crate::main_process::CODE_TO_RDEV_KEY;
+use crate::main_process::input_sink::InputSink;
+
+// One wheel notch, https://www.kernel.org/doc/html/latest/input/event-codes.html#ev-rel
+const HI_RES_PER_LINE: f64 = 120.0;
+const PIXELS_PER_LINE: f64 = 17.0; // Same as WHEEL_LINE_IN_PIXELS
+// A hundred notches, more than any wheel turns between two messages
+const MAX_HI_RES_PER_EVENT: f64 = 100.0 * HI_RES_PER_LINE;
+
+const KEY_PRESS: i32 = 1;
+const KEY_RELEASE: i32 = 0;
+
+struct WheelRemainders {
+    x: f64,
+    y: f64,
+    x_hi_res: i32,
+    y_hi_res: i32,
+}
+
+// Adds the delta to the remainders of one axis, returns the hi-res units and
+// the full notches to send. Huge deltas are clamped and NaN or infinities
+// ignored, they would overflow or stick in the remainders for good.
+fn wheel_axis(delta: f64, remainder: &mut f64, hi_res_remainder: &mut i32) -> (i32, i32) {
+    if !delta.is_finite() {
+        return (0, 0);
+    }
+    let delta = delta.clamp(-MAX_HI_RES_PER_EVENT, MAX_HI_RES_PER_EVENT) + *remainder;
+    let hi_res = delta.trunc() as i32;
+    *remainder = delta.fract();
+
+    *hi_res_remainder += hi_res;
+    let notches = *hi_res_remainder / HI_RES_PER_LINE as i32;
+    *hi_res_remainder -= notches * HI_RES_PER_LINE as i32;
+    (hi_res, notches)
+}
+
+pub struct UinputSink {
+    keyboard: Mutex<VirtualDevice>,
+    mouse: Mutex<VirtualDevice>,
+    wheel_remainders: Mutex<WheelRemainders>,
+}
+
+fn rdev_key_to_evdev(key: &Key) -> Option<evdev::Key> {
+    let key = match key {
+        Key::Alt => evdev::Key::KEY_LEFTALT,
+        Key::AltGr => evdev::Key::KEY_RIGHTALT,
+        Key::Backspace => evdev::Key::KEY_BACKSPACE,
+        Key::CapsLock => evdev::Key::KEY_CAPSLOCK,
+        Key::ControlLeft => evdev::Key::KEY_LEFTCTRL,
+        Key::ControlRight => evdev::Key::KEY_RIGHTCTRL,
+        Key::Delete => evdev::Key::KEY_DELETE,
+        Key::DownArrow => evdev::Key::KEY_DOWN,
+        Key::End => evdev::Key::KEY_END,
+        Key::Escape => evdev::Key::KEY_ESC,
+        Key::F1 => evdev::Key::KEY_F1,
+        Key::F2 => evdev::Key::KEY_F2,
+        Key::F3 => evdev::Key::KEY_F3,
+        Key::F4 => evdev::Key::KEY_F4,
+        Key::F5 => evdev::Key::KEY_F5,
+        Key::F6 => evdev::Key::KEY_F6,
+        Key::F7 => evdev::Key::KEY_F7,
+        Key::F8 => evdev::Key::KEY_F8,
+        Key::F9 => evdev::Key::KEY_F9,
+        Key::F10 => evdev::Key::KEY_F10,
+        Key::F11 => evdev::Key::KEY_F11,
+        Key::F12 => evdev::Key::KEY_F12,
+        Key::Home => evdev::Key::KEY_HOME,
+        Key::LeftArrow => evdev::Key::KEY_LEFT,
+        Key::MetaLeft => evdev::Key::KEY_LEFTMETA,
+        Key::MetaRight => evdev::Key::KEY_RIGHTMETA,
+        Key::PageDown => evdev::Key::KEY_PAGEDOWN,
+        Key::PageUp => evdev::Key::KEY_PAGEUP,
+        Key::Return => evdev::Key::KEY_ENTER,
+        Key::RightArrow => evdev::Key::KEY_RIGHT,
+        Key::ShiftLeft => evdev::Key::KEY_LEFTSHIFT,
+        Key::ShiftRight => evdev::Key::KEY_RIGHTSHIFT,
+        Key::Space => evdev::Key::KEY_SPACE,
+        Key::Tab => evdev::Key::KEY_TAB,
+        Key::UpArrow => evdev::Key::KEY_UP,
+        Key::PrintScreen => evdev::Key::KEY_SYSRQ,
+        Key::ScrollLock => evdev::Key::KEY_SCROLLLOCK,
+        Key::Pause => evdev::Key::KEY_PAUSE,
+        Key::NumLock => evdev::Key::KEY_NUMLOCK,
+        Key::BackQuote => evdev::Key::KEY_GRAVE,
+        Key::Num1 => evdev::Key::KEY_1,
+        Key::Num2 => evdev::Key::KEY_2,
+        Key::Num3 => evdev::Key::KEY_3,
+        Key::Num4 => evdev::Key::KEY_4,
+        Key::Num5 => evdev::Key::KEY_5,
+        Key::Num6 => evdev::Key::KEY_6,
+        Key::Num7 => evdev::Key::KEY_7,
+        Key::Num8 => evdev::Key::KEY_8,
+        Key::Num9 => evdev::Key::KEY_9,
+        Key::Num0 => evdev::Key::KEY_0,
+        Key::Minus => evdev::Key::KEY_MINUS,
+        Key::Equal => evdev::Key::KEY_EQUAL,
+        Key::KeyQ => evdev::Key::KEY_Q,
+        Key::KeyW => evdev::Key::KEY_W,
+        Key::KeyE => evdev::Key::KEY_E,
+        Key::KeyR => evdev::Key::KEY_R,
+        Key::KeyT => evdev::Key::KEY_T,
+        Key::KeyY => evdev::Key::KEY_Y,
+        Key::KeyU => evdev::Key::KEY_U,
+        Key::KeyI => evdev::Key::KEY_I,
+        Key::KeyO => evdev::Key::KEY_O,
+        Key::KeyP => evdev::Key::KEY_P,
+        Key::LeftBracket => evdev::Key::KEY_LEFTBRACE,
+        Key::RightBracket => evdev::Key::KEY_RIGHTBRACE,
+        Key::KeyA => evdev::Key::KEY_A,
+        Key::KeyS => evdev::Key::KEY_S,
+        Key::KeyD => evdev::Key::KEY_D,
+        Key::KeyF => evdev::Key::KEY_F,
+        Key::KeyG => evdev::Key::KEY_G,
+        Key::KeyH => evdev::Key::KEY_H,
+        Key::KeyJ => evdev::Key::KEY_J,
+        Key::KeyK => evdev::Key::KEY_K,
+        Key::KeyL => evdev::Key::KEY_L,
+        Key::SemiColon => evdev::Key::KEY_SEMICOLON,
+        Key::Quote => evdev::Key::KEY_APOSTROPHE,
+        Key::BackSlash => evdev::Key::KEY_BACKSLASH,
+        Key::IntlBackslash => evdev::Key::KEY_102ND,
+        Key::KeyZ => evdev::Key::KEY_Z,
+        Key::KeyX => evdev::Key::KEY_X,
+        Key::KeyC => evdev::Key::KEY_C,
+        Key::KeyV => evdev::Key::KEY_V,
+        Key::KeyB => evdev::Key::KEY_B,
+        Key::KeyN => evdev::Key::KEY_N,
+        Key::KeyM => evdev::Key::KEY_M,
+        Key::Comma => evdev::Key::KEY_COMMA,
+        Key::Dot => evdev::Key::KEY_DOT,
+        Key::Slash => evdev::Key::KEY_SLASH,
+        Key::Insert => evdev::Key::KEY_INSERT,
+        Key::KpReturn => evdev::Key::KEY_KPENTER,
+        Key::KpMinus => evdev::Key::KEY_KPMINUS,
+        Key::KpPlus => evdev::Key::KEY_KPPLUS,
+        Key::KpMultiply => evdev::Key::KEY_KPASTERISK,
+        Key::KpDivide => evdev::Key::KEY_KPSLASH,
+        Key::Kp0 => evdev::Key::KEY_KP0,
+        Key::Kp1 => evdev::Key::KEY_KP1,
+        Key::Kp2 => evdev::Key::KEY_KP2,
+        Key::Kp3 => evdev::Key::KEY_KP3,
+        Key::Kp4 => evdev::Key::KEY_KP4,
+        Key::Kp5 => evdev::Key::KEY_KP5,
+        Key::Kp6 => evdev::Key::KEY_KP6,
+        Key::Kp7 => evdev::Key::KEY_KP7,
+        Key::Kp8 => evdev::Key::KEY_KP8,
+        Key::Kp9 => evdev::Key::KEY_KP9,
+        Key::KpDelete => evdev::Key::KEY_KPDOT,
+        Key::Function => evdev::Key::KEY_FN,
+        _ => return None,
+    };
+    Some(key)
+}
+
+fn rdev_button_to_evdev(button: &Button) -> Option<evdev::Key> {
+    let button = match button {
+        Button::Left => evdev::Key::BTN_LEFT,
+        Button::Middle => evdev::Key::BTN_MIDDLE,
+        Button::Right => evdev::Key::BTN_RIGHT,
+        // The X11 back/forward button numbers used in CODE_TO_RDEV_BUTTON
+        Button::Unknown(8) => evdev::Key::BTN_SIDE,
+        Button::Unknown(9) => evdev::Key::BTN_EXTRA,
+        _ => return None,
+    };
+    Some(button)
+}
+
+impl UinputSink {
+    pub fn new() -> io::Result<Self> {
+        let mut keys = AttributeSet::<evdev::Key>::new();
+        for key in CODE_TO_RDEV_KEY.values() {
+            if let Some(key) = rdev_key_to_evdev(key) {
+                keys.insert(key);
+            }
+        }
+        let keyboard = VirtualDeviceBuilder::new()?
+            .name("linkmouse keyboard")
+            .with_keys(&keys)?
+            .build()?;
+
+        let mut buttons = AttributeSet::<evdev::Key>::new();
+        for button in [
+            evdev::Key::BTN_LEFT,
+            evdev::Key::BTN_MIDDLE,
+            evdev::Key::BTN_RIGHT,
+            evdev::Key::BTN_SIDE,
+            evdev::Key::BTN_EXTRA,
+        ] {
+            buttons.insert(button);
+        }
+        let mut axes = AttributeSet::<RelativeAxisType>::new();
+        for axis in [
+            RelativeAxisType::REL_X,
+            RelativeAxisType::REL_Y,
+            RelativeAxisType::REL_WHEEL,
+            RelativeAxisType::REL_HWHEEL,
+            RelativeAxisType::REL_WHEEL_HI_RES,
+            RelativeAxisType::REL_HWHEEL_HI_RES,
+        ] {
+            axes.insert(axis);
+        }
+        let mouse = VirtualDeviceBuilder::new()?
+            .name("linkmouse mouse")
+            .with_keys(&buttons)?
+            .with_relative_axes(&axes)?
+            .build()?;
+
+        Ok(UinputSink {
+            keyboard: Mutex::new(keyboard),
+            mouse: Mutex::new(mouse),
+            wheel_remainders: Mutex::new(WheelRemainders { x: 0.0, y: 0.0, x_hi_res: 0, y_hi_res: 0 }),
+        })
+    }
+
+    fn emit(device: &Mutex<VirtualDevice>, events: &[InputEvent]) {
+        if let Err(e) = device.lock().unwrap().emit(events) {
+            println!("Could not emit uinput events {:?}: {}", events, e);
+        }
+    }
+
+    fn key_event(key: evdev::Key, value: i32) -> InputEvent {
+        InputEvent::new(evdev::EventType::KEY, key.code(), value)
+    }
+
+    fn rel_event(axis: RelativeAxisType, value: i32) -> InputEvent {
+        InputEvent::new(evdev::EventType::RELATIVE, axis.0, value)
+    }
+
+    // Hi-res units are sent right away, full notches are sent
+    // for applications that only understand REL_WHEEL
+    fn scroll_hi_res(&self, x: f64, y: f64) {
+        let mut events = vec![];
+        {
+            let mut remainders = self.wheel_remainders.lock().unwrap();
+            let remainders = &mut *remainders;
+
+            let (x_hi_res, x_notches) = wheel_axis(x, &mut remainders.x, &mut remainders.x_hi_res);
+            let (y_hi_res, y_notches) = wheel_axis(y, &mut remainders.y, &mut remainders.y_hi_res);
+
+            if x_hi_res != 0 {
+                events.push(Self::rel_event(RelativeAxisType::REL_HWHEEL_HI_RES, x_hi_res));
+            }
+            if x_notches != 0 {
+                events.push(Self::rel_event(RelativeAxisType::REL_HWHEEL, x_notches));
+            }
+            if y_hi_res != 0 {
+                events.push(Self::rel_event(RelativeAxisType::REL_WHEEL_HI_RES, y_hi_res));
+            }
+            if y_notches != 0 {
+                events.push(Self::rel_event(RelativeAxisType::REL_WHEEL, y_notches));
+            }
+        }
+
+        if !events.is_empty() {
+            Self::emit(&self.mouse, &events);
+        }
+    }
+}
+
+impl InputSink for UinputSink {
+    fn send(&self, event_type: &EventType) {
+        match event_type {
+            EventType::KeyPress(key) | EventType::KeyRelease(key) => {
+                let value = match event_type {
+                    EventType::KeyPress(_) => KEY_PRESS,
+                    _ => KEY_RELEASE,
+                };
+                match rdev_key_to_evdev(key) {
+                    Some(key) => Self::emit(&self.keyboard, &[Self::key_event(key, value)]),
+                    None => println!("No uinput key for {:?}", key),
+                }
+            },
+            EventType::ButtonPress(button) | EventType::ButtonRelease(button) => {
+                let value = match event_type {
+                    EventType::ButtonPress(_) => KEY_PRESS,
+                    _ => KEY_RELEASE,
+                };
+                match rdev_button_to_evdev(button) {
+                    Some(button) => Self::emit(&self.mouse, &[Self::key_event(button, value)]),
+                    None => println!("No uinput button for {:?}", button),
+                }
+            },
+            _ => println!("uinput cannot send {:?}", event_type),
+        }
+    }
+
+    fn mouse_move_relative(&self, x: i32, y: i32) {
+        Self::emit(&self.mouse, &[
+            Self::rel_event(RelativeAxisType::REL_X, x),
+            Self::rel_event(RelativeAxisType::REL_Y, y),
+        ]);
+    }
+
+    fn scroll_lines(&self, x: f64, y: f64) {
+        self.scroll_hi_res(x * HI_RES_PER_LINE, y * HI_RES_PER_LINE);
+    }
+
+    fn scroll_pixels(&self, x: f64, y: f64) {
+        self.scroll_hi_res(x / PIXELS_PER_LINE * HI_RES_PER_LINE, y / PIXELS_PER_LINE * HI_RES_PER_LINE);
+    }
+
+    fn text(&self, _text: &str) -> Result<(), String> {
+        Err("textinput: the uinput backend cannot type text, only keys".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main_process::CODE_TO_RDEV_BUTTON;
+
+    #[test]
+    fn every_browser_key_and_button_has_an_evdev_code() {
+        for (code, key) in CODE_TO_RDEV_KEY.iter() {
+            assert!(rdev_key_to_evdev(key).is_some(), "{} {:?}", code, key);
+        }
+        for (code, button) in CODE_TO_RDEV_BUTTON.iter() {
+            assert!(rdev_button_to_evdev(button).is_some(), "{} {:?}", code, button);
+        }
+        assert_eq!(rdev_button_to_evdev(&Button::Unknown(8)), Some(evdev::Key::BTN_SIDE));
+        assert_eq!(rdev_button_to_evdev(&Button::Unknown(9)), Some(evdev::Key::BTN_EXTRA));
+    }
+
+    #[test]
+    fn wheel_sends_notches_once_a_line_is_full() {
+        let (mut remainder, mut hi_res_remainder) = (0.0, 0);
+        assert_eq!(wheel_axis(60.5, &mut remainder, &mut hi_res_remainder), (60, 0));
+        assert_eq!(wheel_axis(60.5, &mut remainder, &mut hi_res_remainder), (61, 1));
+        assert_eq!((remainder, hi_res_remainder), (0.0, 1));
+        assert_eq!(wheel_axis(-241.0, &mut remainder, &mut hi_res_remainder), (-241, -2));
+    }
+
+    #[test]
+    fn wheel_clamps_huge_deltas_and_ignores_non_finite_ones() {
+        let (mut remainder, mut hi_res_remainder) = (0.0, 0);
+        assert_eq!(wheel_axis(1e30, &mut remainder, &mut hi_res_remainder), (12000, 100));
+        assert_eq!(wheel_axis(-1e30, &mut remainder, &mut hi_res_remainder), (-12000, -100));
+        for delta in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(wheel_axis(delta, &mut remainder, &mut hi_res_remainder), (0, 0));
+        }
+        // Still scrolls afterwards
+        assert_eq!(wheel_axis(120.0, &mut remainder, &mut hi_res_remainder), (120, 1));
+    }
+}
@@ -0,0 +1,215 @@
+// Blocks or remaps key chords from the browser before they are injected,
+// e.g. so that a remote user cannot close windows with Alt+F4.
+//
+// A chord is written as modifiers and a KeyboardEvent.code joined with '+',
+// for example "Control+Alt+Delete". The modifiers are Control, Alt, Shift and
+// Meta, either side matches. A rule matches when all its modifiers are held,
+// extra held modifiers do not matter.
+//
+// Rules are edited as text, one per line:
+//   Alt+F4                      blocks the chord
+//   Meta+KeyL => Control+KeyL   sends Control+KeyL instead
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::main_process::held_input::HeldInput;
+use crate::main_process::CODE_TO_RDEV_KEY;
+
+pub const MODIFIERS: [(&str, &[&str]); 4] = [
+    ("Control", &["ControlLeft", "ControlRight"]),
+    ("Alt", &["AltLeft", "AltRight"]),
+    ("Shift", &["ShiftLeft", "ShiftRight"]),
+    ("Meta", &["MetaLeft", "MetaRight", "OSLeft", "OSRight"]),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyChord {
+    pub modifiers: Vec<&'static str>,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyRule {
+    pub chord: KeyChord,
+    pub remap_to: Option<KeyChord>, // None blocks the chord
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeyDecision {
+    Pass,
+    Block(KeyChord),
+    Remap(KeyChord, KeyChord),
+}
+
+impl KeyChord {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts: Vec<&str> = value.split('+').map(|part| part.trim()).collect();
+        let code = match parts.pop() {
+            Some(code) if !code.is_empty() => code,
+            _ => return Err(format!("Empty key chord: '{}'", value)),
+        };
+        if !CODE_TO_RDEV_KEY.contains_key(code) {
+            return Err(format!("Unknown key code '{}' in '{}'", code, value));
+        }
+
+        let mut modifiers = vec![];
+        for part in parts {
+            match MODIFIERS.iter().find(|(name, _)| *name == part) {
+                Some((name, _)) => modifiers.push(*name),
+                None => return Err(format!("Unknown modifier '{}' in '{}', use Control, Alt, Shift or Meta", part, value)),
+            }
+        }
+
+        Ok(KeyChord { modifiers, code: code.to_string() })
+    }
+
+    fn is_held(&self, held_input: &HeldInput) -> bool {
+        self.modifiers.iter().all(|modifier| modifier_codes(modifier).iter().any(|code| held_input.is_key_held(code)))
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for modifier in &self.modifiers {
+            write!(f, "{}+", modifier)?;
+        }
+        write!(f, "{}", self.code)
+    }
+}
+
+impl KeyRule {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        match line.split_once("=>") {
+            Some((chord, remap_to)) => Ok(KeyRule {
+                chord: KeyChord::parse(chord)?,
+                remap_to: Some(KeyChord::parse(remap_to)?),
+            }),
+            None => Ok(KeyRule { chord: KeyChord::parse(line)?, remap_to: None }),
+        }
+    }
+}
+
+impl fmt::Display for KeyRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.remap_to {
+            Some(remap_to) => write!(f, "{} => {}", self.chord, remap_to),
+            None => write!(f, "{}", self.chord),
+        }
+    }
+}
+
+pub fn modifier_codes(modifier: &str) -> &'static [&'static str] {
+    match MODIFIERS.iter().find(|(name, _)| *name == modifier) {
+        Some((_, codes)) => codes,
+        None => &[],
+    }
+}
+
+pub fn is_modifier_code(code: &str) -> bool {
+    MODIFIERS.iter().any(|(_, codes)| codes.contains(&code))
+}
+
+fn default_key_rules() -> Vec<KeyRule> {
+    let mut lines = vec![
+        "Alt+F4".to_string(), // Close window
+        "Meta+KeyL".to_string(), // Lock screen (Windows, GNOME)
+        "Control+Alt+Delete".to_string(),
+        "Control+Alt+Backspace".to_string(), // Kill X server
+    ];
+    // Switch virtual terminal on Linux
+    for i in 1..=12 {
+        lines.push(format!("Control+Alt+F{}", i));
+    }
+    lines.iter().map(|line| KeyRule::parse(line).unwrap()).collect()
+}
+
+lazy_static! {
+    static ref KEY_RULES: Arc<Mutex<Vec<KeyRule>>> = Arc::new(Mutex::new(default_key_rules()));
+}
+
+pub fn get_key_rules_text() -> String {
+    KEY_RULES.lock().unwrap().iter().map(|rule| rule.to_string()).collect::<Vec<String>>().join("\n")
+}
+
+// Keeps the old rules if any line is invalid
+pub fn set_key_rules_text(text: &str) -> Result<(), String> {
+    let mut rules = vec![];
+    for line in text.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+        rules.push(KeyRule::parse(line)?);
+    }
+    *KEY_RULES.lock().unwrap() = rules;
+    Ok(())
+}
+
+fn decide(rules: &[KeyRule], code: &str, held_input: &HeldInput) -> KeyDecision {
+    match rules.iter().find(|rule| rule.chord.code == code && rule.chord.is_held(held_input)).cloned() {
+        Some(KeyRule { chord, remap_to: None }) => KeyDecision::Block(chord),
+        Some(KeyRule { chord, remap_to: Some(remap_to) }) => KeyDecision::Remap(chord, remap_to),
+        None => KeyDecision::Pass,
+    }
+}
+
+// The keyup of a blocked or remapped keydown has to be dropped too
+pub fn filter_keydown(code: &str, held_input: &HeldInput) -> KeyDecision {
+    decide(&KEY_RULES.lock().unwrap(), code, held_input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(codes: &[&str]) -> HeldInput {
+        let mut held_input = HeldInput::new();
+        for code in codes {
+            held_input.key_pressed(code);
+        }
+        held_input
+    }
+
+    fn chord(value: &str) -> KeyChord {
+        KeyChord::parse(value).unwrap()
+    }
+
+    #[test]
+    fn default_rules_parse_back_from_their_text() {
+        let rules = default_key_rules();
+        assert_eq!(rules.len(), 16);
+        for rule in &rules {
+            assert_eq!(rule.remap_to, None);
+            assert_eq!(&KeyRule::parse(&rule.to_string()).unwrap(), rule);
+        }
+    }
+
+    #[test]
+    fn default_rules_block_only_with_their_modifiers_held() {
+        let rules = default_key_rules();
+        assert_eq!(decide(&rules, "F4", &held(&[])), KeyDecision::Pass);
+        assert_eq!(decide(&rules, "F4", &held(&["AltLeft"])), KeyDecision::Block(chord("Alt+F4")));
+        // Either side, and extra modifiers do not matter
+        assert_eq!(decide(&rules, "F4", &held(&["AltRight", "ShiftLeft"])), KeyDecision::Block(chord("Alt+F4")));
+        assert_eq!(decide(&rules, "KeyL", &held(&["OSLeft"])), KeyDecision::Block(chord("Meta+KeyL")));
+        assert_eq!(decide(&rules, "KeyL", &held(&["ControlLeft"])), KeyDecision::Pass);
+        assert_eq!(decide(&rules, "Delete", &held(&["ControlRight", "AltLeft"])), KeyDecision::Block(chord("Control+Alt+Delete")));
+        assert_eq!(decide(&rules, "Delete", &held(&["ControlRight"])), KeyDecision::Pass);
+        assert_eq!(decide(&rules, "F7", &held(&["ControlLeft", "AltLeft"])), KeyDecision::Block(chord("Control+Alt+F7")));
+    }
+
+    #[test]
+    fn remap_rules_send_the_other_chord() {
+        let rules = vec![KeyRule::parse("Meta+KeyL => Control+KeyL").unwrap(), KeyRule::parse("Alt+F4").unwrap()];
+        assert_eq!(decide(&rules, "KeyL", &held(&["MetaRight"])), KeyDecision::Remap(chord("Meta+KeyL"), chord("Control+KeyL")));
+        assert_eq!(decide(&rules, "KeyL", &held(&[])), KeyDecision::Pass);
+        assert_eq!(decide(&rules, "F4", &held(&["AltLeft"])), KeyDecision::Block(chord("Alt+F4")));
+    }
+
+    #[test]
+    fn invalid_chords_are_rejected() {
+        assert!(KeyChord::parse("").is_err());
+        assert!(KeyChord::parse("Control+").is_err());
+        assert!(KeyChord::parse("Control+KeyÖ").is_err());
+        assert!(KeyChord::parse("Hyper+KeyL").is_err());
+        assert!(KeyRule::parse("Alt+F4 => ").is_err());
+        assert_eq!(chord(" Control + Alt + Delete ").to_string(), "Control+Alt+Delete");
+    }
+}
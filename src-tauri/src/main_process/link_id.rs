@@ -0,0 +1,30 @@
+// Random ids for the shared link, e.g. "k3x9vq-..." (4 sections of 6)
+
+use rand::Rng;
+use rand::rngs::OsRng;
+
+const ID_SECTION_LEN: i32 = 6;
+const ID_SECTION_AMOUNT: i32 = 4; // 4;
+
+fn random_lowercase_letter_or_digit() -> char {
+    let mut rng = OsRng;
+    // 01ol dropped due to being easy to mix
+    let chars: Vec<char> = "abcdefghijkmnpqrstuvwxyz23456789".chars().collect();
+    //let chars: Vec<char> = "a".chars().collect();
+    let index = rng.gen_range(0..chars.len());
+    chars.get(index).unwrap().clone()
+}
+
+pub fn random_id() -> String {
+    let mut id = String::new();
+
+    for _ in 0..ID_SECTION_AMOUNT {
+        for _ in 0..ID_SECTION_LEN {
+            id.push(random_lowercase_letter_or_digit());
+        }
+        id.push('-');
+    }
+    id.pop();
+
+    return id;
+}
@@ -1,17 +1,70 @@
-// This chould be kept in sync with: src/MessagesToFe.ts
+// Events for the frontend, emitted as "my_event". The TypeScript types in
+// src/bindings are generated from these with `cargo test export_bindings`,
+// do not edit them by hand.
 
-// Connecting sequence
-pub const CONNECTING_SERVER : &str = "CONNECTING_SERVER";
-pub const SERVER_CONNECTED_WAITING_USER : &str = "SERVER_CONNECTED_WAITING_USER";
-pub const USER_CONNECTING : &str = "USER_CONNECTING";
-pub const USER_CONNECTED : &str = "USER_CONNECTED";
+use serde::Serialize;
+use ts_rs::TS;
 
-// During normal use
-pub const CONTROLLING_STARTED : &str = "CONTROLLING_STARTED";
-pub const CONTROLLING_STOPPED : &str = "CONTROLLING_STOPPED";
+use crate::main_process::datachannel::connection_type::IceConnectionType;
+use crate::main_process::shared_settings::BrowserInfo;
 
-// User leaves
-pub const USER_DISCONNECTED : &str = "USER_DISCONNECTED";
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug, Clone, PartialEq, TS)]
+#[serde(tag = "name", rename_all = "SCREAMING_SNAKE_CASE")]
+#[ts(export, export_to = "../src/bindings/FrontendEvent.ts")]
+pub enum FrontendEvent {
+    // Connecting sequence
+    ConnectingServer,
+    ServerConnectedWaitingUser,
+    UserConnecting,
+    UserApprovalPending {
+        browserInfo: BrowserInfo, // Sent again when the browser tells about itself
+    },
+    UserConnected {
+        browserInfo: BrowserInfo,
+        connectionType: Option<IceConnectionType>, // None if the stats were not ready
+    },
 
-// Timeout if SERVER_CONNECTED_WAITING_USER too long
-pub const SERVER_DISCONNECTED : &str = "SERVER_DISCONNECTED";
+    // During normal use
+    ControllingStarted,
+    ControllingStopped,
+
+    // User leaves
+    UserDisconnected {
+        #[ts(type = "number | null")]
+        sessionDurationMs: Option<u64>, // None if the user never got connected
+    },
+
+    // Too many wrong PIN answers, the link id and PIN must be changed
+    SessionLocked,
+
+    // Timeout if SERVER_CONNECTED_WAITING_USER too long
+    ServerDisconnected,
+
+    // Negotiating with the browser failed, a new connection attempt follows
+    SignalingError {
+        reason: String,
+    },
+}
+
+impl FrontendEvent {
+    // The serialized name, so it cannot drift from the serde tag
+    pub fn name(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value["name"].as_str().map(|name| name.to_string()))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_is_the_serialized_tag() {
+        assert_eq!(FrontendEvent::ServerConnectedWaitingUser.name(), "SERVER_CONNECTED_WAITING_USER");
+        assert_eq!(FrontendEvent::UserDisconnected { sessionDurationMs: Some(5) }.name(), "USER_DISCONNECTED");
+        assert_eq!(FrontendEvent::SignalingError { reason: "timeout".to_string() }.name(), "SIGNALING_ERROR");
+    }
+}
@@ -1,12 +1,31 @@
 mod datachannel;
-mod shared_settings;
-mod messages_to_fe;
-use messages_to_fe::{CONTROLLING_STARTED, CONTROLLING_STOPPED};
-use std::{sync::{Arc}, time::{UNIX_EPOCH, SystemTime, self}, str::Split, thread, collections::HashMap, panic};
-use lazy_static::__Deref;
-use crate::main_process::{datachannel::{process_datachannel_messages, MouseOffset, PostSleepData}, shared_settings::{BrowserInfo, BrowserSettings, BROWSER_INFO, BROWSER_SETTINGS, DESKTOP_INFO}};
-use copypasta::{ClipboardContext, ClipboardProvider};
-use rdev::{Button, EventType, Key, SimulateError, simulate, mouse_move_relative, scroll_lines, scroll_pixels};
+pub mod shared_settings;
+pub mod messages_to_fe;
+mod protocol;
+mod held_input;
+mod approval;
+mod pin;
+pub mod permissions;
+mod key_filter;
+pub mod config;
+pub mod link_id;
+pub mod settings_store;
+pub mod acceleration;
+pub mod predictor;
+pub mod mouse_recording;
+pub mod replay;
+pub mod input_sink;
+pub mod session;
+pub mod stats;
+pub mod clipboard_sync;
+pub mod clipboard_formats;
+pub mod paste;
+pub mod text_input;
+use messages_to_fe::FrontendEvent;
+use std::{sync::{Arc}, time::{UNIX_EPOCH, SystemTime, self}, thread, collections::HashMap, panic};
+use crate::main_process::{datachannel::{process_datachannel_messages, send_to_browser, PostSleepData}, protocol::{Message, ParseError}, session::{current_session, Session}, shared_settings::{BrowserInfo, BrowserSettings, DESKTOP_INFO}};
+use crate::main_process::clipboard_formats::{write_clipboard, ClipboardAction, ClipboardContent};
+use rdev::{Button, EventType, Key};
 use std::sync::mpsc::{Receiver, Sender};
 
 struct MouseHasBeenCenter {
@@ -28,11 +47,51 @@ struct MouseUpdateState {
     last_update: u128,
 }
 
+// The limits for an interval compared to the average, replay.rs can try others
+struct MouseTuning {
+    too_slow: f64,
+    too_fast: f64,
+}
+
 struct MouseSubPixelRemainders {
     pub x: f64,
     pub y: f64,
 }
 
+// Mouse state of one session (see session.rs)
+struct MouseState {
+    sub_pixel_remainders: MouseSubPixelRemainders,
+    latest_nano: Option<u128>,
+    rolling_avg_update_interval: u128,
+    update_state: MouseUpdateState,
+    smoothed_speed: Option<f64>, // For the acceleration
+    predictor_kind: predictor::PredictorKind, // The default when made, see set_motion_predictor
+    predictor: Box<dyn predictor::MotionPredictor>,
+    tuning: MouseTuning,
+}
+
+impl MouseState {
+    fn new() -> Self {
+        let predictor_kind = predictor::get_default_predictor_kind();
+        MouseState {
+            sub_pixel_remainders: MouseSubPixelRemainders { x: 0.0, y: 0.0 },
+            latest_nano: None,
+            rolling_avg_update_interval: 1000000000/60, // Assume 60 updates/second at the start
+            update_state: MouseUpdateState { updates: 0, too_fasts: 0, too_slows: 0, last_update: 0 },
+            smoothed_speed: None,
+            predictor: predictor_kind.build(),
+            predictor_kind,
+            tuning: MouseTuning { too_slow: MOUSE_TOO_SLOW, too_fast: MOUSE_TOO_FAST },
+        }
+    }
+
+    // Takes effect from the next move
+    fn set_predictor(&mut self, predictor_kind: predictor::PredictorKind) {
+        self.predictor = predictor_kind.build();
+        self.predictor_kind = predictor_kind;
+    }
+}
+
 const MOUSE_ROLLING_AVG_MULT : f64 = 0.025;
 const MOUSE_TOO_SLOW : f64 = 1.05;
 const MOUSE_TOO_FAST : f64 = 0.85;
@@ -50,11 +109,6 @@ const WHEEL_SUPPORTS_PIXEL_MOVE: bool = false; */
 lazy_static! {
     static ref WINDOW_SIZE: Arc<std::sync::Mutex<WindowSize>> = Arc::new(std::sync::Mutex::new(WindowSize { x: None, y: None }));
     //static ref MOUSE_LATEST_POS: Arc<std::sync::Mutex<MousePosition>> = Arc::new(std::sync::Mutex::new(MousePosition { x: 0.0, y: 0.0 }));
-    static ref MOUSE_OFFSET_FROM_REAL: Arc<std::sync::Mutex<MouseOffset>> = Arc::new(std::sync::Mutex::new(MouseOffset { x: 0, y: 0 }));
-    static ref MOUSE_SUB_PIXEL_REMAINDERS: Arc<std::sync::Mutex<MouseSubPixelRemainders>> = Arc::new(std::sync::Mutex::new(MouseSubPixelRemainders{ x: 0.0, y: 0.0 }));
-    static ref MOUSE_LATEST_NANO: Arc<std::sync::Mutex<Option<u128>>> = Arc::new(std::sync::Mutex::new(None));
-    static ref MOUSE_ROLLING_AVG_UPDATE_INTERVAL: Arc<std::sync::Mutex<u128>> = Arc::new(std::sync::Mutex::new(1000000000/60)); // Assume 60 updates/second at the start
-    static ref MOUSE_UPDATE_STATE: Arc<std::sync::Mutex<MouseUpdateState>> = Arc::new(std::sync::Mutex::new(MouseUpdateState { updates: 0, too_fasts: 0, too_slows: 0, last_update: 0 }));
     //static ref MOUSE_HAS_BEEN_CENTER: Arc<std::sync::Mutex<MouseHasBeenCenter>> = Arc::new(std::sync::Mutex::new(MouseHasBeenCenter { top: false, left: false, right: false, bottom: false }));
     //static ref WHEEL_SUB_PIXEL_X: Arc<std::sync::Mutex<f64>> = Arc::new(std::sync::Mutex::new(0.0));
     //static ref WHEEL_SUB_PIXEL_Y: Arc<std::sync::Mutex<f64>> = Arc::new(std::sync::Mutex::new(0.0));
@@ -196,21 +250,19 @@ lazy_static! {
     ]);
 }
 
-fn get_epoch_nanos() -> u128 {
+fn get_epoch_nanos(session: &Session) -> u128 {
+    if let Some(nanos) = *session.replay_nanos.lock().unwrap() {
+        return nanos;
+    }
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_nanos()
 }
 
-fn send(event_type: &EventType) {
+fn send(session: &Session, event_type: &EventType) {
     //let delay = time::Duration::from_millis(20);
-    match simulate(event_type) {
-        Ok(()) => (),
-        Err(SimulateError) => {
-            println!("We could not send {:?}", event_type);
-        }
-    }
+    session.input_sink().send(event_type);
     // Let ths OS catchup (at least MacOS)
     // Only if keyboard event!
     //if cfg!(linux) {
@@ -248,66 +300,54 @@ fn update_window_size(x: i32, y: i32) {
     send(&EventType::MouseMove { x, y });
 } */
 
-fn handle_mousemove(mut values: Split<&str>, mut post_sleep_data: PostSleepData/* , enigo_handler_tx: SyncSender<String> */) -> (Option<u128>, PostSleepData) {
+fn handle_mousemove(session: &Session, x_preprosessed: i32, y_preprosessed: i32, mut post_sleep_data: PostSleepData/* , enigo_handler_tx: SyncSender<String> */) -> (Option<u128>, PostSleepData) {
     // Move immediately to new position. Take mouse offset into account
     // (this point may've been forecasted before)
     // Sleep before next forecast, unless lagging:
     // When lagging theres is extra gap (slow) and then burst of positions (fast)
 
-    // Get the next real position (relative)
-    let x_preprosessed = values.next().unwrap().parse::<i32>().unwrap();
-    let y_preprosessed = values.next().unwrap().parse::<i32>().unwrap();
+    let mut mouse_state = session.mouse.lock().unwrap();
+    let mouse_state = &mut *mouse_state;
 
-    // Multiply by speed, add previous remainders, and save new ones
-    let mult = {
-        let browser_settings = BROWSER_SETTINGS.lock().unwrap();
-        browser_settings.mouseSpeed
+    // Update latest mouse nano and save the difference to the previous
+    let now = get_epoch_nanos(session);
+    let diff = match mouse_state.latest_nano {
+        Some(mouse_latest_nano) => Some(now - mouse_latest_nano),
+        None => None,
+    };
+    mouse_state.latest_nano = Some(now);
+
+    // Multiply by speed and acceleration, add previous remainders, and save new ones
+    let (mouse_speed, mouse_acceleration) = {
+        let browser_settings = session.browser_settings.lock().unwrap();
+        (browser_settings.mouseSpeed, browser_settings.mouseAcceleration)
+    };
+    let mult = match mouse_acceleration {
+        true => {
+            let average_interval = mouse_state.rolling_avg_update_interval;
+            mouse_speed * acceleration::acceleration_factor(x_preprosessed, y_preprosessed, diff, average_interval, &mut mouse_state.smoothed_speed)
+        },
+        false => mouse_speed,
     };
 
     let (x, y) = {
-        let mut mouse_sub_pixel_remainders = MOUSE_SUB_PIXEL_REMAINDERS.lock().unwrap();
+        let mouse_sub_pixel_remainders = &mut mouse_state.sub_pixel_remainders;
 
         //println!("x_pre: {}", x_preprosessed);
         //println!("mult: {}", mult);
         //println!("mouse_sub_pixel_remainders.x: {}", mouse_sub_pixel_remainders.x);
 
-        let x_f64 = (x_preprosessed as f64).mul_add(mult, mouse_sub_pixel_remainders.x);
-        let x = x_f64.floor() as i32;
-        mouse_sub_pixel_remainders.x = x_f64.fract();
+        let x = acceleration::scale(x_preprosessed, mult, &mut mouse_sub_pixel_remainders.x);
 
-        //println!("x_f64: {}", x_f64);
         //println!("x: {}", x);
         //println!("mouse_sub_pixel_remainders.x: {}", mouse_sub_pixel_remainders.x);
         //println!("");
 
-        let y_f64 = (y_preprosessed as f64).mul_add(mult, mouse_sub_pixel_remainders.y);
-        let y = y_f64.floor() as i32;
-        mouse_sub_pixel_remainders.y = y_f64.fract();
+        let y = acceleration::scale(y_preprosessed, mult, &mut mouse_sub_pixel_remainders.y);
 
         (x,y)
     };
 
-    // Calculate the how much should be moved when
-    //forecast position has been taken into account
-    // Also have the next forecast position (half of the real relative movement)
-    let offset_x;
-    let offset_y;
-    {
-        let mut mouse_offset = MOUSE_OFFSET_FROM_REAL.lock().unwrap();
-        offset_x = x - mouse_offset.x;
-        offset_y = y - mouse_offset.y;
-        mouse_offset.x = x / 2;
-        mouse_offset.y = y / 2;
-        post_sleep_data.mouse_offset.x = x / 2;
-        post_sleep_data.mouse_offset.y = y / 2;
-    }
-
-    //let check_sides = CHECK_SIDES.lock().unwrap().clone();
-    let check_sides = false;
-
-    // Move mouse
-    /* let (start_x, start_y) =  */mouse_move_relative(offset_x, offset_y, check_sides);
-
     /* // Update if needs jumping
     if check_sides {
         {
@@ -336,31 +376,17 @@ fn handle_mousemove(mut values: Split<&str>, mut post_sleep_data: PostSleepData/
     } */
 
 
-    // Update latest mouse nano and save the difference to the previous
-    let now = get_epoch_nanos();
-    let diff;
-    {
-        let mut mouse_latest_nano_ref = MOUSE_LATEST_NANO.lock().unwrap();
-        let mouse_latest_nano = mouse_latest_nano_ref.deref();
-        
-        diff = match mouse_latest_nano {
-            Some(mouse_latest_nano) => Some(now - mouse_latest_nano),
-            None => None,
-        };
-        *mouse_latest_nano_ref = Some(now);
-    }
-
-    // Save half of the difference as sleep time if there has not been lags
-    // OR the diff is None, which mean this has been the first move after "mouseidle"
-    let sleep_amount = match diff {
+    // Compare the difference to the rolling average, lags show up as an extra
+    // gap (slow) followed by a burst of positions (fast)
+    let irregular = match diff {
         Some(diff) => {
-            let mut mouse_rolling_avg_interval_ref = MOUSE_ROLLING_AVG_UPDATE_INTERVAL.lock().unwrap();
+            let mouse_rolling_avg_interval_ref = &mut mouse_state.rolling_avg_update_interval;
             *mouse_rolling_avg_interval_ref = ((*mouse_rolling_avg_interval_ref as f64) * (1.0 - MOUSE_ROLLING_AVG_MULT) + (diff as f64) * MOUSE_ROLLING_AVG_MULT) as i64 as u128;
             //println!("diff: {}", mouse_rolling_avg_interval_ref);
             let diff64: u64 = diff.try_into().unwrap();
             let value = diff64 as f64 / *mouse_rolling_avg_interval_ref as f64;
 
-            let mut mouse_update_state = MOUSE_UPDATE_STATE.lock().unwrap();
+            let mouse_update_state = &mut mouse_state.update_state;
             mouse_update_state.updates += 1;
 
             if mouse_update_state.updates % MOUSE_CHECK_FREQUENCY == 0 {
@@ -374,7 +400,7 @@ fn handle_mousemove(mut values: Split<&str>, mut post_sleep_data: PostSleepData/
                 mouse_update_state.too_fasts = 0;
                 mouse_update_state.too_slows = 0;
 
-                let now = get_epoch_nanos();
+                let now = get_epoch_nanos(session);
                 let diff = now - mouse_update_state.last_update;
                 if diff < MOUSE_TOO_FAST_UPDATES_LIMIT {
                     println!("TOO FAST ({})", diff);
@@ -384,110 +410,103 @@ fn handle_mousemove(mut values: Split<&str>, mut post_sleep_data: PostSleepData/
                 mouse_update_state.last_update = now;
             }
         
-            if value > MOUSE_TOO_SLOW {
+            let (too_slow, too_fast) = (mouse_state.tuning.too_slow, mouse_state.tuning.too_fast);
+            if value > too_slow {
                 //println!("TOO SLOW: {}, diff: {}", value, mouse_rolling_avg_interval_ref);
                 mouse_update_state.too_slows += 1;
-                {
-                    let mut mouse_offset = MOUSE_OFFSET_FROM_REAL.lock().unwrap();
-                    mouse_offset.x = 0;
-                    mouse_offset.y = 0;
-                }
-                post_sleep_data.mouse_offset.x = 0;
-                post_sleep_data.mouse_offset.y = 0;
-                None
-            } else if value < MOUSE_TOO_FAST {
+                session.input_stats.lock().unwrap().count_too_slow();
+                true
+            } else if value < too_fast {
                 //println!("TOO FAST: {}, diff: {}", value, mouse_rolling_avg_interval_ref);
                 mouse_update_state.too_fasts += 1;
-
-                {
-                    let mut mouse_offset = MOUSE_OFFSET_FROM_REAL.lock().unwrap();
-                    mouse_offset.x = 0;
-                    mouse_offset.y = 0;
-                }
-                post_sleep_data.mouse_offset.x = 0;
-                post_sleep_data.mouse_offset.y = 0;
-                None
+                session.input_stats.lock().unwrap().count_too_fast();
+                true
             } else {
                 //println!("GOOD: {}, diff: {}", value, mouse_rolling_avg_interval_ref);
-                Some(*mouse_rolling_avg_interval_ref / 2)
+                false
             }
             
         },
-        None => {
-            let mouse_rolling_avg_interval_ref = MOUSE_ROLLING_AVG_UPDATE_INTERVAL.lock().unwrap();
-            Some(*mouse_rolling_avg_interval_ref / 2)
-        },
+        None => false,
     };
 
-    return (sleep_amount, post_sleep_data);
+    // Move mouse, the predictor decides if there are more steps after a sleep
+    let timing = predictor::MoveTiming {
+        interval: diff,
+        average_interval: mouse_state.rolling_avg_update_interval,
+        irregular,
+    };
+    let step = mouse_state.predictor.on_move(x, y, &timing);
+    if step.x != 0 || step.y != 0 {
+        session.input_sink().mouse_move_relative(step.x, step.y);
+    }
+
+    return (step.next_sleep, post_sleep_data);
 }
 
-fn handle_mouseidle () {
+fn handle_mouseidle(session: &Session) {
     // Reset mouse latest nano time
     // This will make average mouse update interval more accurate
     // Also reset the offset (keep the "wrong"/forecasted position at the end)
     //println!("mouseidle");
-    {
-        let mut mouse_latest_nano_ref = MOUSE_LATEST_NANO.lock().unwrap();
-        *mouse_latest_nano_ref = None;
-    }
-    {
-        let mut mouse_offset = MOUSE_OFFSET_FROM_REAL.lock().unwrap();
-        mouse_offset.x = 0;
-        mouse_offset.y = 0;
+    let mut mouse_state = session.mouse.lock().unwrap();
+    mouse_state.latest_nano = None;
+    mouse_state.smoothed_speed = None; // The next move starts from a new speed
+    let (x, y) = mouse_state.predictor.reset();
+    if x != 0 || y != 0 {
+        session.input_sink().mouse_move_relative(x, y);
     }
 }
 
-fn handle_mousedown(mut values: Split<&str>/* , enigo_handler_tx: SyncSender<String> */) {
-    let button = values.next().unwrap().parse::<i32>().unwrap();
-    let command = format!("mouse_down,{}", button);
+fn handle_mousedown(session: &Session, button_code: i32/* , enigo_handler_tx: SyncSender<String> */) {
+    let command = format!("mouse_down,{}", button_code);
     println!("{}", command);
 
-    let button = match CODE_TO_RDEV_BUTTON.get(&button) {
+    let button = match CODE_TO_RDEV_BUTTON.get(&button_code) {
         Some(button) => button,
         None => {
             println!("Unknown mouse button");
             return;
         },
     };
-    send(&EventType::ButtonPress(*button));
+    send(session, &EventType::ButtonPress(*button));
+    session.held_input.lock().unwrap().button_pressed(button_code);
 }
 
-fn handle_mouseup(mut values: Split<&str>) {
-    let button = values.next().unwrap().parse::<i32>().unwrap();
-    let command = format!("mouse_up,{}", button);
+fn handle_mouseup(session: &Session, button_code: i32) {
+    let command = format!("mouse_up,{}", button_code);
     println!("{}", command);
     
-    let button = match CODE_TO_RDEV_BUTTON.get(&button) {
+    let button = match CODE_TO_RDEV_BUTTON.get(&button_code) {
         Some(button) => button,
         None => {
             println!("Unknown mouse button");
             return;
         },
     };
-    send(&EventType::ButtonRelease(*button));
+    send(session, &EventType::ButtonRelease(*button));
+    session.held_input.lock().unwrap().button_released(button_code);
 }
 
-fn handle_wheel(mut values: Split<&str>) {
-    let delta_mode = values.next().unwrap().parse::<i32>().unwrap();
-    let y_mult = match BROWSER_SETTINGS.lock().unwrap().scrollReversed {
+fn handle_wheel(session: &Session, delta_mode: i32, x: f64, y: f64) {
+    let y_mult = match session.browser_settings.lock().unwrap().scrollReversed {
         true => 1.0,
         false => -1.0,
     };
 
     let speed_mult = {
-        let browser_settings = BROWSER_SETTINGS.lock().unwrap();
+        let browser_settings = session.browser_settings.lock().unwrap();
         browser_settings.scrollSpeed
     };
 
-    let x = values.next().unwrap().parse::<f64>().unwrap() * speed_mult;
-    let y = values.next().unwrap().parse::<f64>().unwrap() * speed_mult * y_mult;
+    let x = x * speed_mult;
+    let y = y * speed_mult * y_mult;
 
     // deltaModes: https://developer.mozilla.org/en-US/docs/Web/API/Element/wheel_event#event_properties
     // Treat DOM_DELTA_LINE and DOM_DELTA_PAGE the same for now
     match delta_mode {
-        0 => scroll_pixels(x, y),
-        _ => scroll_lines(x, y),
+        0 => session.input_sink().scroll_pixels(x, y),
+        _ => session.input_sink().scroll_lines(x, y),
     }
 }
 
@@ -501,49 +520,197 @@ fn code_to_rdev_key(code: &str) -> Option<&Key>{
     }
 }
 
-fn handle_keydown(mut values: Split<&str>) {
+// Sends the chord instead of the currently held modifiers: modifiers that are
+// not part of the chord are released for the duration and pressed again after
+fn send_chord(session: &Session, chord: &key_filter::KeyChord) {
+    let key = match code_to_rdev_key(&chord.code) {
+        Some(key) => *key,
+        None => return,
+    };
+
+    let held_modifiers: Vec<String> = session.held_input.lock().unwrap().held_keys()
+        .into_iter()
+        .filter(|code| key_filter::is_modifier_code(code))
+        .collect();
+    let chord_modifier_codes: Vec<&str> = chord.modifiers
+        .iter()
+        .flat_map(|modifier| key_filter::modifier_codes(modifier).iter().copied())
+        .collect();
+
+    let lifted: Vec<Key> = held_modifiers
+        .iter()
+        .filter(|code| !chord_modifier_codes.contains(&code.as_str()))
+        .filter_map(|code| code_to_rdev_key(code).copied())
+        .collect();
+    let pressed: Vec<Key> = chord.modifiers
+        .iter()
+        .filter(|modifier| !key_filter::modifier_codes(modifier).iter().any(|code| held_modifiers.iter().any(|held| held == code)))
+        .filter_map(|modifier| code_to_rdev_key(key_filter::modifier_codes(modifier)[0]).copied())
+        .collect();
+
+    for modifier in &lifted {
+        send(session, &EventType::KeyRelease(*modifier));
+    }
+    for modifier in &pressed {
+        send(session, &EventType::KeyPress(*modifier));
+    }
+    send(session, &EventType::KeyPress(key));
+    send(session, &EventType::KeyRelease(key));
+    for modifier in pressed.iter().rev() {
+        send(session, &EventType::KeyRelease(*modifier));
+    }
+    for modifier in &lifted {
+        send(session, &EventType::KeyPress(*modifier));
+    }
+}
+
+// Held modifiers are lifted while typing, Shift or AltGr would change the
+// characters on some platforms
+fn inject_text(session: &Session, text: &str) -> Result<(), String> {
+    let lifted: Vec<Key> = session.held_input.lock().unwrap().held_keys()
+        .into_iter()
+        .filter(|code| key_filter::is_modifier_code(code))
+        .filter_map(|code| code_to_rdev_key(&code).copied())
+        .collect();
+
+    for modifier in &lifted {
+        send(session, &EventType::KeyRelease(*modifier));
+    }
+    let result = session.input_sink().text(text);
+    for modifier in &lifted {
+        send(session, &EventType::KeyPress(*modifier));
+    }
+    result
+}
+
+fn handle_textinput(session: &Session, text: &str) -> Result<(), String> {
+    text_input::check_length(text)?;
+    // Not the text itself, it can be a password
+    println!("text_input,{} characters", text.chars().count());
+    inject_text(session, text)
+}
+
+fn handle_keydown(session: &Session, code: &str, key: &str) {
     // TODO make sutre there is at least 20 ms between kay presses (even on rdev)
     // https://github.com/enigo-rs/enigo/issues/105
-    let code = values.next().unwrap();
-    let key = values.next().unwrap();
 
     let command = format!("key_down,{},{}", code, key);
     println!("{}", command);
 
+    let decision = key_filter::filter_keydown(code, &session.held_input.lock().unwrap());
+    match decision {
+        key_filter::KeyDecision::Pass => (),
+        key_filter::KeyDecision::Block(chord) => {
+            println!("Blocked key chord {}", chord);
+            session.suppressed_keys.lock().unwrap().insert(code.to_string());
+            return;
+        },
+        key_filter::KeyDecision::Remap(chord, remap_to) => {
+            println!("Remapped key chord {} to {}", chord, remap_to);
+            session.suppressed_keys.lock().unwrap().insert(code.to_string());
+            send_chord(session, &remap_to);
+            return;
+        },
+    }
+
+    let action = session.text_input.lock().unwrap().keydown(code, key, &session.held_input.lock().unwrap());
+    match action {
+        text_input::KeyAction::Press => (),
+        text_input::KeyAction::Type(text) => {
+            if let Err(e) = inject_text(session, &text) {
+                // The keyup is not pressed, so the key is tapped. The desktop
+                // layout decides the character like in physical mode.
+                println!("Could not type, tapping {} instead: {}", code, e);
+                if let Some(key) = code_to_rdev_key(code) {
+                    send(session, &EventType::KeyPress(*key));
+                    send(session, &EventType::KeyRelease(*key));
+                }
+            }
+            return;
+        },
+        text_input::KeyAction::Skip => return,
+    }
+
     let key = code_to_rdev_key(code);
     match key {
-        Some(key) => send(&EventType::KeyPress(*key)),
+        Some(key) => {
+            send(session, &EventType::KeyPress(*key));
+            session.held_input.lock().unwrap().key_pressed(code);
+        },
         None => println!("Unknown code: {}", code),
     }
 }
 
-fn handle_keyup(mut values: Split<&str>) {
-    let code = values.next().unwrap();
-    let key = values.next().unwrap();
-
+fn handle_keyup(session: &Session, code: &str, key: &str) {
     let command = format!("key_up,{},{}", code, key);
     println!("{}", command);
+
+    if session.suppressed_keys.lock().unwrap().remove(code) {
+        return;
+    }
+    if session.text_input.lock().unwrap().keyup(code) {
+        return;
+    }
     
     let key = code_to_rdev_key(code);
     match key {
-        Some(key) => send(&EventType::KeyRelease(*key)),
+        Some(key) => {
+            send(session, &EventType::KeyRelease(*key));
+            session.held_input.lock().unwrap().key_released(code);
+        },
         None => println!("Unknown code: {}", code),
     }
 
 }
 
-fn handle_paste(mut values: Split<&str>) {
-    let delay = time::Duration::from_millis(20);
-    
-    let data = values.next().unwrap();
-    let mut ctx = ClipboardContext::new().unwrap();
-    ctx.set_contents(data.to_owned()).unwrap();
-    send(&EventType::KeyPress(Key::ControlLeft));
-    thread::sleep(delay);
-    send(&EventType::KeyPress(Key::KeyV));
-    thread::sleep(delay);
+// Types on its own thread, with TYPE_DELAY_MS between the characters the
+// data channel would otherwise wait for the whole text. One text at a time
+// per session, typing stops if the browser disconnects.
+fn type_text(session: &Arc<Session>, text: &str) -> Result<(), String> {
+    let chords = paste::typing_chords(text)?;
+    let session = session.clone();
+
+    thread::spawn(move || {
+        let delay = time::Duration::from_millis(paste::TYPE_DELAY_MS);
+        let _typing = session.typing.lock().unwrap();
+        for chord in chords {
+            if !session.is_connected() {
+                println!("Typing stopped, the browser disconnected");
+                return;
+            }
+            send_chord(&session, &chord);
+            thread::sleep(delay);
+        }
+    });
+    Ok(())
+}
 
-    println!("Pasted {}!", data);
+// Through the clipboard and the paste shortcut, or typed in type mode
+fn paste_content(session: &Arc<Session>, content: &ClipboardContent) -> Result<(), String> {
+    let mode = session.paste_mode.lock().unwrap().clone();
+    match (&mode, content) {
+        (paste::PasteMode::Type, ClipboardContent::Text(text)) => type_text(session, text)?,
+        _ => {
+            clipboard_sync::remember(session, content);
+            write_clipboard(content)?;
+            // Released again, held modifiers of the browser are left alone
+            send_chord(session, &mode.shortcut());
+        },
+    }
+
+    println!("Pasted {} ({})", content.mime(), mode);
+    Ok(())
+}
+
+// Writes the contents once the last chunk of the transfer is in
+fn handle_clipboard_chunk(session: &Arc<Session>, transfer: u32, index: u32, count: u32, action: &str, mime: &str, data: &str) -> Result<(), String> {
+    let complete = session.clipboard_transfers.lock().unwrap().add(transfer, index, count, action, mime, data)?;
+    match complete {
+        Some((ClipboardAction::Paste, content)) => paste_content(session, &content)?,
+        Some((_, content)) => clipboard_sync::apply(session, &content)?,
+        None => {},
+    }
+    Ok(())
 }
 
 /* fn handle_leftjump(mut values: Split<&str>) {
@@ -640,38 +807,352 @@ fn handle_paste(mut values: Split<&str>) {
     }
 } */
 
-fn handle_browserinfo(values: Split<&str>) {
-    let json_string_browser_info = &values.fold(String::new(), |a, b| a + "," + b)[1..];
-    let browser_info : BrowserInfo = match serde_json::from_str(&json_string_browser_info) {
-        Ok(res) => res,
+fn handle_browserinfo(session: &Session, browser_info: BrowserInfo) {
+    if browser_info.protocolVersion != protocol::PROTOCOL_VERSION {
+        println!(
+            "Browser protocol version {} differs from desktop protocol version {}",
+            browser_info.protocolVersion,
+            protocol::PROTOCOL_VERSION,
+        );
+    }
+
+    // Until the browser sends its own settings
+    if let Some(browser_settings) = settings_store::stored_browser_settings(&browser_info) {
+        println!("Using stored settings of {}", settings_store::browser_identity(&browser_info));
+        *session.browser_settings.lock().unwrap() = browser_settings;
+    }
+
+    {
+        *session.browser_info.lock().unwrap() = browser_info;
+    }
+
+    //println!("Pasted1 {:?}", session.browser_info.lock().unwrap());
+}
+
+fn handle_browsersettings(session: &Session, browser_settings: BrowserSettings) {
+    let browser_info = session.browser_info.lock().unwrap().clone();
+    if !browser_info.is_unknown() {
+        settings_store::remember_browser_settings(&browser_info, &browser_settings);
+    }
+
+    {
+        *session.browser_settings.lock().unwrap() = browser_settings;
+    }
+
+    //println!("Pasted1 {:?}", session.browser_settings.lock().unwrap());
+}
+
+// Handles one message from the browser. Returns how long to sleep before
+// handle_post_sleep is called with the returned data.
+fn handle_message<H>(session: &Arc<Session>, message: Result<Message, ParseError>, send_event_to_front_end: H) -> (Option<u128>, PostSleepData)
+    where
+        H: FnOnce(FrontendEvent) -> (),
+{
+    let name = match &message {
+        Ok(message) => message.name().to_string(),
+        Err(_) => "error".to_string(),
+    };
+
+    let mut sleep_amount: Option<u128> = None;
+    let mut post_sleep_data = PostSleepData {
+        name,
+        is_right: false,
+        side_position: 0.0,
+        is_too_fast: false,
+        response: None,
+    };
+
+    let message = match message {
+        Ok(message) => message,
         Err(e) => {
-            println!("Could not serialize  BrowserInfo message: {}", e);
-            return;
+            // Report back instead of dropping silently, so the browser
+            // can notice it speaks a protocol the desktop does not know
+            println!("Could not parse message: {}", e);
+            post_sleep_data.response = Some(Message::Error { reason: e.to_string() });
+            return (sleep_amount, post_sleep_data);
         },
     };
 
-    {
-        *BROWSER_INFO.lock().unwrap() = browser_info;
+    if message.is_input() && !session.is_let_in() {
+        return (sleep_amount, post_sleep_data);
     }
 
-    //println!("Pasted1 {:?}", BROWSER_INFO.lock().unwrap());
+    let session_permissions = *session.permissions.lock().unwrap();
+    let checked = permissions::check(&message, &session_permissions, &mut session.reported_denials.lock().unwrap());
+    if let Err(error) = checked {
+        post_sleep_data.response = error;
+        return (sleep_amount, post_sleep_data);
+    }
+
+    mouse_recording::record(get_epoch_nanos(session), &message);
+
+    match message {
+        Message::MouseMove { x, y } => {
+            (sleep_amount, post_sleep_data) = handle_mousemove(session, x, y, post_sleep_data);
+        },
+        Message::MouseIdle => handle_mouseidle(session),
+        Message::MouseDown { button } => handle_mousedown(session, button),
+        Message::MouseUp { button } => handle_mouseup(session, button),
+        Message::Wheel { delta_mode, x, y } => handle_wheel(session, delta_mode, x, y),
+        Message::KeyDown { code, key } => handle_keydown(session, &code, &key),
+        Message::KeyUp { code, key } => handle_keyup(session, &code, &key),
+        Message::TextInput { text } => {
+            if let Err(reason) = handle_textinput(session, &text) {
+                println!("Could not type: {}", reason);
+                post_sleep_data.response = Some(Message::Error { reason });
+            }
+        },
+        Message::KeyMode { mode } => {
+            println!("Key mode {}", mode);
+            session.text_input.lock().unwrap().mode = mode;
+        },
+        Message::CompositionStart => session.text_input.lock().unwrap().composition_start(),
+        Message::CompositionUpdate { text } => session.text_input.lock().unwrap().composition_update(&text),
+        Message::CompositionEnd { text } => {
+            let committed = session.text_input.lock().unwrap().composition_end(&text);
+            if let Some(text) = committed {
+                if let Err(reason) = handle_textinput(session, &text) {
+                    println!("Could not type: {}", reason);
+                    post_sleep_data.response = Some(Message::Error { reason });
+                }
+            }
+        },
+        Message::Copy | Message::Cut => {
+            // give 50ms time for copy/cut before reading
+            sleep_amount = Some(50 * 1000000);
+        },
+        Message::Paste { text } => {
+            if let Err(reason) = paste_content(session, &ClipboardContent::Text(text)) {
+                println!("Could not paste: {}", reason);
+                post_sleep_data.response = Some(Message::Error { reason });
+            }
+        },
+        Message::PasteMode { mode } => {
+            println!("Paste mode {}", mode);
+            *session.paste_mode.lock().unwrap() = mode;
+        },
+        Message::Clipboard { text } => {
+            if let Err(reason) = clipboard_sync::apply_text(session, text) {
+                println!("Clipboard from browser not applied: {}", reason);
+                post_sleep_data.response = Some(Message::Error { reason });
+            }
+        },
+        Message::ClipboardChunk { transfer, index, count, action, mime, data } => {
+            if let Err(reason) = handle_clipboard_chunk(session, transfer, index, count, &action, &mime, &data) {
+                println!("Clipboard chunk from browser not applied: {}", reason);
+                post_sleep_data.response = Some(Message::Error { reason });
+            }
+        },
+        Message::BrowserInfo(browser_info) => {
+            handle_browserinfo(session, browser_info);
+            if *session.approval.lock().unwrap() == approval::ApprovalState::Pending {
+                // Show the prompt again with the browser details
+                send_event_to_front_end(FrontendEvent::UserApprovalPending { browserInfo: session.browser_info.lock().unwrap().clone() });
+            }
+        },
+        Message::BrowserSettings(browser_settings) => handle_browsersettings(session, browser_settings),
+        Message::ControllingStarted => send_event_to_front_end(FrontendEvent::ControllingStarted),
+        Message::ControllingStopped => {
+            held_input::release_held_input(session, "controlling stopped");
+            send_event_to_front_end(FrontendEvent::ControllingStopped);
+        },
+        Message::Blur => {
+            held_input::release_held_input(session, "browser lost focus");
+            session.text_input.lock().unwrap().cancel_composition();
+        },
+        Message::PinResponse { response } => {
+            let result = session.pin_check.lock().unwrap().verify(&response);
+            match result {
+                pin::PinResult::Verified => {
+                    post_sleep_data.response = Some(Message::PinAccepted);
+                },
+                pin::PinResult::Failed { attempts_left, challenge } => {
+                    post_sleep_data.response = Some(Message::PinRejected { attempts_left, challenge });
+                },
+                pin::PinResult::Locked => {
+                    println!("Too many wrong PIN answers, locking the session");
+                    pin::rotate_pin();
+                    session.disconnect_browser(Some(Message::PinLocked));
+                    send_event_to_front_end(FrontendEvent::SessionLocked);
+                },
+                pin::PinResult::NoChallenge => println!("PIN response without a challenge, ignored"),
+            }
+        },
+        Message::Error { reason } => println!("Browser reported an error: {}", reason),
+        Message::DesktopInfo(_)
+        | Message::CopyCut { .. }
+        | Message::MouseRight { .. }
+        | Message::TooFast
+        | Message::ApprovalPending
+        | Message::ApprovalAccepted
+        | Message::ApprovalRejected
+        | Message::PinChallenge { .. }
+        | Message::PinAccepted
+        | Message::PinRejected { .. }
+        | Message::PinLocked
+        | Message::Permissions(_)
+        | Message::ConnectionStats(_)
+        | Message::ClipboardSync { .. } => {
+            println!("Unexpected message from browser: {}", post_sleep_data.name);
+            post_sleep_data.response = Some(Message::Error { reason: format!("unexpected message: {}", post_sleep_data.name) });
+        },
+    }
+
+    return (sleep_amount, post_sleep_data);
 }
 
-fn handle_browsersettings(values: Split<&str>) {
-    let json_string_browser_settings = &values.fold(String::new(), |a, b| a + "," + b)[1..];
-    let browser_settings : BrowserSettings = match serde_json::from_str(&json_string_browser_settings) {
-        Ok(res) => res,
-        Err(e) => {
-            println!("Could not serialize  BrowserInfo message: {}", e);
+// Returns how long to sleep before calling this again, if at all
+fn handle_post_sleep(session: &Session, post_sleep_data: &PostSleepData) -> Option<u128> {
+    if post_sleep_data.name != protocol::MOUSEMOVE_NAME {
+        return None;
+    }
+
+    // Steps in between the real moves, e.g. the forecasted half step.
+    // Will be taken into account on the next move.
+    let step = session.mouse.lock().unwrap().predictor.on_step();
+    if step.x != 0 || step.y != 0 {
+        session.input_sink().mouse_move_relative(step.x, step.y);
+    }
+    step.next_sleep
+}
+
+// Of the current session, unknown without one
+pub fn get_browser_info() -> BrowserInfo {
+    match current_session() {
+        Some(session) => session.browser_info.lock().unwrap().clone(),
+        None => BrowserInfo::unknown(),
+    }
+}
+
+// Collected by the connection every STATS_INTERVAL_MS
+pub fn get_connection_stats() -> Option<stats::ConnectionStats> {
+    current_session().and_then(|session| session.latest_stats.lock().unwrap().clone())
+}
+
+pub fn answer_connection_request<H>(accept: bool, send_event_to_front_end: H)
+    where
+        H: FnOnce(FrontendEvent) -> (),
+{
+    let session = match current_session() {
+        Some(session) => session,
+        None => {
+            println!("No connection request to answer, no session");
             return;
         },
     };
+    let answered = session.approval.lock().unwrap().answer(accept);
+    if !answered {
+        println!("No connection request to answer");
+        return;
+    }
 
-    {
-        *BROWSER_SETTINGS.lock().unwrap() = browser_settings;
+    if accept {
+        println!("Connection accepted");
+        session.send_to_browser(Message::ApprovalAccepted);
+        send_event_to_front_end(session.user_connected());
+    } else {
+        println!("Connection rejected");
+        session.disconnect_browser(Some(Message::ApprovalRejected));
+        send_event_to_front_end(session.user_disconnected());
+    }
+}
+
+pub const AUTO_ACCEPT_ENV: &str = "LINKMOUSE_AUTO_ACCEPT";
+
+pub fn auto_accept() -> bool {
+    approval::auto_accept()
+}
+
+pub fn set_auto_accept(auto_accept: bool) {
+    approval::set_auto_accept(auto_accept);
+}
+
+pub fn clipboard_sync() -> bool {
+    clipboard_sync::is_enabled()
+}
+
+// Tells the connected browser too
+pub fn set_clipboard_sync(enabled: bool) {
+    if let Some(session) = current_session() {
+        clipboard_sync::reset(&session);
     }
+    clipboard_sync::set_enabled(enabled);
+    if datachannel::is_connected() {
+        send_to_browser(Message::ClipboardSync { enabled });
+    }
+}
+
+pub fn get_pin() -> Option<String> {
+    pin::get_pin()
+}
 
-    //println!("Pasted1 {:?}", BROWSER_SETTINGS.lock().unwrap());
+pub fn set_pin_enabled(enabled: bool) {
+    pin::set_pin_enabled(enabled);
+}
+
+// Of the current session
+pub fn get_permissions() -> permissions::Permissions {
+    match current_session() {
+        Some(session) => *session.permissions.lock().unwrap(),
+        None => permissions::get_default_permissions(),
+    }
+}
+
+pub fn set_default_permissions(permissions: permissions::Permissions) {
+    permissions::set_default_permissions(permissions);
+}
+
+// Only for the current session, the next one starts with the defaults again
+pub fn set_permissions(new_permissions: permissions::Permissions) {
+    let session = match current_session() {
+        Some(session) => session,
+        None => return,
+    };
+    let old_permissions = std::mem::replace(&mut *session.permissions.lock().unwrap(), new_permissions);
+    session.reported_denials.lock().unwrap().clear();
+    // Keys or buttons held with the old permissions would never be released
+    if (old_permissions.keyboard && !new_permissions.keyboard) || (old_permissions.mouse && !new_permissions.mouse) {
+        held_input::release_held_input(&session, "permissions changed");
+    }
+    if session.is_connected() {
+        session.send_to_browser(Message::Permissions(new_permissions));
+    }
+}
+
+pub fn get_acceleration_profile() -> acceleration::AccelerationProfile {
+    acceleration::get_profile()
+}
+
+// Also saved as the profile to use after restarts
+pub fn set_acceleration_profile(profile: acceleration::AccelerationProfile) -> Result<(), String> {
+    acceleration::set_profile(profile.clone())?;
+    settings_store::update_settings(|settings| settings.acceleration = Some(profile))
+}
+
+// Of the current session
+pub fn get_motion_predictor() -> predictor::PredictorKind {
+    match current_session() {
+        Some(session) => session.mouse.lock().unwrap().predictor_kind.clone(),
+        None => predictor::get_default_predictor_kind(),
+    }
+}
+
+// For the current session and the ones after it
+pub fn set_motion_predictor(kind: predictor::PredictorKind) -> Result<(), String> {
+    predictor::set_default_predictor_kind(kind.clone())?;
+    println!("Using motion predictor {:?}", kind);
+    if let Some(session) = current_session() {
+        session.mouse.lock().unwrap().set_predictor(kind);
+    }
+    Ok(())
+}
+
+pub fn get_key_rules() -> String {
+    key_filter::get_key_rules_text()
+}
+
+pub fn set_key_rules(text: &str) -> Result<(), String> {
+    key_filter::set_key_rules_text(text)
 }
 
 pub async fn main_process<H>(
@@ -684,7 +1165,7 @@ pub async fn main_process<H>(
     send_event_to_front_end: H,
 )
     where
-        H: FnOnce(String) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
+        H: FnOnce(FrontendEvent) -> () + std::marker::Sync + std::marker::Send + 'static + std::marker::Copy,
 {
 
 
@@ -733,78 +1214,14 @@ pub async fn main_process<H>(
         }
     }); */
 
-    let on_message_immmediate = move |msg: String| {
-        let mut values = msg.split(",");
-        let name = values.next().unwrap().to_string();
-
-        let mut sleep_amount: Option<u128> = None;
-        let mut post_sleep_data = PostSleepData {
-            name: name.clone(),
-            mouse_offset: MouseOffset {
-                x: 0,
-                y: 0
-            },
-            is_right: false,
-            side_position: 0.0,
-            is_too_fast: false,
-        };
-
-        if &name == "m" { // "m" == "mousemove"
-            (sleep_amount, post_sleep_data) = handle_mousemove(values, post_sleep_data);
-        } else if &name == "mouseidle" {
-            handle_mouseidle();
-        } else if &name == "mousedown" {
-            handle_mousedown(values);
-        } else if &name == "mouseup" {
-            handle_mouseup(values);
-        } else if &name == "wheel" {
-            handle_wheel(values);
-        } else if &name == "keydown" {
-            handle_keydown(values);
-        } else if &name == "keyup" {
-            handle_keyup(values);
-        } else if &name == "copy" || &name == "cut" {
-            // give 50ms time for copy/cut before reading
-            sleep_amount = Some(50 * 1000000);
-        } else if &name == "paste" {
-            handle_paste(values);
-        /* } else if &name == "leftjump" {
-            handle_leftjump(values); */
-        /* } else if &name == "mousehide" {
-            handle_mousehide(); */
-        } else if &name == "browserinfo" {
-            handle_browserinfo(values);
-        } else if &name == "browsersettings" {
-            handle_browsersettings(values);
-        } else if &name == "controllingstarted" {
-            send_event_to_front_end(CONTROLLING_STARTED.to_string());
-        } else if &name == "controllingstopped" {
-            send_event_to_front_end(CONTROLLING_STOPPED.to_string());
-        } else {
-            println!("Unknown event.name: {}", name);
-        }
-
-        return (sleep_amount, post_sleep_data);
-    };
-        
-    let on_message_post_sleep = move |post_sleep_data: PostSleepData| {
-        if post_sleep_data.name == "mousemove"{
-            // Move halfway halfway to the forecasted new position.
-            // Will be taken into account on the next move.
-            // Forecasts smoothen the operation, as the mouse updates are doubled.
-            if post_sleep_data.mouse_offset.x == 0 && post_sleep_data.mouse_offset.y == 0 {
-                //println!("Zero move skipped");
-                return;
-            }
-
-            mouse_move_relative(post_sleep_data.mouse_offset.x, post_sleep_data.mouse_offset.y, false);
-        }
+    let on_message_immmediate = move |session: &Arc<Session>, message: Result<Message, ParseError>| {
+        handle_message(session, message, send_event_to_front_end)
     };
 
     process_datachannel_messages(
         random_id,
         on_message_immmediate,
-        on_message_post_sleep,
+        handle_post_sleep,
         recv_stop_2,
         recv_stop_3,
         send_event_to_front_end,
@@ -818,4 +1235,163 @@ pub async fn main_process<H>(
     if let Err(_e) = send_finished.send(true) {
         println!("Could not send finished");
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main_process::input_sink::{InjectedEvent, RecordingSink};
+
+    // A session that was let in, injecting into the returned sink
+    fn accepted_session() -> (Arc<Session>, Arc<RecordingSink>) {
+        let sink = Arc::new(RecordingSink::new());
+        let session = Session::with_input_sink(sink.clone());
+        *session.approval.lock().unwrap() = approval::ApprovalState::Accepted;
+        session.pin_check.lock().unwrap().start();
+        (session, sink)
+    }
+
+    // Handles the message and its steps after the sleeps, returns what was injected
+    fn handle(session: &Arc<Session>, sink: &RecordingSink, msg: &str) -> Vec<InjectedEvent> {
+        let (_, post_sleep_data) = handle_message(session, Message::parse(msg), |_| {});
+        while handle_post_sleep(session, &post_sleep_data).is_some() {}
+        sink.take_events()
+    }
+
+    #[test]
+    fn keys_are_pressed_and_released() {
+        let (session, sink) = accepted_session();
+        assert_eq!(handle(&session, &sink, "keydown,KeyA,a"), vec![InjectedEvent::Event(EventType::KeyPress(Key::KeyA))]);
+        assert_eq!(handle(&session, &sink, "keyup,KeyA,a"), vec![InjectedEvent::Event(EventType::KeyRelease(Key::KeyA))]);
+    }
+
+    #[test]
+    fn blocked_chords_drop_their_keyup() {
+        let (session, sink) = accepted_session();
+        handle(&session, &sink, "keydown,AltLeft,Alt");
+        assert_eq!(handle(&session, &sink, "keydown,F4,F4"), vec![]);
+        assert_eq!(handle(&session, &sink, "keyup,F4,F4"), vec![]);
+        assert_eq!(handle(&session, &sink, "keyup,AltLeft,Alt"), vec![InjectedEvent::Event(EventType::KeyRelease(Key::Alt))]);
+    }
+
+    #[test]
+    fn held_input_is_released_on_blur() {
+        let (session, sink) = accepted_session();
+        handle(&session, &sink, "keydown,ShiftLeft,Shift");
+        handle(&session, &sink, "mousedown,2");
+        let mut released = handle(&session, &sink, "blur");
+        released.sort_by_key(|event| format!("{:?}", event));
+        assert_eq!(released, vec![
+            InjectedEvent::Event(EventType::ButtonRelease(Button::Right)),
+            InjectedEvent::Event(EventType::KeyRelease(Key::ShiftLeft)),
+        ]);
+        assert_eq!(handle(&session, &sink, "blur"), vec![]);
+    }
+
+    #[test]
+    fn compositions_are_injected_as_text() {
+        let (session, sink) = accepted_session();
+        handle(&session, &sink, "compositionstart");
+        assert_eq!(handle(&session, &sink, "keydown,KeyN,Process"), vec![]);
+        assert_eq!(handle(&session, &sink, "compositionupdate,に"), vec![]);
+        assert_eq!(handle(&session, &sink, "keyup,KeyN,n"), vec![]);
+        assert_eq!(handle(&session, &sink, "keydown,Enter,Process"), vec![]);
+        assert_eq!(handle(&session, &sink, "compositionend,日本"), vec![InjectedEvent::Text("日本".to_string())]);
+        assert_eq!(handle(&session, &sink, "keyup,Enter,Enter"), vec![]);
+        // Commas are part of the text
+        handle(&session, &sink, "compositionstart");
+        assert_eq!(handle(&session, &sink, "compositionend,a,b"), vec![InjectedEvent::Text("a,b".to_string())]);
+    }
+
+    #[test]
+    fn dead_keys_leave_no_stray_keystrokes() {
+        let (session, sink) = accepted_session();
+        handle(&session, &sink, "keymode,character");
+        assert_eq!(handle(&session, &sink, "keydown,Quote,Dead"), vec![]);
+        assert_eq!(handle(&session, &sink, "keyup,Quote,Dead"), vec![]);
+        assert_eq!(handle(&session, &sink, "keydown,KeyE,é"), vec![InjectedEvent::Text("é".to_string())]);
+        assert_eq!(handle(&session, &sink, "keyup,KeyE,e"), vec![]);
+
+        // Cancelled, an empty commit types nothing
+        handle(&session, &sink, "compositionstart");
+        assert_eq!(handle(&session, &sink, "keydown,Quote,Dead"), vec![]);
+        assert_eq!(handle(&session, &sink, "compositionupdate,´"), vec![]);
+        assert_eq!(handle(&session, &sink, "compositionend"), vec![]);
+        assert_eq!(handle(&session, &sink, "keyup,Quote,Dead"), vec![]);
+        assert_eq!(handle(&session, &sink, "keydown,KeyA,a"), vec![InjectedEvent::Text("a".to_string())]);
+
+        // Lost with the focus, the next keys are pressed again
+        handle(&session, &sink, "keymode,physical");
+        handle(&session, &sink, "compositionstart");
+        handle(&session, &sink, "compositionupdate,´");
+        assert_eq!(handle(&session, &sink, "blur"), vec![]);
+        assert_eq!(handle(&session, &sink, "keydown,KeyA,a"), vec![InjectedEvent::Event(EventType::KeyPress(Key::KeyA))]);
+    }
+
+    #[test]
+    fn mouse_buttons_are_pressed_and_released() {
+        let (session, sink) = accepted_session();
+        assert_eq!(handle(&session, &sink, "mousedown,0"), vec![InjectedEvent::Event(EventType::ButtonPress(Button::Left))]);
+        assert_eq!(handle(&session, &sink, "mouseup,0"), vec![InjectedEvent::Event(EventType::ButtonRelease(Button::Left))]);
+    }
+
+    #[test]
+    fn wheel_scrolls_by_pixels_or_lines() {
+        let (session, sink) = accepted_session();
+        // Not reversed, so the browser deltas are turned around for y
+        assert_eq!(handle(&session, &sink, "wheel,0,1.5,2"), vec![InjectedEvent::ScrollPixels { x: 1.5, y: -2.0 }]);
+        assert_eq!(handle(&session, &sink, "wheel,1,0,3"), vec![InjectedEvent::ScrollLines { x: 0.0, y: -3.0 }]);
+
+        session.browser_settings.lock().unwrap().scrollSpeed = 2.0;
+        session.browser_settings.lock().unwrap().scrollReversed = true;
+        assert_eq!(handle(&session, &sink, "wheel,2,1,1"), vec![InjectedEvent::ScrollLines { x: 2.0, y: 2.0 }]);
+    }
+
+    #[test]
+    fn mousemove_keeps_the_sub_pixel_remainder() {
+        let (session, sink) = accepted_session();
+        session.mouse.lock().unwrap().set_predictor(predictor::PredictorKind::None);
+        {
+            let mut browser_settings = session.browser_settings.lock().unwrap();
+            browser_settings.mouseSpeed = 0.5;
+            browser_settings.mouseAcceleration = false;
+        }
+        // 1.5 and -1.5, half a pixel left over on both axes
+        assert_eq!(handle(&session, &sink, "m,3,-3"), vec![InjectedEvent::MouseMoveRelative { x: 1, y: -2 }]);
+        assert_eq!(handle(&session, &sink, "m,3,-3"), vec![InjectedEvent::MouseMoveRelative { x: 2, y: -1 }]);
+    }
+
+    #[test]
+    fn mousemove_steps_come_after_the_sleep() {
+        let (session, sink) = accepted_session();
+        session.mouse.lock().unwrap().set_predictor(predictor::PredictorKind::HalfStep);
+        session.browser_settings.lock().unwrap().mouseAcceleration = false;
+
+        let (sleep_amount, post_sleep_data) = handle_message(&session, Message::parse("m,10,4"), |_| {});
+        assert!(sleep_amount.is_some());
+        assert_eq!(sink.take_events(), vec![InjectedEvent::MouseMoveRelative { x: 10, y: 4 }]);
+        assert_eq!(handle_post_sleep(&session, &post_sleep_data), None);
+        assert_eq!(sink.take_events(), vec![InjectedEvent::MouseMoveRelative { x: 5, y: 2 }]);
+    }
+
+    #[test]
+    fn input_follows_the_session_permissions() {
+        let (session, sink) = accepted_session();
+        *session.permissions.lock().unwrap() = permissions::Permissions::parse("keyboard").unwrap();
+        let (_, post_sleep_data) = handle_message(&session, Message::parse("mousedown,0"), |_| {});
+        assert!(matches!(post_sleep_data.response, Some(Message::Error { .. })));
+        assert_eq!(handle(&session, &sink, "m,5,5"), vec![]);
+        assert_eq!(handle(&session, &sink, "keydown,KeyA,a"), vec![InjectedEvent::Event(EventType::KeyPress(Key::KeyA))]);
+
+        // A new session starts with the defaults again
+        let (session, sink) = accepted_session();
+        assert_eq!(handle(&session, &sink, "mousedown,0"), vec![InjectedEvent::Event(EventType::ButtonPress(Button::Left))]);
+    }
+
+    #[test]
+    fn input_waits_for_approval() {
+        let sink = Arc::new(RecordingSink::new());
+        let session = Session::with_input_sink(sink.clone());
+        assert_eq!(handle(&session, &sink, "keydown,KeyA,a"), vec![]);
+        assert_eq!(handle(&session, &sink, "m,5,5"), vec![]);
+    }
+}
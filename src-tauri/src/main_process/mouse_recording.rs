@@ -0,0 +1,87 @@
+// Records the mouse messages from the browser with their arrival time, so
+// that the smoothing can be evaluated offline with linkmouse-replay (see
+// replay.rs). Enabled with LINKMOUSE_RECORD_MOUSE=<path>. One message per
+// line, the arrival time in nanoseconds and the text form of the message:
+//
+//   1699999999123456789 browsersettings,{"mouseSpeed":1.0,...}
+//   1699999999140000000 m,12,-3
+//   1699999999500000000 mouseidle
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::main_process::protocol::Message;
+
+pub const RECORD_MOUSE_ENV: &str = "LINKMOUSE_RECORD_MOUSE";
+
+lazy_static! {
+    static ref RECORDING: Arc<Mutex<Option<BufWriter<File>>>> = Arc::new(Mutex::new(None));
+}
+
+// Appends to the file, so restarts of the connection end up in the same recording
+pub fn start_recording(path: &Path) -> Result<(), String> {
+    let file = match fs::OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(format!("Could not open {}: {}", path.display(), e)),
+    };
+    *RECORDING.lock().unwrap() = Some(BufWriter::new(file));
+    println!("Recording mouse messages to {}", path.display());
+    Ok(())
+}
+
+pub fn is_recorded(message: &Message) -> bool {
+    matches!(message, Message::MouseMove { .. } | Message::MouseIdle | Message::BrowserSettings(_))
+}
+
+pub fn record(nanos: u128, message: &Message) {
+    if !is_recorded(message) {
+        return;
+    }
+    let mut recording = RECORDING.lock().unwrap();
+    let writer = match recording.as_mut() {
+        Some(writer) => writer,
+        None => return,
+    };
+    let mut result = writeln!(writer, "{} {}", nanos, message.serialize());
+    // A burst of moves ends with mouseidle, a good time to write it out
+    if result.is_ok() && *message == Message::MouseIdle {
+        result = writer.flush();
+    }
+    if let Err(e) = result {
+        println!("Could not record mouse messages, stopping: {}", e);
+        *recording = None;
+    }
+}
+
+pub fn read_recording(path: &Path) -> Result<Vec<(u128, Message)>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => return Err(format!("Could not read {}: {}", path.display(), e)),
+    };
+
+    let mut messages = vec![];
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (nanos, message) = match line.split_once(' ') {
+            Some(parts) => parts,
+            None => return Err(format!("{}:{}: expected '<nanos> <message>'", path.display(), index + 1)),
+        };
+        let nanos = match nanos.parse::<u128>() {
+            Ok(nanos) => nanos,
+            Err(_) => return Err(format!("{}:{}: invalid time '{}'", path.display(), index + 1, nanos)),
+        };
+        match Message::parse(message) {
+            Ok(message) => messages.push((nanos, message)),
+            Err(e) => return Err(format!("{}:{}: {}", path.display(), index + 1, e)),
+        }
+    }
+    // Messages are handled one at a time, but check anyway in case files were concatenated
+    if messages.windows(2).any(|pair| pair[0].0 > pair[1].0) {
+        return Err(format!("{}: times are not in order", path.display()));
+    }
+    Ok(messages)
+}
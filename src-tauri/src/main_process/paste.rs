@@ -0,0 +1,209 @@
+// How `paste` reaches the application. By default the text goes to the
+// clipboard and the paste shortcut of the desktop OS is pressed: Cmd+V on
+// macOS, Ctrl+V elsewhere. The browser can change this for its session with
+// `pastemode,<mode>`:
+//
+//   pastemode,auto                     the shortcut of the desktop OS
+//   pastemode,Control+Shift+KeyV       another shortcut, e.g. for terminals
+//   pastemode,type                     types the text out key by key
+//
+// Typing is for applications that block pasting, like VM consoles and
+// password fields. It presses the keys of a US layout, so the desktop should
+// use one, and text with other characters is rejected before anything is
+// typed. This differs from `textinput` (InputSink::text, see text_input.rs),
+// which types any character whatever the layout: VM consoles and remote
+// desktops pass on key presses only. The keys are pressed on a thread of
+// their own, see type_text in mod.rs.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::main_process::key_filter::KeyChord;
+use crate::main_process::shared_settings::DESKTOP_INFO;
+
+pub const TYPE_MAX_CHARS: usize = 2000;
+pub const TYPE_DELAY_MS: u64 = 10; // Between characters, some applications drop faster input
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasteMode {
+    Auto,
+    Shortcut(KeyChord),
+    Type,
+}
+
+impl FromStr for PasteMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(PasteMode::Auto),
+            "type" => Ok(PasteMode::Type),
+            _ => KeyChord::parse(value).map(PasteMode::Shortcut),
+        }
+    }
+}
+
+impl fmt::Display for PasteMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasteMode::Auto => write!(f, "auto"),
+            PasteMode::Shortcut(chord) => write!(f, "{}", chord),
+            PasteMode::Type => write!(f, "type"),
+        }
+    }
+}
+
+impl PasteMode {
+    // Also used for images and other formats in type mode
+    pub fn shortcut(&self) -> KeyChord {
+        match self {
+            PasteMode::Shortcut(chord) => chord.clone(),
+            PasteMode::Auto | PasteMode::Type => os_paste_shortcut(&DESKTOP_INFO.lock().unwrap().osName),
+        }
+    }
+}
+
+pub fn os_paste_shortcut(os_name: &str) -> KeyChord {
+    let modifier = match os_name {
+        "macos" => "Meta",
+        _ => "Control",
+    };
+    KeyChord { modifiers: vec![modifier], code: "KeyV".to_string() }
+}
+
+fn key(code: &str, shift: bool) -> KeyChord {
+    let modifiers = match shift {
+        true => vec!["Shift"],
+        false => vec![],
+    };
+    KeyChord { modifiers, code: code.to_string() }
+}
+
+// US layout
+fn char_to_chord(c: char) -> Option<KeyChord> {
+    const DIGITS_SHIFTED: &str = ")!@#$%^&*(";
+    const SYMBOLS: [(&str, char, char); 11] = [
+        ("Backquote", '`', '~'),
+        ("Minus", '-', '_'),
+        ("Equal", '=', '+'),
+        ("BracketLeft", '[', '{'),
+        ("BracketRight", ']', '}'),
+        ("Backslash", '\\', '|'),
+        ("Semicolon", ';', ':'),
+        ("Quote", '\'', '"'),
+        ("Comma", ',', '<'),
+        ("Period", '.', '>'),
+        ("Slash", '/', '?'),
+    ];
+
+    match c {
+        'a'..='z' => Some(key(&format!("Key{}", c.to_ascii_uppercase()), false)),
+        'A'..='Z' => Some(key(&format!("Key{}", c), true)),
+        '0'..='9' => Some(key(&format!("Digit{}", c), false)),
+        ' ' => Some(key("Space", false)),
+        '\n' => Some(key("Enter", false)),
+        '\t' => Some(key("Tab", false)),
+        _ => {
+            if let Some(digit) = DIGITS_SHIFTED.find(c) {
+                return Some(key(&format!("Digit{}", digit), true));
+            }
+            SYMBOLS.iter().find_map(|(code, plain, shifted)| match c {
+                _ if c == *plain => Some(key(code, false)),
+                _ if c == *shifted => Some(key(code, true)),
+                _ => None,
+            })
+        },
+    }
+}
+
+// The keys to type, or why the text cannot be typed
+pub fn typing_chords(text: &str) -> Result<Vec<KeyChord>, String> {
+    let text = text.replace("\r\n", "\n");
+    let length = text.chars().count();
+    if length > TYPE_MAX_CHARS {
+        return Err(format!("paste: {} characters is over {} for typing", length, TYPE_MAX_CHARS));
+    }
+
+    let mut chords = vec![];
+    for c in text.chars() {
+        match char_to_chord(c) {
+            Some(chord) => chords.push(chord),
+            None => return Err(format!("paste: cannot type {:?} on a US layout", c)),
+        }
+    }
+    Ok(chords)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chords(values: &[&str]) -> Vec<KeyChord> {
+        values.iter().map(|value| KeyChord::parse(value).unwrap()).collect()
+    }
+
+    #[test]
+    fn letters_digits_and_whitespace() {
+        assert_eq!(typing_chords("aZ 0\t9").unwrap(), chords(&["KeyA", "Shift+KeyZ", "Space", "Digit0", "Tab", "Digit9"]));
+        assert_eq!(typing_chords("a\r\nb\nc").unwrap(), chords(&["KeyA", "Enter", "KeyB", "Enter", "KeyC"]));
+        assert_eq!(typing_chords("").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn shifted_digits_and_symbols() {
+        assert_eq!(
+            typing_chords("!@#$%^&*()").unwrap(),
+            chords(&[
+                "Shift+Digit1", "Shift+Digit2", "Shift+Digit3", "Shift+Digit4", "Shift+Digit5",
+                "Shift+Digit6", "Shift+Digit7", "Shift+Digit8", "Shift+Digit9", "Shift+Digit0",
+            ]),
+        );
+        assert_eq!(typing_chords("-_=+").unwrap(), chords(&["Minus", "Shift+Minus", "Equal", "Shift+Equal"]));
+        assert_eq!(typing_chords("\\|'\"").unwrap(), chords(&["Backslash", "Shift+Backslash", "Quote", "Shift+Quote"]));
+        assert_eq!(typing_chords("`~/?").unwrap(), chords(&["Backquote", "Shift+Backquote", "Slash", "Shift+Slash"]));
+    }
+
+    #[test]
+    fn every_printable_ascii_character_is_a_known_key() {
+        for c in ' '..='~' {
+            let chord = char_to_chord(c).unwrap_or_else(|| panic!("{:?} has no key", c));
+            assert_eq!(KeyChord::parse(&chord.to_string()), Ok(chord));
+        }
+    }
+
+    #[test]
+    fn other_characters_are_rejected_before_typing() {
+        for text in ["café", "a\u{00a0}b", "€", "日本", "a\rb", "🙂"] {
+            assert!(typing_chords(text).is_err(), "{:?}", text);
+        }
+        // Even when the rest could be typed
+        assert!(typing_chords(&format!("{}é", "a".repeat(100))).is_err());
+    }
+
+    #[test]
+    fn long_text_is_rejected() {
+        assert_eq!(typing_chords(&"a".repeat(TYPE_MAX_CHARS)).unwrap().len(), TYPE_MAX_CHARS);
+        assert!(typing_chords(&"a".repeat(TYPE_MAX_CHARS + 1)).is_err());
+        // Line ends count once
+        assert_eq!(typing_chords(&"\r\n".repeat(TYPE_MAX_CHARS)).unwrap().len(), TYPE_MAX_CHARS);
+    }
+
+    #[test]
+    fn paste_shortcut_of_each_os() {
+        assert_eq!(os_paste_shortcut("macos"), KeyChord::parse("Meta+KeyV").unwrap());
+        assert_eq!(os_paste_shortcut("windows"), KeyChord::parse("Control+KeyV").unwrap());
+        assert_eq!(os_paste_shortcut("linux"), KeyChord::parse("Control+KeyV").unwrap());
+        assert_eq!(os_paste_shortcut(""), KeyChord::parse("Control+KeyV").unwrap());
+    }
+
+    #[test]
+    fn paste_modes() {
+        assert_eq!("auto".parse(), Ok(PasteMode::Auto));
+        assert_eq!("type".parse(), Ok(PasteMode::Type));
+        let terminal: PasteMode = "Control+Shift+KeyV".parse().unwrap();
+        assert_eq!(terminal, PasteMode::Shortcut(KeyChord::parse("Control+Shift+KeyV").unwrap()));
+        assert_eq!(terminal.to_string(), "Control+Shift+KeyV");
+        assert_eq!(terminal.shortcut(), KeyChord::parse("Control+Shift+KeyV").unwrap());
+        assert!("paste".parse::<PasteMode>().is_err());
+    }
+}
@@ -0,0 +1,174 @@
+// What the connected browser is allowed to do. Every session starts with the
+// default permissions (CLI, env or stored settings, otherwise everything), and
+// the host can change them for the session from the UI. Enforced in
+// handle_message.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+
+use crate::main_process::protocol::Message;
+
+pub const PERMISSIONS_ENV: &str = "LINKMOUSE_PERMISSIONS";
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Permissions {
+    pub mouse: bool,
+    pub keyboard: bool,
+    pub clipboardRead: bool, // copy/cut send the desktop clipboard to the browser
+    pub clipboardWrite: bool, // paste writes the browser clipboard on the desktop
+}
+
+impl Permissions {
+    pub fn all() -> Self {
+        Permissions { mouse: true, keyboard: true, clipboardRead: true, clipboardWrite: true }
+    }
+
+    pub fn view_only() -> Self {
+        Permissions { mouse: false, keyboard: false, clipboardRead: false, clipboardWrite: false }
+    }
+
+    // "all", "view-only", or a comma separated list of
+    // "mouse", "keyboard", "clipboard-read" and "clipboard-write"
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "all" => return Ok(Permissions::all()),
+            "view-only" => return Ok(Permissions::view_only()),
+            _ => (),
+        }
+
+        let mut permissions = Permissions::view_only();
+        for scope in value.split(',').map(|scope| scope.trim()) {
+            match scope {
+                "mouse" => permissions.mouse = true,
+                "keyboard" => permissions.keyboard = true,
+                "clipboard-read" => permissions.clipboardRead = true,
+                "clipboard-write" => permissions.clipboardWrite = true,
+                _ => return Err(format!("Unknown permission: {}", scope)),
+            }
+        }
+        Ok(permissions)
+    }
+
+    // The scope that is missing for the message, if any
+    pub fn missing_scope(&self, message: &Message) -> Option<&'static str> {
+        match message {
+            Message::MouseMove { .. }
+            | Message::MouseIdle
+            | Message::MouseDown { .. }
+            | Message::MouseUp { .. }
+            | Message::Wheel { .. } if !self.mouse => Some("mouse"),
+            Message::KeyDown { .. }
+            | Message::KeyUp { .. }
+            | Message::TextInput { .. }
+            | Message::CompositionStart
+            | Message::CompositionUpdate { .. }
+            | Message::CompositionEnd { .. } if !self.keyboard => Some("keyboard"),
+            Message::Copy | Message::Cut if !self.clipboardRead => Some("clipboard-read"),
+            Message::Paste { .. } | Message::Clipboard { .. } | Message::ClipboardChunk { .. } if !self.clipboardWrite => Some("clipboard-write"),
+            // Paste presses the paste shortcut
+            Message::Paste { .. } if !self.keyboard => Some("keyboard"),
+            Message::ClipboardChunk { action, .. } if action == "paste" && !self.keyboard => Some("keyboard"),
+            _ => None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref DEFAULT_PERMISSIONS: Arc<Mutex<Permissions>> = Arc::new(Mutex::new(Permissions::all()));
+}
+
+pub fn get_default_permissions() -> Permissions {
+    *DEFAULT_PERMISSIONS.lock().unwrap()
+}
+
+// For the sessions made after this, the current one keeps its permissions
+pub fn set_default_permissions(permissions: Permissions) {
+    *DEFAULT_PERMISSIONS.lock().unwrap() = permissions;
+}
+
+// Returns the error to send back to the browser, None if it has been sent
+// already. reported_denials are the message names already reported in the
+// session, mouse moves would otherwise produce an error for every event.
+pub fn check(message: &Message, permissions: &Permissions, reported_denials: &mut HashSet<&'static str>) -> Result<(), Option<Message>> {
+    let scope = match permissions.missing_scope(message) {
+        Some(scope) => scope,
+        None => return Ok(()),
+    };
+
+    if !reported_denials.insert(message.name()) {
+        return Err(None);
+    }
+    println!("Denied {}, missing permission {}", message.name(), scope);
+    Err(Some(Message::Error { reason: format!("permission denied: {} requires {}", message.name(), scope) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(action: &str) -> Message {
+        Message::ClipboardChunk { transfer: 1, index: 0, count: 1, action: action.to_string(), mime: "image/png".to_string(), data: String::new() }
+    }
+
+    #[test]
+    fn parses_scopes() {
+        assert_eq!(Permissions::parse("all"), Ok(Permissions::all()));
+        assert_eq!(Permissions::parse("view-only"), Ok(Permissions::view_only()));
+        assert_eq!(
+            Permissions::parse("mouse, clipboard-read"),
+            Ok(Permissions { mouse: true, keyboard: false, clipboardRead: true, clipboardWrite: false }),
+        );
+        assert!(Permissions::parse("mouse,admin").is_err());
+    }
+
+    #[test]
+    fn each_message_needs_its_scope() {
+        let missing = |permissions: Permissions, message: Message| permissions.missing_scope(&message);
+        let view_only = Permissions::view_only();
+        assert_eq!(missing(view_only, Message::MouseMove { x: 1, y: 1 }), Some("mouse"));
+        assert_eq!(missing(view_only, Message::Wheel { delta_mode: 0, x: 0.0, y: 1.0 }), Some("mouse"));
+        assert_eq!(missing(view_only, Message::KeyDown { code: "KeyA".to_string(), key: "a".to_string() }), Some("keyboard"));
+        assert_eq!(missing(view_only, Message::CompositionEnd { text: "a".to_string() }), Some("keyboard"));
+        assert_eq!(missing(view_only, Message::Copy), Some("clipboard-read"));
+        assert_eq!(missing(view_only, Message::Clipboard { text: "a".to_string() }), Some("clipboard-write"));
+        assert_eq!(missing(view_only, Message::Blur), None);
+        assert_eq!(missing(view_only, Message::PinResponse { response: "a".to_string() }), None);
+
+        for message in [Message::MouseIdle, Message::KeyUp { code: "KeyA".to_string(), key: "a".to_string() }, Message::Cut, chunk("copy")] {
+            assert_eq!(missing(Permissions::all(), message), None);
+        }
+    }
+
+    #[test]
+    fn paste_also_needs_the_keyboard() {
+        let clipboard_only = Permissions { mouse: false, keyboard: false, clipboardRead: true, clipboardWrite: true };
+        let paste = Message::Paste { text: "a".to_string() };
+        assert_eq!(clipboard_only.missing_scope(&paste), Some("keyboard"));
+        assert_eq!(clipboard_only.missing_scope(&chunk("paste")), Some("keyboard"));
+        // Only written to the clipboard, no shortcut is pressed
+        assert_eq!(clipboard_only.missing_scope(&chunk("sync")), None);
+        assert_eq!(clipboard_only.missing_scope(&Message::Clipboard { text: "a".to_string() }), None);
+
+        let keyboard_only = Permissions { mouse: false, keyboard: true, clipboardRead: false, clipboardWrite: false };
+        assert_eq!(keyboard_only.missing_scope(&paste), Some("clipboard-write"));
+        assert_eq!(keyboard_only.missing_scope(&chunk("paste")), Some("clipboard-write"));
+    }
+
+    #[test]
+    fn reports_one_error_per_message_name() {
+        let mut reported_denials = HashSet::new();
+        let view_only = Permissions::view_only();
+        let mousemove = Message::MouseMove { x: 1, y: 1 };
+
+        match check(&mousemove, &view_only, &mut reported_denials) {
+            Err(Some(Message::Error { reason })) => assert_eq!(reason, "permission denied: m requires mouse"),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(check(&mousemove, &view_only, &mut reported_denials), Err(None));
+        assert!(matches!(check(&Message::MouseIdle, &view_only, &mut reported_denials), Err(Some(_))));
+        assert_eq!(check(&mousemove, &Permissions::all(), &mut reported_denials), Ok(()));
+    }
+}
@@ -0,0 +1,220 @@
+// Optional PIN on top of the random link id. The PIN is only shown in the
+// desktop UI and never goes through the signaling server.
+//
+// The browser proves it knows the PIN without sending it: the desktop sends a
+// random challenge, and the browser answers with the hex encoded
+// SHA-256("<challenge>:<pin>"). Every answer gets a new challenge. After
+// MAX_PIN_FAILURES wrong answers for the same link the session is locked,
+// and the link id and the PIN are rotated.
+//
+// The PIN and the failures are kept across connections, the challenge and
+// whether it was answered are in the PinCheck of the Session.
+
+use std::sync::{Arc, Mutex};
+
+use rand::Rng;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+
+const PIN_LENGTH: usize = 6;
+const CHALLENGE_BYTES: usize = 16;
+pub const MAX_PIN_FAILURES: u32 = 3;
+
+struct PinState {
+    pin: Option<String>,
+    failures: u32, // Not reset between connections, only when rotated
+}
+
+// One per session
+pub struct PinCheck {
+    challenge: Option<String>,
+    verified: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PinResult {
+    Verified,
+    Failed { attempts_left: u32, challenge: String },
+    Locked,
+    NoChallenge, // No PIN, or a late or duplicate answer, ignored
+}
+
+lazy_static! {
+    static ref PIN_STATE: Arc<Mutex<PinState>> = Arc::new(Mutex::new(PinState { pin: None, failures: 0 }));
+}
+
+fn random_pin() -> String {
+    let mut rng = OsRng;
+    (0..PIN_LENGTH).map(|_| char::from(b'0' + rng.gen_range(0..10))).collect()
+}
+
+fn random_challenge() -> String {
+    let mut rng = OsRng;
+    (0..CHALLENGE_BYTES).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
+fn expected_response(challenge: &str, pin: &str) -> String {
+    Sha256::digest(format!("{}:{}", challenge, pin).as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Compares every byte, so the time taken does not tell how much matched
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub fn get_pin() -> Option<String> {
+    PIN_STATE.lock().unwrap().pin.clone()
+}
+
+pub fn set_pin_enabled(enabled: bool) {
+    let mut state = PIN_STATE.lock().unwrap();
+    state.pin = match enabled {
+        true => Some(random_pin()),
+        false => None,
+    };
+    state.failures = 0;
+}
+
+pub fn rotate_pin() {
+    let mut state = PIN_STATE.lock().unwrap();
+    if state.pin.is_some() {
+        state.pin = Some(random_pin());
+    }
+    state.failures = 0;
+}
+
+impl PinCheck {
+    // Nothing can be verified before the data channel
+    pub fn new() -> Self {
+        PinCheck { challenge: None, verified: false }
+    }
+
+    // Called for each new data channel. Returns the challenge to send,
+    // or None if the PIN is not in use.
+    pub fn start(&mut self) -> Option<String> {
+        self.start_with(&PIN_STATE.lock().unwrap())
+    }
+
+    fn start_with(&mut self, state: &PinState) -> Option<String> {
+        match state.pin {
+            Some(_) => {
+                let challenge = random_challenge();
+                self.challenge = Some(challenge.clone());
+                self.verified = false;
+                Some(challenge)
+            },
+            None => {
+                self.challenge = None;
+                self.verified = true;
+                None
+            },
+        }
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.is_verified_with(&PIN_STATE.lock().unwrap())
+    }
+
+    fn is_verified_with(&self, state: &PinState) -> bool {
+        state.pin.is_none() || self.verified
+    }
+
+    pub fn verify(&mut self, response: &str) -> PinResult {
+        self.verify_with(&mut PIN_STATE.lock().unwrap(), response)
+    }
+
+    fn verify_with(&mut self, state: &mut PinState, response: &str) -> PinResult {
+        let (pin, challenge) = match (state.pin.clone(), self.challenge.take()) {
+            (Some(pin), Some(challenge)) => (pin, challenge),
+            _ => return PinResult::NoChallenge,
+        };
+
+        if constant_time_eq(response, &expected_response(&challenge, &pin)) {
+            self.verified = true;
+            state.failures = 0;
+            return PinResult::Verified;
+        }
+
+        state.failures += 1;
+        println!("Wrong PIN response ({}/{})", state.failures, MAX_PIN_FAILURES);
+        if state.failures >= MAX_PIN_FAILURES {
+            return PinResult::Locked;
+        }
+
+        let challenge = random_challenge();
+        self.challenge = Some(challenge.clone());
+        PinResult::Failed { attempts_left: MAX_PIN_FAILURES - state.failures, challenge }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The global PIN_STATE is left alone, other tests let sessions in without a PIN
+    fn pin_state() -> PinState {
+        PinState { pin: Some("123456".to_string()), failures: 0 }
+    }
+
+    #[test]
+    fn accepts_the_answer_to_the_challenge() {
+        let mut state = pin_state();
+        let mut pin_check = PinCheck::new();
+        let challenge = pin_check.start_with(&state).unwrap();
+        assert_eq!(challenge.len(), CHALLENGE_BYTES * 2);
+        assert!(!pin_check.is_verified_with(&state));
+
+        let response = expected_response(&challenge, "123456");
+        assert_eq!(pin_check.verify_with(&mut state, &response), PinResult::Verified);
+        assert!(pin_check.is_verified_with(&state));
+        // The challenge is used up
+        assert_eq!(pin_check.verify_with(&mut state, &response), PinResult::NoChallenge);
+    }
+
+    #[test]
+    fn wrong_answers_get_a_new_challenge_until_locked() {
+        let mut state = pin_state();
+        let mut pin_check = PinCheck::new();
+        let mut challenge = pin_check.start_with(&state).unwrap();
+
+        for attempts_left in (1..MAX_PIN_FAILURES).rev() {
+            let wrong = expected_response(&challenge, "654321");
+            match pin_check.verify_with(&mut state, &wrong) {
+                PinResult::Failed { attempts_left: left, challenge: next } => {
+                    assert_eq!(left, attempts_left);
+                    assert_ne!(next, challenge);
+                    challenge = next;
+                },
+                other => panic!("{:?}", other),
+            }
+            assert!(!pin_check.is_verified_with(&state));
+        }
+
+        assert_eq!(pin_check.verify_with(&mut state, "00"), PinResult::Locked);
+        assert_eq!(state.failures, MAX_PIN_FAILURES);
+        assert!(!pin_check.is_verified_with(&state));
+    }
+
+    #[test]
+    fn answers_without_a_challenge_are_ignored() {
+        let mut state = pin_state();
+        let mut pin_check = PinCheck::new();
+        let response = expected_response("", "123456");
+        assert_eq!(pin_check.verify_with(&mut state, &response), PinResult::NoChallenge);
+        assert_eq!(state.failures, 0);
+        assert!(!pin_check.is_verified_with(&state));
+    }
+
+    #[test]
+    fn verified_without_a_pin() {
+        let mut state = PinState { pin: None, failures: 0 };
+        let mut pin_check = PinCheck::new();
+        assert!(pin_check.is_verified_with(&state));
+        assert_eq!(pin_check.start_with(&state), None);
+        assert!(pin_check.is_verified_with(&state));
+        assert_eq!(pin_check.verify_with(&mut state, "anything"), PinResult::NoChallenge);
+    }
+}
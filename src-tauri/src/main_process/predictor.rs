@@ -0,0 +1,488 @@
+// Smoothing of the relative mouse moves from the browser. The browser sends
+// moves at its own rate (often 60/s) and the network adds jitter, so the
+// desktop can add moves of its own in between.
+//
+// A predictor gets each real move in on_move and returns what to move right
+// away, and optionally how long to sleep before on_step is called. on_step can
+// ask for another sleep, up to the next real move. The messages are handled
+// one at a time, so all steps should be done within the average interval.
+//
+//   halfStep     moves to the real position, then half of the same move again
+//                after half of the average interval (the forecast is taken
+//                back on the next move)
+//   none         moves to the real position only
+//   linear       like halfStep, but the forecast extrapolates the velocity of
+//                the last two moves, timed by their arrival
+//   oneEuro      one-euro filter on the position, no extra moves
+//   interpolate  spreads each move evenly at rateHz
+//
+// Each session builds its own predictor from the default kind (PREDICTOR_ENV,
+// --predictor or the window) when it is made.
+
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+
+pub const PREDICTOR_ENV: &str = "LINKMOUSE_PREDICTOR";
+const INTERPOLATE_MAX_STEPS: u128 = 16;
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PredictorKind {
+    #[default]
+    HalfStep,
+    None,
+    Linear,
+    OneEuro { minCutoff: f64, beta: f64 },
+    Interpolate { rateHz: f64 },
+}
+
+// Timing of a real move, in nanoseconds
+pub struct MoveTiming {
+    pub interval: Option<u128>, // None for the first move after mouseidle
+    pub average_interval: u128,
+    pub irregular: bool, // Much faster or slower than the average, e.g. after a lag
+}
+
+pub struct Step {
+    pub x: i32,
+    pub y: i32,
+    pub next_sleep: Option<u128>,
+}
+
+impl Step {
+    fn now(x: i32, y: i32) -> Self {
+        Step { x, y, next_sleep: None }
+    }
+}
+
+pub trait MotionPredictor: Send {
+    fn on_move(&mut self, x: i32, y: i32, timing: &MoveTiming) -> Step;
+    fn on_step(&mut self) -> Step;
+    // The mouse has stopped, returns the move that is still left to do
+    fn reset(&mut self) -> (i32, i32);
+}
+
+impl PredictorKind {
+    // "half-step", "none", "linear", "one-euro[:<minCutoff>:<beta>]" or "interpolate[:<rateHz>]"
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.split(':');
+        let name = parts.next().unwrap_or("");
+        let numbers: Vec<f64> = match parts.map(|part| part.trim().parse::<f64>()).collect() {
+            Ok(numbers) => numbers,
+            Err(_) => return Err(format!("Invalid predictor parameters: {}", value)),
+        };
+
+        let kind = match (name, numbers.as_slice()) {
+            ("half-step", []) => PredictorKind::HalfStep,
+            ("none", []) => PredictorKind::None,
+            ("linear", []) => PredictorKind::Linear,
+            ("one-euro", []) => PredictorKind::OneEuro { minCutoff: 3.0, beta: 0.01 },
+            ("one-euro", [min_cutoff, beta]) => PredictorKind::OneEuro { minCutoff: *min_cutoff, beta: *beta },
+            ("interpolate", []) => PredictorKind::Interpolate { rateHz: 240.0 },
+            ("interpolate", [rate_hz]) => PredictorKind::Interpolate { rateHz: *rate_hz },
+            _ => return Err(format!("Unknown predictor: {}, use half-step, none, linear, one-euro or interpolate", value)),
+        };
+        kind.validate()?;
+        Ok(kind)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            PredictorKind::OneEuro { minCutoff, beta } if !(*minCutoff > 0.0 && *beta >= 0.0 && minCutoff.is_finite() && beta.is_finite()) => {
+                Err("One-euro filter needs minCutoff > 0 and beta >= 0".to_string())
+            },
+            PredictorKind::Interpolate { rateHz } if !(*rateHz > 0.0 && rateHz.is_finite()) => {
+                Err("Interpolation needs rateHz > 0".to_string())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn MotionPredictor> {
+        match self {
+            PredictorKind::HalfStep => Box::new(HalfStep { forecast: (0, 0) }),
+            PredictorKind::None => Box::new(NoPrediction),
+            PredictorKind::Linear => Box::new(Linear { forecast: (0, 0), previous: None }),
+            PredictorKind::OneEuro { minCutoff, beta } => Box::new(OneEuro::new(*minCutoff, *beta)),
+            PredictorKind::Interpolate { rateHz } => Box::new(Interpolate::new(*rateHz)),
+        }
+    }
+}
+
+struct NoPrediction;
+
+impl MotionPredictor for NoPrediction {
+    fn on_move(&mut self, x: i32, y: i32, _timing: &MoveTiming) -> Step {
+        Step::now(x, y)
+    }
+
+    fn on_step(&mut self) -> Step {
+        Step::now(0, 0)
+    }
+
+    fn reset(&mut self) -> (i32, i32) {
+        (0, 0)
+    }
+}
+
+// Moves to the real position (minus the forecast already moved), then the
+// forecast after half of the average interval
+struct HalfStep {
+    forecast: (i32, i32),
+}
+
+impl MotionPredictor for HalfStep {
+    fn on_move(&mut self, x: i32, y: i32, timing: &MoveTiming) -> Step {
+        let step = Step::now(x - self.forecast.0, y - self.forecast.1);
+
+        self.forecast = (x / 2, y / 2);
+        // Keep the "wrong" position, the forecast would only make a lag worse.
+        // Nothing to sleep for without a forecast either.
+        if timing.irregular || self.forecast == (0, 0) {
+            self.forecast = (0, 0);
+            return step;
+        }
+
+        Step { next_sleep: Some(timing.average_interval / 2), ..step }
+    }
+
+    fn on_step(&mut self) -> Step {
+        Step::now(self.forecast.0, self.forecast.1)
+    }
+
+    fn reset(&mut self) -> (i32, i32) {
+        self.forecast = (0, 0);
+        (0, 0)
+    }
+}
+
+// Extrapolates the velocity of the last two moves (distance over the time
+// between their arrivals) to half of the average interval ahead, and moves
+// there like HalfStep. Moves that arrive closer together forecast further.
+struct Linear {
+    forecast: (i32, i32),
+    previous: Option<((i32, i32), u128)>, // Move and the time since the one before
+}
+
+impl MotionPredictor for Linear {
+    fn on_move(&mut self, x: i32, y: i32, timing: &MoveTiming) -> Step {
+        let step = Step::now(x - self.forecast.0, y - self.forecast.1);
+        self.forecast = (0, 0);
+
+        // No velocity for the first move, and none worth keeping after a lag
+        let interval = match timing.interval {
+            Some(interval) if !timing.irregular && interval > 0 => interval,
+            _ => {
+                self.previous = None;
+                return step;
+            },
+        };
+        let (distance, time) = match self.previous {
+            Some(((previous_x, previous_y), previous_interval)) => ((x + previous_x, y + previous_y), interval + previous_interval),
+            None => ((x, y), interval),
+        };
+        self.previous = Some(((x, y), interval));
+
+        let lead = (timing.average_interval / 2) as f64 / time as f64;
+        self.forecast = ((distance.0 as f64 * lead).round() as i32, (distance.1 as f64 * lead).round() as i32);
+        if self.forecast == (0, 0) {
+            return step;
+        }
+        Step { next_sleep: Some(timing.average_interval / 2), ..step }
+    }
+
+    fn on_step(&mut self) -> Step {
+        Step::now(self.forecast.0, self.forecast.1)
+    }
+
+    fn reset(&mut self) -> (i32, i32) {
+        self.forecast = (0, 0);
+        self.previous = None;
+        (0, 0)
+    }
+}
+
+// https://gery.casiez.net/1euro/
+struct OneEuro {
+    min_cutoff: f64,
+    beta: f64,
+    position: (f64, f64), // Sum of the real moves
+    filtered: Option<((f64, f64), (f64, f64))>, // Position and speed
+    moved: (i32, i32), // Sum of the moves done
+}
+
+const ONE_EURO_DERIVATIVE_CUTOFF: f64 = 1.0;
+
+fn smoothing_factor(cutoff: f64, interval_seconds: f64) -> f64 {
+    let tau = 1.0 / (2.0 * PI * cutoff);
+    1.0 / (1.0 + tau / interval_seconds)
+}
+
+impl OneEuro {
+    fn new(min_cutoff: f64, beta: f64) -> Self {
+        OneEuro { min_cutoff, beta, position: (0.0, 0.0), filtered: None, moved: (0, 0) }
+    }
+
+    fn filter_axis(&self, value: f64, previous: f64, previous_speed: f64, interval_seconds: f64) -> (f64, f64) {
+        let speed = (value - previous) / interval_seconds;
+        let alpha_speed = smoothing_factor(ONE_EURO_DERIVATIVE_CUTOFF, interval_seconds);
+        let speed = previous_speed + alpha_speed * (speed - previous_speed);
+
+        let cutoff = self.min_cutoff + self.beta * speed.abs();
+        let alpha = smoothing_factor(cutoff, interval_seconds);
+        (previous + alpha * (value - previous), speed)
+    }
+}
+
+impl MotionPredictor for OneEuro {
+    fn on_move(&mut self, x: i32, y: i32, timing: &MoveTiming) -> Step {
+        self.position.0 += x as f64;
+        self.position.1 += y as f64;
+
+        let interval = timing.interval.unwrap_or(timing.average_interval).max(1);
+        let interval_seconds = interval as f64 / 1_000_000_000.0;
+        let filtered = match self.filtered {
+            Some(((previous_x, previous_y), (speed_x, speed_y))) => {
+                let (filtered_x, speed_x) = self.filter_axis(self.position.0, previous_x, speed_x, interval_seconds);
+                let (filtered_y, speed_y) = self.filter_axis(self.position.1, previous_y, speed_y, interval_seconds);
+                ((filtered_x, filtered_y), (speed_x, speed_y))
+            },
+            None => (self.position, (0.0, 0.0)),
+        };
+        self.filtered = Some(filtered);
+
+        let target = (filtered.0.0.round() as i32, filtered.0.1.round() as i32);
+        let step = Step::now(target.0 - self.moved.0, target.1 - self.moved.1);
+        self.moved = target;
+        step
+    }
+
+    fn on_step(&mut self) -> Step {
+        Step::now(0, 0)
+    }
+
+    // Catch up with the real position
+    fn reset(&mut self) -> (i32, i32) {
+        let left = (self.position.0 as i32 - self.moved.0, self.position.1 as i32 - self.moved.1);
+        self.position = (0.0, 0.0);
+        self.filtered = None;
+        self.moved = (0, 0);
+        left
+    }
+}
+
+// Splits each move into steps at rate_hz, the first one right away
+struct Interpolate {
+    rate_hz: f64,
+    target: (i32, i32),
+    steps: u128,
+    step: u128,
+    step_interval: u128,
+}
+
+impl Interpolate {
+    fn new(rate_hz: f64) -> Self {
+        Interpolate { rate_hz, target: (0, 0), steps: 0, step: 0, step_interval: 0 }
+    }
+
+    // The part of the move done up to the given step
+    fn done_at(&self, step: u128) -> (i32, i32) {
+        let fraction = step as f64 / self.steps as f64;
+        ((self.target.0 as f64 * fraction).round() as i32, (self.target.1 as f64 * fraction).round() as i32)
+    }
+
+    fn next(&mut self) -> Step {
+        let before = self.done_at(self.step);
+        self.step += 1;
+        let after = self.done_at(self.step);
+        let next_sleep = match self.step < self.steps {
+            true => Some(self.step_interval),
+            false => None,
+        };
+        Step { x: after.0 - before.0, y: after.1 - before.1, next_sleep }
+    }
+}
+
+impl MotionPredictor for Interpolate {
+    fn on_move(&mut self, x: i32, y: i32, timing: &MoveTiming) -> Step {
+        // Whatever was left of the previous move is done now
+        let left = match self.steps {
+            0 => (0, 0),
+            _ => {
+                let done = self.done_at(self.step);
+                (self.target.0 - done.0, self.target.1 - done.1)
+            },
+        };
+
+        let steps = (timing.average_interval as f64 * self.rate_hz / 1_000_000_000.0) as u128;
+        let steps = steps.clamp(1, INTERPOLATE_MAX_STEPS);
+        if timing.irregular || steps == 1 {
+            self.steps = 0;
+            return Step::now(left.0 + x, left.1 + y);
+        }
+
+        self.target = (x, y);
+        self.steps = steps;
+        self.step = 0;
+        self.step_interval = timing.average_interval / steps;
+        let step = self.next();
+        Step { x: step.x + left.0, y: step.y + left.1, ..step }
+    }
+
+    fn on_step(&mut self) -> Step {
+        match self.step < self.steps {
+            true => self.next(),
+            false => Step::now(0, 0),
+        }
+    }
+
+    fn reset(&mut self) -> (i32, i32) {
+        let left = match self.steps {
+            0 => (0, 0),
+            _ => {
+                let done = self.done_at(self.step);
+                (self.target.0 - done.0, self.target.1 - done.1)
+            },
+        };
+        self.steps = 0;
+        left
+    }
+}
+
+lazy_static! {
+    static ref DEFAULT_PREDICTOR_KIND: Arc<Mutex<PredictorKind>> = Arc::new(Mutex::new(PredictorKind::default()));
+}
+
+pub fn get_default_predictor_kind() -> PredictorKind {
+    DEFAULT_PREDICTOR_KIND.lock().unwrap().clone()
+}
+
+// Each session builds its own predictor from the default when it is made
+pub fn set_default_predictor_kind(kind: PredictorKind) -> Result<(), String> {
+    kind.validate()?;
+    *DEFAULT_PREDICTOR_KIND.lock().unwrap() = kind;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: u128 = 16_000_000;
+
+    fn timing(interval: Option<u128>) -> MoveTiming {
+        MoveTiming { interval, average_interval: INTERVAL, irregular: false }
+    }
+
+    // What on_move and the steps after it moved
+    fn moved(predictor: &mut dyn MotionPredictor, x: i32, y: i32, timing: &MoveTiming) -> ((i32, i32), (i32, i32)) {
+        let step = predictor.on_move(x, y, timing);
+        let mut after = (0, 0);
+        let mut next_sleep = step.next_sleep;
+        while next_sleep.is_some() {
+            let step = predictor.on_step();
+            after = (after.0 + step.x, after.1 + step.y);
+            next_sleep = step.next_sleep;
+        }
+        ((step.x, step.y), after)
+    }
+
+    #[test]
+    fn half_step_forecasts_half_of_the_last_move() {
+        let mut predictor = PredictorKind::HalfStep.build();
+        assert_eq!(moved(&mut *predictor, 10, -4, &timing(None)), ((10, -4), (5, -2)));
+        assert_eq!(moved(&mut *predictor, 10, -4, &timing(Some(INTERVAL))), ((5, -2), (5, -2)));
+        assert_eq!(predictor.reset(), (0, 0));
+    }
+
+    #[test]
+    fn linear_extrapolates_the_velocity() {
+        let mut predictor = PredictorKind::Linear.build();
+        // No velocity yet
+        assert_eq!(moved(&mut *predictor, 10, 0, &timing(None)), ((10, 0), (0, 0)));
+        // 10 px per interval, half an interval ahead
+        assert_eq!(moved(&mut *predictor, 10, 0, &timing(Some(INTERVAL))), ((10, 0), (5, 0)));
+        // Faster: 20 px in 1.5 intervals, then 20 px in one
+        assert_eq!(moved(&mut *predictor, 10, 0, &timing(Some(INTERVAL / 2))), ((5, 0), (7, 0)));
+        assert_eq!(moved(&mut *predictor, 10, 0, &timing(Some(INTERVAL / 2))), ((3, 0), (10, 0)));
+    }
+
+    // Every step of a move, the first one included, with the sleep before the next
+    fn steps(predictor: &mut dyn MotionPredictor, x: i32, y: i32, timing: &MoveTiming) -> Vec<(i32, i32, Option<u128>)> {
+        let step = predictor.on_move(x, y, timing);
+        let mut steps = vec![(step.x, step.y, step.next_sleep)];
+        while steps.last().unwrap().2.is_some() {
+            let step = predictor.on_step();
+            steps.push((step.x, step.y, step.next_sleep));
+        }
+        steps
+    }
+
+    #[test]
+    fn one_euro_lags_behind_and_catches_up_on_reset() {
+        let mut predictor = PredictorKind::OneEuro { minCutoff: 3.0, beta: 0.01 }.build();
+        // Nothing to filter against yet
+        assert_eq!(steps(&mut *predictor, 10, 0, &timing(None)), vec![(10, 0, None)]);
+        // Filtered to 12.6 of the real 20, no extra steps
+        assert_eq!(steps(&mut *predictor, 10, 0, &timing(Some(INTERVAL))), vec![(3, 0, None)]);
+        assert_eq!(steps(&mut *predictor, 10, 0, &timing(Some(INTERVAL))), vec![(5, 0, None)]);
+        // The mouse stopped at 30
+        assert_eq!(predictor.reset(), (12, 0));
+        assert_eq!(steps(&mut *predictor, 0, 5, &timing(None)), vec![(0, 5, None)]);
+    }
+
+    #[test]
+    fn one_euro_follows_faster_with_a_higher_beta() {
+        let followed = |beta: f64| {
+            let mut predictor = PredictorKind::OneEuro { minCutoff: 3.0, beta }.build();
+            predictor.on_move(10, 0, &timing(None));
+            (0..5).map(|_| predictor.on_move(10, 0, &timing(Some(INTERVAL))).x).sum::<i32>()
+        };
+        assert!(followed(1.0) > followed(0.0), "{} {}", followed(1.0), followed(0.0));
+        assert!(followed(1.0) <= 50);
+    }
+
+    #[test]
+    fn interpolate_spreads_the_move_at_the_rate() {
+        // 240 Hz is 3 steps in the 16 ms between moves
+        let mut predictor = PredictorKind::Interpolate { rateHz: 240.0 }.build();
+        let step_interval = Some(INTERVAL / 3);
+        assert_eq!(steps(&mut *predictor, 9, -3, &timing(None)), vec![(3, -1, step_interval), (3, -1, step_interval), (3, -1, None)]);
+        assert_eq!(steps(&mut *predictor, 10, 0, &timing(Some(INTERVAL))), vec![(3, 0, step_interval), (4, 0, step_interval), (3, 0, None)]);
+        assert_eq!(predictor.reset(), (0, 0));
+    }
+
+    #[test]
+    fn interpolate_finishes_the_previous_move_first() {
+        let mut predictor = PredictorKind::Interpolate { rateHz: 240.0 }.build();
+        predictor.on_move(9, 0, &timing(None));
+        // The next move came before the last two steps
+        assert_eq!(predictor.on_move(3, 0, &timing(Some(INTERVAL))).x, 6 + 1);
+        assert_eq!(predictor.on_step().x, 1);
+        // After a lag the whole move is done at once
+        let lag = MoveTiming { interval: Some(INTERVAL * 5), average_interval: INTERVAL, irregular: true };
+        assert_eq!(steps(&mut *predictor, 6, 0, &lag), vec![(1 + 6, 0, None)]);
+        assert_eq!(predictor.reset(), (0, 0));
+    }
+
+    #[test]
+    fn interpolate_reset_returns_the_steps_left() {
+        let mut predictor = PredictorKind::Interpolate { rateHz: 240.0 }.build();
+        predictor.on_move(9, 6, &timing(None));
+        assert_eq!(predictor.reset(), (6, 4));
+        assert_eq!(predictor.on_step().x, 0);
+    }
+
+    #[test]
+    fn linear_drops_the_velocity_after_a_lag() {
+        let mut predictor = PredictorKind::Linear.build();
+        moved(&mut *predictor, 10, 0, &timing(None));
+        moved(&mut *predictor, 10, 0, &timing(Some(INTERVAL)));
+        let lag = MoveTiming { interval: Some(INTERVAL * 5), average_interval: INTERVAL, irregular: true };
+        assert_eq!(moved(&mut *predictor, 10, 0, &lag), ((5, 0), (0, 0)));
+        assert_eq!(moved(&mut *predictor, 10, 0, &timing(Some(INTERVAL))), ((10, 0), (5, 0)));
+    }
+}
@@ -0,0 +1,617 @@
+// Data channel protocol between the browser and the desktop.
+//
+// Every message is a text frame: the message name followed by comma separated
+// fields, for example `m,12,-3` or `keydown,KeyA,a`. The last field of a message
+// may contain commas itself (`paste,a,b,c` pastes "a,b,c").
+//
+// The high-frequency messages (`m`, `mousedown`, `mouseup` and `wheel`) can
+// also be sent as binary frames, which are cheaper to decode. The desktop
+// announces support with `binaryProtocol` in `desktopinfo`, and the browser
+// opts in with `binaryProtocol` in `browserinfo`. Text frames keep working
+// either way. Binary layout, little endian, first byte is the opcode:
+//
+//   m:         0x01, x: i16, y: i16
+//   mousedown: 0x02, button: u8
+//   mouseup:   0x03, button: u8
+//   wheel:     0x04, delta_mode: u8, x: f32, y: f32
+//
+// Clipboard contents other than text are chunked, see clipboard_formats.rs.
+//
+// Bump PROTOCOL_VERSION when the meaning of an existing message changes.
+// The version is announced to the browser in `desktopinfo`, and the browser
+// sends its own version in `browserinfo`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::main_process::paste::PasteMode;
+use crate::main_process::permissions::Permissions;
+use crate::main_process::text_input::KeyMode;
+use crate::main_process::shared_settings::{BrowserInfo, BrowserSettings, DesktopInfo};
+use crate::main_process::stats::ConnectionStats;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+// Mouse moves are the most frequent message, so the name is short
+pub const MOUSEMOVE_NAME: &str = "m";
+
+const OPCODE_MOUSEMOVE: u8 = 0x01;
+const OPCODE_MOUSEDOWN: u8 = 0x02;
+const OPCODE_MOUSEUP: u8 = 0x03;
+const OPCODE_WHEEL: u8 = 0x04;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    // Browser -> desktop
+    MouseMove { x: i32, y: i32 },
+    MouseIdle,
+    MouseDown { button: i32 },
+    MouseUp { button: i32 },
+    Wheel { delta_mode: i32, x: f64, y: f64 },
+    KeyDown { code: String, key: String },
+    KeyUp { code: String, key: String },
+    Copy,
+    Cut,
+    Paste { text: String },
+    BrowserInfo(BrowserInfo),
+    BrowserSettings(BrowserSettings),
+    ControllingStarted,
+    ControllingStopped,
+    Blur, // Browser window lost focus, held keys will not get keyup
+    PinResponse { response: String },
+    PasteMode { mode: PasteMode }, // For the rest of the session, see paste.rs
+    TextInput { text: String }, // Typed by character, see text_input.rs
+    KeyMode { mode: KeyMode }, // For the rest of the session
+    CompositionStart,
+    CompositionUpdate { text: String },
+    CompositionEnd { text: String }, // The committed text
+
+    // Both ways, with clipboard sync on (see clipboard_sync.rs)
+    Clipboard { text: String },
+    ClipboardChunk { transfer: u32, index: u32, count: u32, action: String, mime: String, data: String },
+
+    // Desktop -> browser
+    DesktopInfo(DesktopInfo),
+    CopyCut { text: String },
+    MouseRight { side_position: f64 },
+    TooFast,
+    Error { reason: String },
+    ApprovalPending,
+    ApprovalAccepted,
+    ApprovalRejected,
+    PinChallenge { challenge: String },
+    PinAccepted,
+    PinRejected { attempts_left: u32, challenge: String },
+    PinLocked,
+    Permissions(Permissions),
+    ConnectionStats(ConnectionStats), // Every STATS_INTERVAL_MS
+    ClipboardSync { enabled: bool }, // On open and when changed
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    InvalidUtf8,
+    Empty,
+    UnknownName(String),
+    MissingField { name: String, field: &'static str },
+    InvalidField { name: String, field: &'static str, value: String },
+    InvalidJson { name: String, error: String },
+    BinaryNotNegotiated,
+    UnknownOpcode(u8),
+    InvalidLength { name: &'static str, expected: usize, actual: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidUtf8 => write!(f, "message is not valid UTF-8"),
+            ParseError::Empty => write!(f, "empty message"),
+            ParseError::UnknownName(name) => write!(f, "unknown message: {}", name),
+            ParseError::MissingField { name, field } => write!(f, "{}: missing field {}", name, field),
+            ParseError::InvalidField { name, field, value } => write!(f, "{}: invalid {} '{}'", name, field, value),
+            ParseError::InvalidJson { name, error } => write!(f, "{}: invalid JSON: {}", name, error),
+            ParseError::BinaryNotNegotiated => write!(f, "binary message before binaryProtocol was enabled in browserinfo"),
+            ParseError::UnknownOpcode(opcode) => write!(f, "unknown binary opcode: {:#04x}", opcode),
+            ParseError::InvalidLength { name, expected, actual } => write!(f, "{}: expected {} bytes, got {}", name, expected, actual),
+        }
+    }
+}
+
+// Reads the comma separated fields after the message name
+struct Fields<'a> {
+    name: &'a str,
+    rest: Option<&'a str>,
+}
+
+impl<'a> Fields<'a> {
+    fn next_str(&mut self, field: &'static str) -> Result<&'a str, ParseError> {
+        let rest = match self.rest {
+            Some(rest) => rest,
+            None => return Err(ParseError::MissingField { name: self.name.to_string(), field }),
+        };
+        let (value, rest) = match rest.split_once(',') {
+            Some((value, rest)) => (value, Some(rest)),
+            None => (rest, None),
+        };
+        self.rest = rest;
+        Ok(value)
+    }
+
+    fn next<T: FromStr>(&mut self, field: &'static str) -> Result<T, ParseError> {
+        let value = self.next_str(field)?;
+        match value.parse::<T>() {
+            Ok(value) => Ok(value),
+            Err(_) => Err(ParseError::InvalidField { name: self.name.to_string(), field, value: value.to_string() }),
+        }
+    }
+
+    // NaN and infinities parse as f64, but are no distance to scroll
+    fn next_finite(&mut self, field: &'static str) -> Result<f64, ParseError> {
+        let value = self.next_str(field)?;
+        match value.parse::<f64>() {
+            Ok(number) if number.is_finite() => Ok(number),
+            _ => Err(ParseError::InvalidField { name: self.name.to_string(), field, value: value.to_string() }),
+        }
+    }
+
+    // Everything that is left, commas included
+    fn rest(&mut self, field: &'static str) -> Result<&'a str, ParseError> {
+        match self.rest.take() {
+            Some(rest) => Ok(rest),
+            None => Err(ParseError::MissingField { name: self.name.to_string(), field }),
+        }
+    }
+
+    fn json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, ParseError> {
+        let json = self.rest("json")?;
+        match serde_json::from_str(json) {
+            Ok(value) => Ok(value),
+            Err(e) => Err(ParseError::InvalidJson { name: self.name.to_string(), error: e.to_string() }),
+        }
+    }
+}
+
+impl Message {
+    pub fn parse(msg: &str) -> Result<Message, ParseError> {
+        if msg.is_empty() {
+            return Err(ParseError::Empty);
+        }
+
+        let (name, rest) = match msg.split_once(',') {
+            Some((name, rest)) => (name, Some(rest)),
+            None => (msg, None),
+        };
+        let mut fields = Fields { name, rest };
+
+        let message = match name {
+            MOUSEMOVE_NAME => Message::MouseMove { x: fields.next("x")?, y: fields.next("y")? },
+            "mouseidle" => Message::MouseIdle,
+            "mousedown" => Message::MouseDown { button: fields.next("button")? },
+            "mouseup" => Message::MouseUp { button: fields.next("button")? },
+            "wheel" => Message::Wheel {
+                delta_mode: fields.next("delta_mode")?,
+                x: fields.next_finite("x")?,
+                y: fields.next_finite("y")?,
+            },
+            "keydown" => Message::KeyDown { code: fields.next_str("code")?.to_string(), key: fields.rest("key")?.to_string() },
+            "keyup" => Message::KeyUp { code: fields.next_str("code")?.to_string(), key: fields.rest("key")?.to_string() },
+            "copy" => Message::Copy,
+            "cut" => Message::Cut,
+            "paste" => Message::Paste { text: fields.rest("text")?.to_string() },
+            "browserinfo" => Message::BrowserInfo(fields.json()?),
+            "browsersettings" => Message::BrowserSettings(fields.json()?),
+            "controllingstarted" => Message::ControllingStarted,
+            "controllingstopped" => Message::ControllingStopped,
+            "blur" => Message::Blur,
+            "pinresponse" => Message::PinResponse { response: fields.rest("response")?.to_string() },
+            "pastemode" => Message::PasteMode { mode: fields.next("mode")? },
+            "textinput" => Message::TextInput { text: fields.rest("text")?.to_string() },
+            "keymode" => Message::KeyMode { mode: fields.next("mode")? },
+            "compositionstart" => Message::CompositionStart,
+            "compositionupdate" => Message::CompositionUpdate { text: fields.rest("text").unwrap_or_default().to_string() },
+            "compositionend" => Message::CompositionEnd { text: fields.rest("text").unwrap_or_default().to_string() },
+            "clipboard" => Message::Clipboard { text: fields.rest("text")?.to_string() },
+            "clipboardchunk" => Message::ClipboardChunk {
+                transfer: fields.next("transfer")?,
+                index: fields.next("index")?,
+                count: fields.next("count")?,
+                action: fields.next_str("action")?.to_string(),
+                mime: fields.next_str("mime")?.to_string(),
+                data: fields.rest("data")?.to_string(),
+            },
+            "desktopinfo" => Message::DesktopInfo(fields.json()?),
+            "copycut" => Message::CopyCut { text: fields.rest("text")?.to_string() },
+            "mouseright" => Message::MouseRight { side_position: fields.next("side_position")? },
+            "toofast" => Message::TooFast,
+            "error" => Message::Error { reason: fields.rest("reason")?.to_string() },
+            "approvalpending" => Message::ApprovalPending,
+            "approvalaccepted" => Message::ApprovalAccepted,
+            "approvalrejected" => Message::ApprovalRejected,
+            "pinchallenge" => Message::PinChallenge { challenge: fields.rest("challenge")?.to_string() },
+            "pinaccepted" => Message::PinAccepted,
+            "pinrejected" => Message::PinRejected { attempts_left: fields.next("attempts_left")?, challenge: fields.rest("challenge")?.to_string() },
+            "pinlocked" => Message::PinLocked,
+            "permissions" => Message::Permissions(fields.json()?),
+            "connectionstats" => Message::ConnectionStats(fields.json()?),
+            "clipboardsync" => Message::ClipboardSync { enabled: fields.next("enabled")? },
+            _ => return Err(ParseError::UnknownName(name.to_string())),
+        };
+
+        Ok(message)
+    }
+
+    pub fn parse_binary(data: &[u8]) -> Result<Message, ParseError> {
+        let opcode = match data.first() {
+            Some(opcode) => *opcode,
+            None => return Err(ParseError::Empty),
+        };
+
+        let (name, expected) = match opcode {
+            OPCODE_MOUSEMOVE => (MOUSEMOVE_NAME, 5),
+            OPCODE_MOUSEDOWN => ("mousedown", 2),
+            OPCODE_MOUSEUP => ("mouseup", 2),
+            OPCODE_WHEEL => ("wheel", 10),
+            _ => return Err(ParseError::UnknownOpcode(opcode)),
+        };
+        if data.len() != expected {
+            return Err(ParseError::InvalidLength { name, expected, actual: data.len() });
+        }
+
+        let i16_at = |i: usize| i16::from_le_bytes([data[i], data[i + 1]]) as i32;
+        let f32_at = |i: usize, field: &'static str| {
+            let value = f32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+            if !value.is_finite() {
+                return Err(ParseError::InvalidField { name: name.to_string(), field, value: value.to_string() });
+            }
+            Ok(value as f64)
+        };
+
+        let message = match opcode {
+            OPCODE_MOUSEMOVE => Message::MouseMove { x: i16_at(1), y: i16_at(3) },
+            OPCODE_MOUSEDOWN => Message::MouseDown { button: data[1] as i32 },
+            OPCODE_MOUSEUP => Message::MouseUp { button: data[1] as i32 },
+            _ => Message::Wheel { delta_mode: data[1] as i32, x: f32_at(2, "x")?, y: f32_at(6, "y")? }, // OPCODE_WHEEL
+        };
+
+        Ok(message)
+    }
+
+    // None if the message has no binary form, or its values do not fit in it.
+    // Only the browser sends binary frames, this is for the tests.
+    #[cfg(test)]
+    pub fn serialize_binary(&self) -> Option<Vec<u8>> {
+        match self {
+            Message::MouseMove { x, y } => {
+                let x = i16::try_from(*x).ok()?;
+                let y = i16::try_from(*y).ok()?;
+                let mut data = vec![OPCODE_MOUSEMOVE];
+                data.extend_from_slice(&x.to_le_bytes());
+                data.extend_from_slice(&y.to_le_bytes());
+                Some(data)
+            },
+            Message::MouseDown { button } => Some(vec![OPCODE_MOUSEDOWN, u8::try_from(*button).ok()?]),
+            Message::MouseUp { button } => Some(vec![OPCODE_MOUSEUP, u8::try_from(*button).ok()?]),
+            Message::Wheel { delta_mode, x, y } => {
+                let mut data = vec![OPCODE_WHEEL, u8::try_from(*delta_mode).ok()?];
+                data.extend_from_slice(&(*x as f32).to_le_bytes());
+                data.extend_from_slice(&(*y as f32).to_le_bytes());
+                Some(data)
+            },
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::MouseMove { .. } => MOUSEMOVE_NAME,
+            Message::MouseIdle => "mouseidle",
+            Message::MouseDown { .. } => "mousedown",
+            Message::MouseUp { .. } => "mouseup",
+            Message::Wheel { .. } => "wheel",
+            Message::KeyDown { .. } => "keydown",
+            Message::KeyUp { .. } => "keyup",
+            Message::Copy => "copy",
+            Message::Cut => "cut",
+            Message::Paste { .. } => "paste",
+            Message::BrowserInfo(_) => "browserinfo",
+            Message::BrowserSettings(_) => "browsersettings",
+            Message::ControllingStarted => "controllingstarted",
+            Message::ControllingStopped => "controllingstopped",
+            Message::Blur => "blur",
+            Message::PinResponse { .. } => "pinresponse",
+            Message::PasteMode { .. } => "pastemode",
+            Message::TextInput { .. } => "textinput",
+            Message::KeyMode { .. } => "keymode",
+            Message::CompositionStart => "compositionstart",
+            Message::CompositionUpdate { .. } => "compositionupdate",
+            Message::CompositionEnd { .. } => "compositionend",
+            Message::Clipboard { .. } => "clipboard",
+            Message::ClipboardChunk { .. } => "clipboardchunk",
+            Message::DesktopInfo(_) => "desktopinfo",
+            Message::CopyCut { .. } => "copycut",
+            Message::MouseRight { .. } => "mouseright",
+            Message::TooFast => "toofast",
+            Message::Error { .. } => "error",
+            Message::ApprovalPending => "approvalpending",
+            Message::ApprovalAccepted => "approvalaccepted",
+            Message::ApprovalRejected => "approvalrejected",
+            Message::PinChallenge { .. } => "pinchallenge",
+            Message::PinAccepted => "pinaccepted",
+            Message::PinRejected { .. } => "pinrejected",
+            Message::PinLocked => "pinlocked",
+            Message::Permissions(_) => "permissions",
+            Message::ConnectionStats(_) => "connectionstats",
+            Message::ClipboardSync { .. } => "clipboardsync",
+        }
+    }
+
+    // Messages that control the desktop, as opposed to session housekeeping
+    pub fn is_input(&self) -> bool {
+        match self {
+            Message::MouseMove { .. }
+            | Message::MouseIdle
+            | Message::MouseDown { .. }
+            | Message::MouseUp { .. }
+            | Message::Wheel { .. }
+            | Message::KeyDown { .. }
+            | Message::KeyUp { .. }
+            | Message::TextInput { .. }
+            | Message::CompositionStart
+            | Message::CompositionUpdate { .. }
+            | Message::CompositionEnd { .. }
+            | Message::Copy
+            | Message::Cut
+            | Message::Paste { .. }
+            | Message::Clipboard { .. }
+            | Message::ClipboardChunk { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn serialize(&self) -> String {
+        let name = self.name();
+        match self {
+            Message::MouseMove { x, y } => format!("{},{},{}", name, x, y),
+            Message::MouseDown { button } | Message::MouseUp { button } => format!("{},{}", name, button),
+            Message::Wheel { delta_mode, x, y } => format!("{},{},{},{}", name, delta_mode, x, y),
+            Message::KeyDown { code, key } | Message::KeyUp { code, key } => format!("{},{},{}", name, code, key),
+            Message::Paste { text } | Message::CopyCut { text } | Message::Clipboard { text } => format!("{},{}", name, text),
+            Message::BrowserInfo(info) => format!("{},{}", name, serde_json::to_string(info).unwrap_or_default()),
+            Message::BrowserSettings(settings) => format!("{},{}", name, serde_json::to_string(settings).unwrap_or_default()),
+            Message::DesktopInfo(info) => format!("{},{}", name, serde_json::to_string(info).unwrap_or_default()),
+            Message::Permissions(permissions) => format!("{},{}", name, serde_json::to_string(permissions).unwrap_or_default()),
+            Message::ConnectionStats(stats) => format!("{},{}", name, serde_json::to_string(stats).unwrap_or_default()),
+            Message::ClipboardSync { enabled } => format!("{},{}", name, enabled),
+            Message::ClipboardChunk { transfer, index, count, action, mime, data } => {
+                format!("{},{},{},{},{},{},{}", name, transfer, index, count, action, mime, data)
+            },
+            Message::MouseRight { side_position } => format!("{},{}", name, side_position),
+            Message::Error { reason } => format!("{},{}", name, reason),
+            Message::PinResponse { response } => format!("{},{}", name, response),
+            Message::PasteMode { mode } => format!("{},{}", name, mode),
+            Message::TextInput { text } | Message::CompositionUpdate { text } | Message::CompositionEnd { text } => format!("{},{}", name, text),
+            Message::KeyMode { mode } => format!("{},{}", name, mode),
+            Message::PinChallenge { challenge } => format!("{},{}", name, challenge),
+            Message::PinRejected { attempts_left, challenge } => format!("{},{},{}", name, attempts_left, challenge),
+            Message::MouseIdle
+            | Message::Copy
+            | Message::Cut
+            | Message::ControllingStarted
+            | Message::ControllingStopped
+            | Message::Blur
+            | Message::CompositionStart
+            | Message::TooFast
+            | Message::ApprovalPending
+            | Message::ApprovalAccepted
+            | Message::ApprovalRejected
+            | Message::PinAccepted
+            | Message::PinLocked => name.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn browser_info() -> BrowserInfo {
+        BrowserInfo {
+            linkmouseVersion: "0.0.1".to_string(),
+            osName: "linux".to_string(),
+            browserName: "Firefox".to_string(),
+            engineName: "Gecko".to_string(),
+            protocolVersion: PROTOCOL_VERSION,
+            binaryProtocol: true,
+            richClipboard: false,
+        }
+    }
+
+    // One of every variant
+    fn all_messages() -> Vec<Message> {
+        vec![
+            Message::MouseMove { x: 12, y: -3 },
+            Message::MouseIdle,
+            Message::MouseDown { button: 0 },
+            Message::MouseUp { button: 2 },
+            Message::Wheel { delta_mode: 1, x: 0.5, y: -3.0 },
+            Message::KeyDown { code: "KeyA".to_string(), key: "a".to_string() },
+            Message::KeyUp { code: "ShiftLeft".to_string(), key: "Shift".to_string() },
+            Message::Copy,
+            Message::Cut,
+            Message::Paste { text: "hello".to_string() },
+            Message::BrowserInfo(browser_info()),
+            Message::BrowserSettings(BrowserSettings { mouseSpeed: 1.5, mouseAcceleration: true, scrollSpeed: 1.0, scrollReversed: false }),
+            Message::ControllingStarted,
+            Message::ControllingStopped,
+            Message::Blur,
+            Message::PinResponse { response: "abc123".to_string() },
+            Message::PasteMode { mode: PasteMode::Auto },
+            Message::PasteMode { mode: PasteMode::Type },
+            Message::PasteMode { mode: "Control+Shift+KeyV".parse().unwrap() },
+            Message::TextInput { text: "é".to_string() },
+            Message::KeyMode { mode: KeyMode::Character },
+            Message::CompositionStart,
+            Message::CompositionUpdate { text: "か".to_string() },
+            Message::CompositionUpdate { text: "".to_string() },
+            Message::CompositionEnd { text: "漢字".to_string() },
+            Message::Clipboard { text: "copied".to_string() },
+            Message::ClipboardChunk {
+                transfer: 7,
+                index: 0,
+                count: 2,
+                action: "paste".to_string(),
+                mime: "image/png".to_string(),
+                data: "iVBORw0KGgo=".to_string(),
+            },
+            Message::DesktopInfo(DesktopInfo {
+                linkmouseVersion: "0.0.1".to_string(),
+                osName: "macos".to_string(),
+                protocolVersion: PROTOCOL_VERSION,
+                binaryProtocol: true,
+                richClipboard: true,
+            }),
+            Message::CopyCut { text: "copied".to_string() },
+            Message::MouseRight { side_position: 0.25 },
+            Message::TooFast,
+            Message::Error { reason: "something failed".to_string() },
+            Message::ApprovalPending,
+            Message::ApprovalAccepted,
+            Message::ApprovalRejected,
+            Message::PinChallenge { challenge: "f00d".to_string() },
+            Message::PinAccepted,
+            Message::PinRejected { attempts_left: 2, challenge: "beef".to_string() },
+            Message::PinLocked,
+            Message::Permissions(Permissions::view_only()),
+            Message::ConnectionStats(ConnectionStats::default()),
+            Message::ClipboardSync { enabled: true },
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        for message in all_messages() {
+            let text = message.serialize();
+            assert!(text.starts_with(message.name()), "{}", text);
+            assert_eq!(Message::parse(&text), Ok(message), "{}", text);
+        }
+    }
+
+    #[test]
+    fn last_field_keeps_commas() {
+        let messages = [
+            Message::Paste { text: "a,b,,c,".to_string() },
+            Message::KeyDown { code: "Comma".to_string(), key: ",".to_string() },
+            Message::TextInput { text: ",".to_string() },
+            Message::CompositionEnd { text: "1,2".to_string() },
+            Message::Error { reason: "x: invalid, try again".to_string() },
+            Message::PinRejected { attempts_left: 1, challenge: "a,b".to_string() },
+        ];
+        for message in messages {
+            assert_eq!(Message::parse(&message.serialize()), Ok(message));
+        }
+        assert_eq!(Message::parse("paste,a,b,c"), Ok(Message::Paste { text: "a,b,c".to_string() }));
+        assert_eq!(Message::parse("keydown,Comma,,"), Ok(Message::KeyDown { code: "Comma".to_string(), key: ",".to_string() }));
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        assert_eq!(Message::parse(""), Err(ParseError::Empty));
+        assert_eq!(Message::parse("jump"), Err(ParseError::UnknownName("jump".to_string())));
+        assert_eq!(Message::parse("jump,1,2"), Err(ParseError::UnknownName("jump".to_string())));
+        assert_eq!(Message::parse("M,1,2"), Err(ParseError::UnknownName("M".to_string())));
+    }
+
+    #[test]
+    fn rejects_missing_fields() {
+        let missing = |name: &str, field| Err(ParseError::MissingField { name: name.to_string(), field });
+        assert_eq!(Message::parse("m"), missing("m", "x"));
+        assert_eq!(Message::parse("m,1"), missing("m", "y"));
+        assert_eq!(Message::parse("mousedown"), missing("mousedown", "button"));
+        assert_eq!(Message::parse("wheel,0,1"), missing("wheel", "y"));
+        assert_eq!(Message::parse("keydown,KeyA"), missing("keydown", "key"));
+        assert_eq!(Message::parse("paste"), missing("paste", "text"));
+        assert_eq!(Message::parse("browserinfo"), missing("browserinfo", "json"));
+        assert_eq!(Message::parse("clipboardchunk,1,0,1,paste"), missing("clipboardchunk", "mime"));
+        assert_eq!(Message::parse("pinrejected,2"), missing("pinrejected", "challenge"));
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        let invalid = |name: &str, field, value: &str| Err(ParseError::InvalidField { name: name.to_string(), field, value: value.to_string() });
+        assert_eq!(Message::parse("m,1,x"), invalid("m", "y", "x"));
+        assert_eq!(Message::parse("m,1.5,2"), invalid("m", "x", "1.5"));
+        assert_eq!(Message::parse("m,,2"), invalid("m", "x", ""));
+        assert_eq!(Message::parse("mouseup,left"), invalid("mouseup", "button", "left"));
+        assert_eq!(Message::parse("wheel,0,up,1"), invalid("wheel", "x", "up"));
+        assert_eq!(Message::parse("clipboardchunk,-1,0,1,paste,text/html,x"), invalid("clipboardchunk", "transfer", "-1"));
+        assert_eq!(Message::parse("clipboardsync,yes"), invalid("clipboardsync", "enabled", "yes"));
+        assert_eq!(Message::parse("keymode,qwerty"), invalid("keymode", "mode", "qwerty"));
+        assert_eq!(Message::parse("wheel,0,NaN,1"), invalid("wheel", "x", "NaN"));
+        assert_eq!(Message::parse("wheel,0,1,inf"), invalid("wheel", "y", "inf"));
+        assert_eq!(Message::parse("wheel,1,-infinity,0"), invalid("wheel", "x", "-infinity"));
+        assert_eq!(Message::parse("wheel,0,1e400,0"), invalid("wheel", "x", "1e400"));
+
+        match Message::parse("browsersettings,{\"mouseSpeed\":1}") {
+            Err(ParseError::InvalidJson { name, .. }) => assert_eq!(name, "browsersettings"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_binary_messages() {
+        let messages = [
+            Message::MouseMove { x: 12, y: -3 },
+            Message::MouseMove { x: i16::MIN as i32, y: i16::MAX as i32 },
+            Message::MouseDown { button: 0 },
+            Message::MouseUp { button: 255 },
+            Message::Wheel { delta_mode: 0, x: 0.5, y: -120.0 },
+            Message::Wheel { delta_mode: 1, x: 0.0, y: 3.0 },
+        ];
+        for message in messages {
+            let data = message.serialize_binary().unwrap();
+            assert_eq!(Message::parse_binary(&data), Ok(message));
+        }
+
+        assert_eq!(Message::MouseMove { x: 1, y: -2 }.serialize_binary(), Some(vec![0x01, 0x01, 0x00, 0xfe, 0xff]));
+        assert_eq!(Message::MouseDown { button: 2 }.serialize_binary(), Some(vec![0x02, 0x02]));
+        assert_eq!(Message::MouseUp { button: 2 }.serialize_binary(), Some(vec![0x03, 0x02]));
+        assert_eq!(Message::Wheel { delta_mode: 1, x: 0.0, y: 1.0 }.serialize_binary(), Some(vec![0x04, 0x01, 0, 0, 0, 0, 0, 0, 0x80, 0x3f]));
+    }
+
+    #[test]
+    fn binary_falls_back_to_text() {
+        assert_eq!(Message::MouseMove { x: 40000, y: 0 }.serialize_binary(), None);
+        assert_eq!(Message::MouseDown { button: -1 }.serialize_binary(), None);
+        assert_eq!(Message::MouseUp { button: 256 }.serialize_binary(), None);
+        assert_eq!(Message::Wheel { delta_mode: 3000, x: 0.0, y: 0.0 }.serialize_binary(), None);
+        assert_eq!(Message::MouseIdle.serialize_binary(), None);
+        assert_eq!(Message::KeyDown { code: "KeyA".to_string(), key: "a".to_string() }.serialize_binary(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_binary_frames() {
+        assert_eq!(Message::parse_binary(&[]), Err(ParseError::Empty));
+        assert_eq!(Message::parse_binary(&[0x00]), Err(ParseError::UnknownOpcode(0x00)));
+        assert_eq!(Message::parse_binary(&[0x05, 0x01]), Err(ParseError::UnknownOpcode(0x05)));
+        assert_eq!(Message::parse_binary(b"m,1,2"), Err(ParseError::UnknownOpcode(b'm')));
+
+        let invalid_length = |name, expected, actual| Err(ParseError::InvalidLength { name, expected, actual });
+        assert_eq!(Message::parse_binary(&[0x01]), invalid_length("m", 5, 1));
+        assert_eq!(Message::parse_binary(&[0x01, 0x01, 0x00, 0x02]), invalid_length("m", 5, 4));
+        assert_eq!(Message::parse_binary(&[0x01, 0x01, 0x00, 0x02, 0x00, 0x00]), invalid_length("m", 5, 6));
+        assert_eq!(Message::parse_binary(&[0x02]), invalid_length("mousedown", 2, 1));
+        assert_eq!(Message::parse_binary(&[0x03, 0x00, 0x00]), invalid_length("mouseup", 2, 3));
+        assert_eq!(Message::parse_binary(&[0x04, 0x00, 0, 0, 0, 0, 0, 0, 0x80]), invalid_length("wheel", 10, 9));
+
+        let wheel = |x: f32, y: f32| {
+            let mut data = vec![0x04, 0x00];
+            data.extend_from_slice(&x.to_le_bytes());
+            data.extend_from_slice(&y.to_le_bytes());
+            Message::parse_binary(&data)
+        };
+        let invalid = |field, value: &str| Err(ParseError::InvalidField { name: "wheel".to_string(), field, value: value.to_string() });
+        assert_eq!(wheel(f32::NAN, 0.0), invalid("x", "NaN"));
+        assert_eq!(wheel(0.0, f32::INFINITY), invalid("y", "inf"));
+        assert_eq!(wheel(f32::NEG_INFINITY, 0.0), invalid("x", "-inf"));
+        assert_eq!(wheel(f32::MAX, 0.0), Ok(Message::Wheel { delta_mode: 0, x: f32::MAX as f64, y: 0.0 }));
+    }
+}
@@ -0,0 +1,341 @@
+// Replays a mouse recording (see mouse_recording.rs) through the same
+// handle_mousemove / handle_post_sleep / handle_mouseidle as a real session,
+// on a virtual clock and into a RecordingSink, and measures the result.
+//
+// Messages are handled one at a time like on the data channel: a message that
+// arrives while the steps of the previous one are still sleeping waits for them.
+// Acceleration is turned off, the ground truth is the browser path times
+// mouseSpeed, linearly interpolated between the arrival times. Both paths are
+// sampled every SAMPLE_INTERVAL while the mouse moves:
+//
+//   error      distance between the injected and the true position
+//   overshoot  how far the injected position is ahead of the true one, in the
+//              direction of the movement
+//   jitter     standard deviation of the time between injected moves
+//   rate       injected moves per second while moving (input rate for comparison)
+//   end error  distance left at the end, under a pixel unless the predictor
+//              keeps its forecast after mouseidle (HalfStep)
+//
+// Runs on its own Session with a RecordingSink, a virtual clock and the
+// predictor and limits of the options, so a real session is not affected.
+
+use std::sync::Arc;
+
+use crate::main_process::datachannel::PostSleepData;
+use crate::main_process::input_sink::{InjectedEvent, RecordingSink};
+use crate::main_process::predictor::PredictorKind;
+use crate::main_process::protocol::Message;
+use crate::main_process::session::Session;
+use crate::main_process::{MouseTuning, MOUSE_TOO_FAST, MOUSE_TOO_SLOW};
+
+const SAMPLE_INTERVAL: u128 = 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    pub predictor: PredictorKind,
+    pub too_slow: f64, // MOUSE_TOO_SLOW
+    pub too_fast: f64, // MOUSE_TOO_FAST
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            predictor: PredictorKind::default(),
+            too_slow: MOUSE_TOO_SLOW,
+            too_fast: MOUSE_TOO_FAST,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    pub moves_in: usize,
+    pub moves_out: usize,
+    pub bursts: usize, // Movements ended by mouseidle
+    pub mean_error: f64, // Pixels
+    pub max_error: f64,
+    pub mean_overshoot: f64,
+    pub max_overshoot: f64,
+    pub jitter_ms: f64,
+    pub input_rate: f64, // Per second
+    pub output_rate: f64,
+    pub end_error: f64,
+}
+
+// One movement, from the first move to mouseidle
+struct Burst {
+    truth: Vec<(u128, f64, f64)>, // Arrival time and true position
+    outputs: Vec<(u128, i32, i32)>, // Time and injected position
+    start_output: (i32, i32),
+}
+
+impl Burst {
+    fn truth_at(&self, nanos: u128) -> ((f64, f64), (f64, f64)) {
+        let index = self.truth.iter().rposition(|(time, _, _)| *time <= nanos).unwrap_or_default();
+        let (time_0, x_0, y_0) = self.truth[index];
+        let (previous, next) = match self.truth.get(index + 1) {
+            Some(next) => ((time_0, x_0, y_0), *next),
+            None if index > 0 => (self.truth[index - 1], (time_0, x_0, y_0)),
+            None => ((time_0, x_0, y_0), (time_0, x_0, y_0)),
+        };
+        let direction = (next.1 - previous.1, next.2 - previous.2);
+
+        let position = match self.truth.get(index + 1) {
+            Some((time_1, x_1, y_1)) if *time_1 > time_0 && nanos > time_0 => {
+                let t = (nanos - time_0) as f64 / (time_1 - time_0) as f64;
+                (x_0 + (x_1 - x_0) * t, y_0 + (y_1 - y_0) * t)
+            },
+            _ => (x_0, y_0),
+        };
+        (position, direction)
+    }
+
+    fn output_at(&self, nanos: u128) -> (i32, i32) {
+        match self.outputs.iter().rev().find(|(time, _, _)| *time <= nanos) {
+            Some((_, x, y)) => (*x, *y),
+            None => self.start_output,
+        }
+    }
+
+    fn end(&self) -> u128 {
+        let last_truth = self.truth.last().map_or(0, |(time, _, _)| *time);
+        let last_output = self.outputs.last().map_or(0, |(time, _, _)| *time);
+        last_truth.max(last_output)
+    }
+}
+
+struct Replayer {
+    session: Arc<Session>, // Fresh for every replay
+    sink: Arc<RecordingSink>,
+    now: u128,
+    speed: f64,
+    truth: (f64, f64),
+    output: (i32, i32),
+    bursts: Vec<Burst>,
+    in_burst: bool,
+}
+
+impl Replayer {
+    fn set_now(&mut self, nanos: u128) {
+        self.now = nanos;
+        *self.session.replay_nanos.lock().unwrap() = Some(nanos);
+    }
+
+    // Adds what the handlers injected since the last call
+    fn collect_outputs(&mut self) {
+        for event in self.sink.take_events() {
+            if let InjectedEvent::MouseMoveRelative { x, y } = event {
+                self.output = (self.output.0 + x, self.output.1 + y);
+                if let Some(burst) = self.bursts.last_mut() {
+                    burst.outputs.push((self.now, self.output.0, self.output.1));
+                }
+            }
+        }
+    }
+
+    fn mouse_move(&mut self, arrival: u128, x: i32, y: i32) {
+        if !self.in_burst {
+            self.bursts.push(Burst { truth: vec![(arrival, self.truth.0, self.truth.1)], outputs: vec![], start_output: self.output });
+            self.in_burst = true;
+        }
+        self.truth = (self.truth.0 + x as f64 * self.speed, self.truth.1 + y as f64 * self.speed);
+        self.bursts.last_mut().unwrap().truth.push((arrival, self.truth.0, self.truth.1));
+
+        let post_sleep_data = PostSleepData {
+            name: Message::MouseMove { x, y }.name().to_string(),
+            is_right: false,
+            side_position: 0.0,
+            is_too_fast: false,
+            response: None,
+        };
+        let (mut next_sleep, post_sleep_data) = super::handle_mousemove(&self.session, x, y, post_sleep_data);
+        self.collect_outputs();
+        while let Some(sleep_amount) = next_sleep {
+            self.set_now(self.now + sleep_amount);
+            next_sleep = super::handle_post_sleep(&self.session, &post_sleep_data);
+            self.collect_outputs();
+        }
+    }
+
+    fn mouse_idle(&mut self) {
+        super::handle_mouseidle(&self.session);
+        self.collect_outputs();
+        self.in_burst = false;
+    }
+}
+
+fn replay_session(sink: Arc<RecordingSink>, options: &ReplayOptions) -> Result<Arc<Session>, String> {
+    options.predictor.validate()?;
+    let session = Session::with_input_sink(sink);
+    {
+        let mut mouse_state = session.mouse.lock().unwrap();
+        mouse_state.set_predictor(options.predictor.clone());
+        mouse_state.tuning = MouseTuning { too_slow: options.too_slow, too_fast: options.too_fast };
+    }
+    *session.replay_nanos.lock().unwrap() = Some(0);
+    session.browser_settings.lock().unwrap().mouseAcceleration = false;
+    Ok(session)
+}
+
+fn standard_deviation(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    (values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / values.len() as f64).sqrt()
+}
+
+fn measure(replayer: &Replayer, moves_in: usize) -> ReplayReport {
+    let mut report = ReplayReport { moves_in, bursts: replayer.bursts.len(), ..Default::default() };
+
+    let mut samples = 0;
+    let mut error_sum = 0.0;
+    let mut overshoot_sum = 0.0;
+    let mut intervals = vec![];
+    let mut input_nanos = 0;
+    let mut output_nanos = 0;
+    for burst in &replayer.bursts {
+        let start = burst.truth[0].0;
+        let end = burst.end();
+        // The last sample is at the end, after the last injected move
+        let mut sample_times: Vec<u128> = (start..end).step_by(SAMPLE_INTERVAL as usize).collect();
+        sample_times.push(end);
+        for nanos in sample_times {
+            let ((true_x, true_y), (direction_x, direction_y)) = burst.truth_at(nanos);
+            let (output_x, output_y) = burst.output_at(nanos);
+            let (error_x, error_y) = (output_x as f64 - true_x, output_y as f64 - true_y);
+            let error = (error_x.powi(2) + error_y.powi(2)).sqrt();
+
+            let length = (direction_x.powi(2) + direction_y.powi(2)).sqrt();
+            let overshoot = match length > 0.0 {
+                true => ((error_x * direction_x + error_y * direction_y) / length).max(0.0),
+                false => 0.0,
+            };
+
+            samples += 1;
+            error_sum += error;
+            overshoot_sum += overshoot;
+            report.max_error = report.max_error.max(error);
+            report.max_overshoot = report.max_overshoot.max(overshoot);
+        }
+
+        report.moves_out += burst.outputs.len();
+        intervals.extend(burst.outputs.windows(2).map(|pair| (pair[1].0 - pair[0].0) as f64 / 1_000_000.0));
+        input_nanos += burst.truth.last().unwrap().0 - start;
+        output_nanos += burst.outputs.last().map_or(start, |(time, _, _)| *time) - start;
+    }
+
+    if samples > 0 {
+        report.mean_error = error_sum / samples as f64;
+        report.mean_overshoot = overshoot_sum / samples as f64;
+    }
+    report.jitter_ms = standard_deviation(&intervals);
+    if input_nanos > 0 {
+        report.input_rate = moves_in as f64 / (input_nanos as f64 / 1_000_000_000.0);
+    }
+    if output_nanos > 0 {
+        report.output_rate = report.moves_out as f64 / (output_nanos as f64 / 1_000_000_000.0);
+    }
+    report.end_error = ((replayer.output.0 as f64 - replayer.truth.0).powi(2) + (replayer.output.1 as f64 - replayer.truth.1).powi(2)).sqrt();
+    report
+}
+
+pub fn replay(recording: &[(u128, Message)], options: &ReplayOptions) -> Result<ReplayReport, String> {
+    let sink = Arc::new(RecordingSink::new());
+    let session = replay_session(sink.clone(), options)?;
+    let speed = session.browser_settings.lock().unwrap().mouseSpeed;
+    let mut replayer = Replayer {
+        session,
+        sink,
+        now: 0,
+        speed,
+        truth: (0.0, 0.0),
+        output: (0, 0),
+        bursts: vec![],
+        in_burst: false,
+    };
+    let mut moves_in = 0;
+    for (arrival, message) in recording {
+        // Waits for the steps of the previous message
+        replayer.set_now(replayer.now.max(*arrival));
+        match message {
+            Message::MouseMove { x, y } => {
+                moves_in += 1;
+                replayer.mouse_move(*arrival, *x, *y);
+            },
+            Message::MouseIdle => replayer.mouse_idle(),
+            Message::BrowserSettings(browser_settings) => {
+                replayer.speed = browser_settings.mouseSpeed;
+                let mut session_settings = replayer.session.browser_settings.lock().unwrap();
+                *session_settings = browser_settings.clone();
+                session_settings.mouseAcceleration = false;
+            },
+            _ => (),
+        }
+    }
+    // A recording cut in the middle of a movement still gets the last forecast taken back
+    if replayer.in_burst {
+        replayer.mouse_idle();
+    }
+    Ok(measure(&replayer, moves_in))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u128 = 1_000_000;
+
+    // Moves of (x, 0) at the given times, then mouseidle
+    fn burst(times: &[u128], x: i32) -> Vec<(u128, Message)> {
+        let mut recording: Vec<(u128, Message)> = times.iter().map(|time| (*time, Message::MouseMove { x, y: 0 })).collect();
+        recording.push((times[times.len() - 1] + 100 * MS, Message::MouseIdle));
+        recording
+    }
+
+    fn replay_with(recording: &[(u128, Message)], predictor: PredictorKind) -> ReplayReport {
+        replay(recording, &ReplayOptions { predictor, ..Default::default() }).unwrap()
+    }
+
+    #[test]
+    fn no_prediction_lags_the_browser_path() {
+        let times: Vec<u128> = (1..=10).map(|i| i * 16 * MS).collect();
+        let report = replay_with(&burst(&times, 10), PredictorKind::None);
+        assert_eq!((report.moves_in, report.moves_out, report.bursts), (10, 10, 1));
+        // Sampled every millisecond, up to 15 ms behind a move of 10 px in 16 ms
+        assert_eq!(report.max_error, 10.0 * 15.0 / 16.0);
+        assert_eq!(report.max_overshoot, 0.0);
+        assert_eq!(report.jitter_ms, 0.0);
+        assert_eq!(report.end_error, 0.0);
+    }
+
+    #[test]
+    fn half_step_overshoots_where_the_mouse_stops() {
+        let times: Vec<u128> = (1..=10).map(|i| i * 16 * MS).collect();
+        let half_step = replay_with(&burst(&times, 10), PredictorKind::HalfStep);
+        let none = replay_with(&burst(&times, 10), PredictorKind::None);
+        // Half of the last move, mouseidle keeps the forecast
+        assert_eq!(half_step.max_overshoot, 5.0);
+        assert_eq!(half_step.end_error, 5.0);
+        assert!(half_step.mean_error < none.mean_error);
+        assert!(half_step.moves_out > none.moves_out);
+    }
+
+    #[test]
+    fn jitter_is_the_deviation_of_the_injected_intervals() {
+        let times = [10 * MS, 20 * MS, 40 * MS, 50 * MS, 70 * MS];
+        let report = replay_with(&burst(&times, 4), PredictorKind::None);
+        // Intervals of 10 and 20 ms
+        assert_eq!(report.jitter_ms, 5.0);
+        assert_eq!(report.input_rate, 5.0 / 0.06);
+    }
+
+    #[test]
+    fn bursts_are_split_by_mouseidle() {
+        let mut recording = burst(&[10 * MS, 26 * MS], 3);
+        recording.extend(burst(&[500 * MS, 516 * MS, 532 * MS], -3));
+        let report = replay_with(&recording, PredictorKind::None);
+        assert_eq!((report.moves_in, report.bursts), (5, 2));
+        assert_eq!(report.end_error, 0.0);
+    }
+}
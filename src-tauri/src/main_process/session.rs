@@ -0,0 +1,241 @@
+// State of one connection attempt: the signaling and WebRTC handles, the
+// browser and what it has been let to do, and its input state. A new Session
+// is made for every attempt, so nothing (pending ICE candidates, ICE servers,
+// forecasts, held keys, approval) leaks from the previous connection.
+// The handlers get the session they run for. CURRENT_SESSION is only for
+// callers outside of the connection, e.g. Tauri commands. A session can have
+// its own input sink and clock, so replay.rs can run next to a real one.
+//
+// The USER_CONNECTED and USER_DISCONNECTED events are made here, they carry
+// the connection type and how long the user was connected.
+//
+// A SignalingError from any of the callbacks goes through fail(), and the
+// connection loop waiting in next_error() ends the attempt.
+
+use std::collections::HashSet;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::sync::Mutex;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::main_process::approval::ApprovalState;
+use crate::main_process::clipboard_formats::ClipboardTransfers;
+use crate::main_process::clipboard_sync;
+use crate::main_process::datachannel::connection_type::IceConnectionType;
+use crate::main_process::datachannel::signaling_error::SignalingError;
+use crate::main_process::held_input::HeldInput;
+use crate::main_process::input_sink::{input_sink, InputSink};
+use crate::main_process::messages_to_fe::FrontendEvent;
+use crate::main_process::paste::PasteMode;
+use crate::main_process::permissions::{get_default_permissions, Permissions};
+use crate::main_process::pin::PinCheck;
+use crate::main_process::text_input::TextInput;
+use crate::main_process::protocol::Message;
+use crate::main_process::shared_settings::{BrowserInfo, BrowserSettings};
+use crate::main_process::stats::{ConnectionStats, InputCounters};
+use crate::main_process::MouseState;
+
+pub struct Session {
+    pub peer_connection: Mutex<Option<Arc<RTCPeerConnection>>>,
+    pub pending_candidates: Mutex<Vec<RTCIceCandidate>>, // Until the remote description is set
+    pub signaling_tx: Mutex<Option<SyncSender<String>>>, // To the websocket thread
+    pub ice_servers: Mutex<Option<Vec<RTCIceServer>>>, // From the signaling server
+    pub data_channel: std::sync::Mutex<Option<(tokio::runtime::Handle, Arc<RTCDataChannel>)>>,
+    pub disconnect_tx: std::sync::Mutex<Option<tokio::sync::mpsc::Sender<()>>>,
+    pub connection_type: std::sync::Mutex<Option<IceConnectionType>>, // Once the peer connection is connected
+    connected_at: std::sync::Mutex<Option<Instant>>, // Since USER_CONNECTED
+    pub input_stats: std::sync::Mutex<InputCounters>,
+    pub latest_stats: std::sync::Mutex<Option<ConnectionStats>>, // See stats.rs
+    pub clipboard_transfers: std::sync::Mutex<ClipboardTransfers>, // clipboardchunk from the browser
+    pub paste_mode: std::sync::Mutex<PasteMode>, // From the browser, see paste.rs
+    pub text_input: std::sync::Mutex<TextInput>, // Key mode from the browser, see text_input.rs
+    pub typing: std::sync::Mutex<()>, // Held while typing a paste in type mode, see type_text
+    pub browser_info: std::sync::Mutex<BrowserInfo>,
+    pub browser_settings: std::sync::Mutex<BrowserSettings>,
+    pub approval: std::sync::Mutex<ApprovalState>, // See approval.rs
+    pub pin_check: std::sync::Mutex<PinCheck>, // See pin.rs
+    pub held_input: std::sync::Mutex<HeldInput>, // See held_input.rs
+    pub suppressed_keys: std::sync::Mutex<HashSet<String>>, // Blocked or remapped keydowns, their keyup is dropped too
+    pub permissions: std::sync::Mutex<Permissions>, // The defaults when made, changed from the UI
+    pub reported_denials: std::sync::Mutex<HashSet<&'static str>>, // See permissions::check
+    pub clipboard_contents: std::sync::Mutex<Option<u64>>, // Fingerprint, see clipboard_sync.rs
+    error_tx: tokio::sync::mpsc::Sender<SignalingError>,
+    error_rx: Mutex<tokio::sync::mpsc::Receiver<SignalingError>>,
+    input_sink: Option<Arc<dyn InputSink>>, // Instead of the selected backend
+    pub(super) replay_nanos: std::sync::Mutex<Option<u128>>, // Virtual time while replaying
+    pub(super) mouse: std::sync::Mutex<MouseState>,
+}
+
+lazy_static! {
+    static ref CURRENT_SESSION: Arc<std::sync::Mutex<Option<Arc<Session>>>> = Arc::new(std::sync::Mutex::new(None));
+}
+
+impl Session {
+    pub fn new() -> Arc<Session> {
+        Session::build(None)
+    }
+
+    // Injects into the given sink only, e.g. a RecordingSink
+    pub fn with_input_sink(input_sink: Arc<dyn InputSink>) -> Arc<Session> {
+        Session::build(Some(input_sink))
+    }
+
+    fn build(input_sink: Option<Arc<dyn InputSink>>) -> Arc<Session> {
+        let (error_tx, error_rx) = tokio::sync::mpsc::channel(1);
+        Arc::new(Session {
+            peer_connection: Mutex::new(None),
+            pending_candidates: Mutex::new(vec![]),
+            signaling_tx: Mutex::new(None),
+            ice_servers: Mutex::new(None),
+            data_channel: std::sync::Mutex::new(None),
+            disconnect_tx: std::sync::Mutex::new(None),
+            connection_type: std::sync::Mutex::new(None),
+            connected_at: std::sync::Mutex::new(None),
+            input_stats: std::sync::Mutex::new(InputCounters::new()),
+            latest_stats: std::sync::Mutex::new(None),
+            clipboard_transfers: std::sync::Mutex::new(ClipboardTransfers::new()),
+            paste_mode: std::sync::Mutex::new(PasteMode::Auto),
+            text_input: std::sync::Mutex::new(TextInput::new()),
+            typing: std::sync::Mutex::new(()),
+            browser_info: std::sync::Mutex::new(BrowserInfo::unknown()),
+            browser_settings: std::sync::Mutex::new(BrowserSettings::default()),
+            approval: std::sync::Mutex::new(ApprovalState::Pending),
+            pin_check: std::sync::Mutex::new(PinCheck::new()),
+            held_input: std::sync::Mutex::new(HeldInput::new()),
+            suppressed_keys: std::sync::Mutex::new(HashSet::new()),
+            permissions: std::sync::Mutex::new(get_default_permissions()),
+            reported_denials: std::sync::Mutex::new(HashSet::new()),
+            clipboard_contents: std::sync::Mutex::new(None),
+            error_tx,
+            error_rx: Mutex::new(error_rx),
+            input_sink,
+            replay_nanos: std::sync::Mutex::new(None),
+            mouse: std::sync::Mutex::new(MouseState::new()),
+        })
+    }
+
+    pub fn input_sink(&self) -> Arc<dyn InputSink> {
+        match &self.input_sink {
+            Some(input_sink) => input_sink.clone(),
+            None => input_sink(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.data_channel.lock().unwrap().is_some()
+    }
+
+    // Called for each new data channel, with the auto accept and PIN settings
+    // of that moment. Returns the approval state and the PIN challenge to send.
+    pub fn start_browser(&self) -> (ApprovalState, Option<String>) {
+        let approval_state = ApprovalState::initial();
+        *self.approval.lock().unwrap() = approval_state;
+        let pin_challenge = self.pin_check.lock().unwrap().start();
+        clipboard_sync::reset(self);
+        (approval_state, pin_challenge)
+    }
+
+    // Accepted and the PIN answered, if there is one
+    pub fn is_let_in(&self) -> bool {
+        *self.approval.lock().unwrap() == ApprovalState::Accepted && self.pin_check.lock().unwrap().is_verified()
+    }
+
+    pub fn send_to_browser(&self, message: Message) {
+        let data_channel = self.data_channel.lock().unwrap().clone();
+        match data_channel {
+            Some((runtime_handle, d)) => {
+                runtime_handle.spawn(async move {
+                    if let Err(e) = d.send_text(message.serialize()).await {
+                        println!("Sending failed: {}", e);
+                    }
+                });
+            },
+            None => println!("Could not send {}, no data channel", message.name()),
+        }
+    }
+
+    // Sends last_message first if there is one
+    pub fn disconnect_browser(&self, last_message: Option<Message>) {
+        let data_channel = self.data_channel.lock().unwrap().clone();
+        let disconnect_tx = self.disconnect_tx.lock().unwrap().clone();
+        let (runtime_handle, d) = match data_channel {
+            Some(data_channel) => data_channel,
+            None => {
+                println!("Could not disconnect, no data channel");
+                return;
+            },
+        };
+
+        runtime_handle.spawn(async move {
+            if let Some(message) = last_message {
+                if let Err(e) = d.send_text(message.serialize()).await {
+                    println!("Sending failed: {}", e);
+                }
+            }
+            match disconnect_tx {
+                Some(disconnect_tx) => {
+                    let _ = disconnect_tx.try_send(());
+                },
+                None => println!("Could not disconnect, no disconnect channel"),
+            }
+        });
+    }
+
+    pub fn user_connected(&self) -> FrontendEvent {
+        self.connected_at.lock().unwrap().get_or_insert_with(Instant::now);
+        FrontendEvent::UserConnected {
+            browserInfo: self.browser_info.lock().unwrap().clone(),
+            connectionType: *self.connection_type.lock().unwrap(),
+        }
+    }
+
+    pub fn user_disconnected(&self) -> FrontendEvent {
+        let connected_at = self.connected_at.lock().unwrap().take();
+        FrontendEvent::UserDisconnected {
+            sessionDurationMs: connected_at.map(|connected_at| connected_at.elapsed().as_millis() as u64),
+        }
+    }
+
+    // Ends the connection attempt, only the first error is kept
+    pub fn fail(&self, error: SignalingError) {
+        println!("Signaling failed: {}", error);
+        let _ = self.error_tx.try_send(error);
+    }
+
+    pub async fn next_error(&self) -> Option<SignalingError> {
+        self.error_rx.lock().await.recv().await
+    }
+
+    // Sends to the browser through the signaling server
+    pub async fn signal(&self, message: String) -> Result<(), String> {
+        let signaling_tx = self.signaling_tx.lock().await.clone();
+        match signaling_tx {
+            Some(signaling_tx) => match signaling_tx.send(message) {
+                Ok(()) => Ok(()),
+                Err(_) => Err("websocket thread has stopped".to_string()),
+            },
+            None => Err("not connected to the signaling server".to_string()),
+        }
+    }
+}
+
+pub fn current_session() -> Option<Arc<Session>> {
+    CURRENT_SESSION.lock().unwrap().clone()
+}
+
+pub fn set_current_session(session: Option<Arc<Session>>) {
+    *CURRENT_SESSION.lock().unwrap() = session;
+}
+
+// Clears the current session only if it is still this one
+pub fn end_session(session: &Arc<Session>) {
+    let mut current_session = CURRENT_SESSION.lock().unwrap();
+    if current_session.as_ref().is_some_and(|current| Arc::ptr_eq(current, session)) {
+        *current_session = None;
+    }
+}
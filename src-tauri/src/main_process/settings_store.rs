@@ -0,0 +1,279 @@
+// Settings that survive restarts, saved as JSON next to config.json
+// (<config dir>/linkmouse/settings.json, or the path in LINKMOUSE_SETTINGS).
+//
+// Unlike config.json this file is written by the app itself. The signaling
+// URL and the permissions are defaults, env and CLI still override them. The
+// permissions are applied at startup, the signaling URL also when changed
+// from the window (see change_stored_settings in main.rs). The acceleration
+// profile is applied when loaded or changed.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+
+use crate::main_process::acceleration::{set_profile, AccelerationProfile};
+use crate::main_process::link_id::random_id;
+use crate::main_process::permissions::Permissions;
+use crate::main_process::shared_settings::{BrowserInfo, BrowserSettings};
+
+pub const SETTINGS_ENV: &str = "LINKMOUSE_SETTINGS";
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct StoredSettings {
+    pub keepId: bool, // Keep the same link after restarts
+    pub desktopId: Option<String>,
+    pub signalingUrl: Option<String>,
+    pub permissions: Option<Permissions>,
+    pub acceleration: Option<AccelerationProfile>,
+    pub browserSettings: HashMap<String, BrowserSettings>, // By browser_identity()
+}
+
+impl StoredSettings {
+    // The fields set from the window. The stored desktop id and the settings
+    // of each browser are kept, the window does not own them.
+    pub fn apply_window_settings(&mut self, window_settings: StoredSettings) {
+        self.keepId = window_settings.keepId;
+        self.signalingUrl = window_settings.signalingUrl;
+        self.permissions = window_settings.permissions;
+        self.acceleration = window_settings.acceleration;
+    }
+}
+
+lazy_static! {
+    static ref SETTINGS: Arc<Mutex<StoredSettings>> = Arc::new(Mutex::new(StoredSettings::default()));
+    // One write at a time, so the file ends up with the latest settings
+    static ref SAVING: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
+}
+
+pub fn settings_path() -> Option<PathBuf> {
+    match env::var(SETTINGS_ENV) {
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => dirs::config_dir().map(|dir| dir.join("linkmouse").join("settings.json")),
+    }
+}
+
+// A browser has no stable id, so the same browser on the same OS shares settings
+pub fn browser_identity(browser_info: &BrowserInfo) -> String {
+    format!("{} ({}, {})", browser_info.browserName, browser_info.engineName, browser_info.osName)
+}
+
+fn save(settings: &StoredSettings) -> Result<(), String> {
+    match settings_path() {
+        Some(path) => write_settings(&path, settings),
+        None => Err("No config directory for the settings".to_string()),
+    }
+}
+
+fn write_settings(path: &Path, settings: &StoredSettings) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(format!("Could not create {}: {}", dir.display(), e));
+        }
+    }
+    match fs::write(path, serde_json::to_string_pretty(settings).unwrap()) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Could not write {}: {}", path.display(), e)),
+    }
+}
+
+// Reads the file into the current settings, a missing file gives the defaults
+pub fn load_settings() -> Result<StoredSettings, String> {
+    let path = match settings_path() {
+        Some(path) => path,
+        None => return Ok(get_settings()),
+    };
+    let settings = read_settings(&path)?;
+    if let Some(profile) = settings.acceleration.clone() {
+        if let Err(e) = set_profile(profile) {
+            println!("Invalid acceleration profile in {}: {}", path.display(), e);
+        }
+    }
+    *SETTINGS.lock().unwrap() = settings.clone();
+    Ok(settings)
+}
+
+fn read_settings(path: &Path) -> Result<StoredSettings, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => match serde_json::from_str(&text) {
+            Ok(settings) => Ok(settings),
+            Err(e) => Err(format!("Invalid settings file {}: {}", path.display(), e)),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(StoredSettings::default()),
+        Err(e) => Err(format!("Could not read {}: {}", path.display(), e)),
+    }
+}
+
+pub fn get_settings() -> StoredSettings {
+    SETTINGS.lock().unwrap().clone()
+}
+
+// Changes the current settings and saves them. SETTINGS is not held during
+// the write, readers do not wait for the disk.
+pub fn update_settings<F>(update: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut StoredSettings),
+{
+    if !apply_update(&SETTINGS, update) {
+        return Ok(());
+    }
+
+    let _saving = SAVING.lock().unwrap();
+    save(&get_settings())
+}
+
+// False if the update did not change anything, then there is nothing to save
+fn apply_update<F>(settings: &Mutex<StoredSettings>, update: F) -> bool
+    where
+        F: FnOnce(&mut StoredSettings),
+{
+    let mut settings = settings.lock().unwrap();
+    let mut new_settings = settings.clone();
+    update(&mut new_settings);
+    if new_settings == *settings {
+        return false;
+    }
+    *settings = new_settings;
+    true
+}
+
+// The stored id if the link is kept, otherwise a new one
+pub fn initial_id() -> String {
+    let settings = get_settings();
+    match (settings.keepId, settings.desktopId) {
+        (true, Some(id)) => id,
+        _ => {
+            let id = random_id();
+            remember_id(&id);
+            id
+        },
+    }
+}
+
+pub fn remember_id(id: &str) {
+    let result = update_settings(|settings| {
+        settings.desktopId = match settings.keepId {
+            true => Some(id.to_string()),
+            false => None,
+        };
+    });
+    if let Err(e) = result {
+        println!("{}", e);
+    }
+}
+
+pub fn stored_browser_settings(browser_info: &BrowserInfo) -> Option<BrowserSettings> {
+    SETTINGS.lock().unwrap().browserSettings.get(&browser_identity(browser_info)).cloned()
+}
+
+pub fn remember_browser_settings(browser_info: &BrowserInfo, browser_settings: &BrowserSettings) {
+    let result = update_settings(|settings| {
+        settings.browserSettings.insert(browser_identity(browser_info), browser_settings.clone());
+    });
+    if let Err(e) = result {
+        println!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn browser_info(browser_name: &str, os_name: &str) -> BrowserInfo {
+        BrowserInfo { browserName: browser_name.to_string(), osName: os_name.to_string(), engineName: "Blink".to_string(), ..BrowserInfo::unknown() }
+    }
+
+    fn browser_settings(mouse_speed: f64) -> BrowserSettings {
+        BrowserSettings { mouseSpeed: mouse_speed, ..BrowserSettings::default() }
+    }
+
+    // A file of its own in the temp dir, the real settings are left alone
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("linkmouse-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn browsers_are_told_apart_by_name_and_os() {
+        let chrome = browser_info("Chrome", "linux");
+        let newer_chrome = BrowserInfo { linkmouseVersion: "9.9.9".to_string(), ..chrome.clone() };
+        assert_eq!(browser_identity(&chrome), "Chrome (Blink, linux)");
+        assert_eq!(browser_identity(&chrome), browser_identity(&newer_chrome));
+        assert_ne!(browser_identity(&chrome), browser_identity(&browser_info("Chrome", "windows")));
+        assert_ne!(browser_identity(&chrome), browser_identity(&browser_info("Edge", "linux")));
+    }
+
+    #[test]
+    fn window_settings_keep_the_id_and_the_browsers() {
+        let mut stored = StoredSettings {
+            keepId: true,
+            desktopId: Some("abc".to_string()),
+            browserSettings: HashMap::from([("Chrome (Blink, linux)".to_string(), browser_settings(2.0))]),
+            ..StoredSettings::default()
+        };
+        let window_settings = StoredSettings {
+            keepId: true,
+            signalingUrl: Some("wss://example.com".to_string()),
+            permissions: Some(Permissions::view_only()),
+            ..StoredSettings::default()
+        };
+        stored.apply_window_settings(window_settings);
+        assert_eq!(stored.desktopId.as_deref(), Some("abc"));
+        assert_eq!(stored.browserSettings.len(), 1);
+        assert_eq!(stored.signalingUrl.as_deref(), Some("wss://example.com"));
+        assert_eq!(stored.permissions, Some(Permissions::view_only()));
+    }
+
+    #[test]
+    fn updates_that_change_nothing_are_not_saved() {
+        let settings = Mutex::new(StoredSettings::default());
+        assert!(!apply_update(&settings, |_| ()));
+        assert!(!apply_update(&settings, |settings| settings.keepId = false));
+        assert!(apply_update(&settings, |settings| settings.keepId = true));
+        assert!(settings.lock().unwrap().keepId);
+        assert!(!apply_update(&settings, |settings| settings.keepId = true));
+    }
+
+    #[test]
+    fn round_trips_through_the_file() {
+        let path = temp_path("settings.json");
+        let settings = StoredSettings {
+            keepId: true,
+            desktopId: Some("abc".to_string()),
+            browserSettings: HashMap::from([("Firefox (Gecko, macos)".to_string(), browser_settings(0.5))]),
+            ..StoredSettings::default()
+        };
+        write_settings(&path, &settings).unwrap();
+        assert_eq!(read_settings(&path), Ok(settings));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn missing_or_partial_files_give_the_defaults() {
+        assert_eq!(read_settings(&temp_path("missing.json")), Ok(StoredSettings::default()));
+
+        let path = temp_path("partial.json");
+        fs::write(&path, r#"{"keepId": true}"#).unwrap();
+        assert_eq!(read_settings(&path), Ok(StoredSettings { keepId: true, ..StoredSettings::default() }));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_files_are_an_error() {
+        let path = temp_path("corrupt.json");
+        fs::write(&path, "{\"keepId\": tru").unwrap();
+        match read_settings(&path) {
+            Err(e) => assert!(e.starts_with("Invalid settings file"), "{}", e),
+            other => panic!("{:?}", other),
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
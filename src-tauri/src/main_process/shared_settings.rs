@@ -2,25 +2,38 @@ use std::sync::{Arc, Mutex};
 use std::env;
 
 use serde::{Serialize, Deserialize};
+use ts_rs::TS;
+
+use crate::main_process::protocol::PROTOCOL_VERSION;
 
 #[allow(non_snake_case)]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DesktopInfo{
     pub linkmouseVersion: String,
     pub osName: String,
+    pub protocolVersion: u32,
+    pub binaryProtocol: bool,
+    pub richClipboard: bool, // clipboardchunk, see clipboard_formats.rs
 }
 
 #[allow(non_snake_case)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
+#[ts(export, export_to = "../src/bindings/BrowserInfo.ts")]
 pub struct BrowserInfo{
     pub linkmouseVersion: String,
     pub osName: String,
     pub browserName: String,
     pub engineName: String,
+    #[serde(default)] // Browsers before the versioned protocol do not send this
+    pub protocolVersion: u32,
+    #[serde(default)]
+    pub binaryProtocol: bool,
+    #[serde(default)]
+    pub richClipboard: bool,
 }
 
 #[allow(non_snake_case)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct BrowserSettings{
     pub mouseSpeed: f64,
     pub mouseAcceleration: bool,
@@ -28,27 +41,45 @@ pub struct BrowserSettings{
     pub scrollReversed: bool,
 }
 
-lazy_static! {
-    pub static ref DESKTOP_INFO: Arc<Mutex<DesktopInfo>> = Arc::new(Mutex::new(
-        DesktopInfo {
-            linkmouseVersion: "0.0.1".to_string(),
-            osName: env::consts::OS.to_string(),
-        }
-    ));
-    pub static ref BROWSER_INFO: Arc<Mutex<BrowserInfo>> = Arc::new(Mutex::new(
+impl BrowserInfo {
+    // Nothing received from the browser yet
+    pub fn unknown() -> Self {
         BrowserInfo {
             linkmouseVersion: "unknown".to_string(),
             osName: "unknown".to_string(),
             browserName: "unknown".to_string(),
             engineName: "unknown".to_string(),
+            protocolVersion: 0,
+            binaryProtocol: false,
+            richClipboard: false,
         }
-    ));
-    pub static ref BROWSER_SETTINGS: Arc<Mutex<BrowserSettings>> = Arc::new(Mutex::new(
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        *self == BrowserInfo::unknown()
+    }
+}
+
+// Until the browser sends its own
+impl Default for BrowserSettings {
+    fn default() -> Self {
         BrowserSettings {
             mouseSpeed: 1.00,
             mouseAcceleration: true,
             scrollSpeed: 1.00,
             scrollReversed: false,
         }
+    }
+}
+
+lazy_static! {
+    pub static ref DESKTOP_INFO: Arc<Mutex<DesktopInfo>> = Arc::new(Mutex::new(
+        DesktopInfo {
+            linkmouseVersion: "0.0.1".to_string(),
+            osName: env::consts::OS.to_string(),
+            protocolVersion: PROTOCOL_VERSION,
+            binaryProtocol: true,
+            richClipboard: cfg!(feature = "desktop"), // See clipboard_formats.rs
+        }
     ));
 }
\ No newline at end of file
@@ -0,0 +1,160 @@
+// Connection quality: WebRTC stats of the peer connection and how the input
+// pipeline keeps up. Collected every STATS_INTERVAL_MS while the data channel
+// is open, shown in the UI (get_connection_stats) and sent to the browser in
+// `connectionstats`.
+//
+// The input counts are over the interval. The queue delay is how long handling
+// a message took, sleeps and mouse steps included: the data channel reads the
+// next message only after that, so it is what the following messages wait.
+
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+use ts_rs::TS;
+use webrtc::stats::StatsReportType;
+
+use crate::main_process::datachannel::connection_type::{candidate, connection_type, selected_pair, IceConnectionType};
+use crate::main_process::session::Session;
+
+pub const STATS_INTERVAL_MS: u64 = 2000;
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, TS)]
+#[ts(export, export_to = "../src/bindings/WebRtcStats.ts")]
+pub struct WebRtcStats {
+    pub roundTripTimeMs: Option<f64>,
+    pub selectedPair: Option<String>, // "local -> remote" addresses
+    pub connectionType: Option<IceConnectionType>,
+    #[ts(type = "number")]
+    pub bytesSent: u64, // Data channel payload
+    #[ts(type = "number")]
+    pub bytesReceived: u64,
+    #[ts(type = "number")]
+    pub bufferedAmount: u64, // Waiting to be sent to the browser
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, TS)]
+#[ts(export, export_to = "../src/bindings/InputStats.ts")]
+pub struct InputStats {
+    pub eventsPerSecond: f64,
+    pub mouseMovesPerSecond: f64,
+    pub tooFasts: u32, // Mouse moves compared to the rolling average interval
+    pub tooSlows: u32,
+    pub queueDelayMs: f64, // Average
+    pub maxQueueDelayMs: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, TS)]
+#[ts(export, export_to = "../src/bindings/ConnectionStats.ts")]
+pub struct ConnectionStats {
+    pub webrtc: WebRtcStats,
+    pub input: InputStats,
+}
+
+// Counted since the previous snapshot
+pub struct InputCounters {
+    since: Instant,
+    events: u32,
+    mouse_moves: u32,
+    too_fasts: u32,
+    too_slows: u32,
+    handled: u32,
+    queue_delay_total: Duration,
+    queue_delay_max: Duration,
+}
+
+impl InputCounters {
+    pub fn new() -> Self {
+        InputCounters {
+            since: Instant::now(),
+            events: 0,
+            mouse_moves: 0,
+            too_fasts: 0,
+            too_slows: 0,
+            handled: 0,
+            queue_delay_total: Duration::ZERO,
+            queue_delay_max: Duration::ZERO,
+        }
+    }
+
+    pub fn count_event(&mut self, is_mouse_move: bool) {
+        self.events += 1;
+        if is_mouse_move {
+            self.mouse_moves += 1;
+        }
+    }
+
+    pub fn count_too_fast(&mut self) {
+        self.too_fasts += 1;
+    }
+
+    pub fn count_too_slow(&mut self) {
+        self.too_slows += 1;
+    }
+
+    pub fn count_handled(&mut self, took: Duration) {
+        self.handled += 1;
+        self.queue_delay_total += took;
+        self.queue_delay_max = self.queue_delay_max.max(took);
+    }
+
+    // Starts the next interval
+    pub fn snapshot(&mut self) -> InputStats {
+        let seconds = self.since.elapsed().as_secs_f64().max(0.001);
+        let stats = InputStats {
+            eventsPerSecond: self.events as f64 / seconds,
+            mouseMovesPerSecond: self.mouse_moves as f64 / seconds,
+            tooFasts: self.too_fasts,
+            tooSlows: self.too_slows,
+            queueDelayMs: match self.handled {
+                0 => 0.0,
+                handled => self.queue_delay_total.as_secs_f64() * 1000.0 / handled as f64,
+            },
+            maxQueueDelayMs: self.queue_delay_max.as_secs_f64() * 1000.0,
+        };
+        *self = InputCounters::new();
+        stats
+    }
+}
+
+async fn collect_webrtc_stats(session: &Session) -> WebRtcStats {
+    let mut webrtc_stats = WebRtcStats::default();
+
+    let data_channel = session.data_channel.lock().unwrap().clone();
+    if let Some((_, data_channel)) = data_channel {
+        webrtc_stats.bufferedAmount = data_channel.buffered_amount().await as u64;
+    }
+
+    let peer_connection = session.peer_connection.lock().await.clone();
+    if let Some(peer_connection) = peer_connection {
+        let stats = peer_connection.get_stats().await;
+        if let Some(pair) = selected_pair(&stats) {
+            webrtc_stats.roundTripTimeMs = Some(pair.current_round_trip_time * 1000.0);
+            let address = |id: &str| match candidate(&stats, id) {
+                Some(candidate) => format!("{}:{}", candidate.ip, candidate.port),
+                None => "unknown".to_string(),
+            };
+            webrtc_stats.selectedPair = Some(format!("{} -> {}", address(&pair.local_candidate_id), address(&pair.remote_candidate_id)));
+        }
+        webrtc_stats.connectionType = connection_type(&stats);
+        for report in stats.reports.values() {
+            if let StatsReportType::DataChannel(data_channel) = report {
+                webrtc_stats.bytesSent += data_channel.bytes_sent as u64;
+                webrtc_stats.bytesReceived += data_channel.bytes_received as u64;
+            }
+        }
+    }
+
+    webrtc_stats
+}
+
+// Also kept in the session for get_connection_stats
+pub async fn collect(session: &Session) -> ConnectionStats {
+    let stats = ConnectionStats {
+        webrtc: collect_webrtc_stats(session).await,
+        input: session.input_stats.lock().unwrap().snapshot(),
+    };
+    *session.latest_stats.lock().unwrap() = Some(stats.clone());
+    stats
+}
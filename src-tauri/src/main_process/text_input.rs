@@ -0,0 +1,241 @@
+// Typing by character instead of by physical key. keydown carries the
+// physical `code`, and pressing it leaves the character to the desktop
+// layout: with another layout in the browser the user gets other characters,
+// and characters without a key on the desktop layout cannot be typed at all.
+// The browser picks the mode for its session with `keymode,<mode>`:
+//
+//   keymode,physical     the code is pressed (default)
+//   keymode,character    the `key` of printable keys is injected as text
+//
+// Keys without a single character (Enter, arrows, F1...) and shortcuts with
+// Control, Alt or Meta held are pressed in character mode too, so Ctrl+C
+// keeps working. AltGr (AltRight) counts as typing. Text that has no key at
+// all can be sent in either mode with `textinput,<text>`, see
+// InputSink::text for how it is injected. The uinput backend cannot inject
+// text: textinput gets an error back, and character mode keys are tapped.
+//
+// IMEs (Japanese, Chinese...), dead keys and emoji pickers compose the text
+// in the browser, in either mode:
+//
+//   compositionstart
+//   compositionupdate,<text>    what is being composed, not injected
+//   compositionend,<text>       the committed text, injected like textinput
+//
+// Keys are not pressed while composing, and neither are keydowns that the
+// IME takes ("Process"). In character mode dead keys ("Dead") are not pressed
+// either, the composed character comes in the next keydown or compositionend.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::main_process::held_input::HeldInput;
+
+pub const TEXT_INPUT_MAX_CHARS: usize = 1000;
+
+// Held, these make a keydown a shortcut
+const SHORTCUT_CODES: [&str; 7] = ["ControlLeft", "ControlRight", "AltLeft", "MetaLeft", "MetaRight", "OSLeft", "OSRight"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyMode {
+    Physical,
+    Character,
+}
+
+impl FromStr for KeyMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "physical" => Ok(KeyMode::Physical),
+            "character" => Ok(KeyMode::Character),
+            _ => Err(format!("Unknown key mode: {}", value)),
+        }
+    }
+}
+
+impl fmt::Display for KeyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyMode::Physical => write!(f, "physical"),
+            KeyMode::Character => write!(f, "character"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeyAction {
+    Press,
+    Type(String),
+    Skip,
+}
+
+// One per session
+pub struct TextInput {
+    pub mode: KeyMode,
+    composition: Option<String>, // Since compositionstart
+    unpressed_codes: HashSet<String>, // Typed or skipped, their keyup is not pressed either
+}
+
+fn is_character(key: &str) -> bool {
+    let mut chars = key.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => !c.is_control(),
+        _ => false,
+    }
+}
+
+fn is_shortcut(held_input: &HeldInput) -> bool {
+    !held_input.is_key_held("AltRight") && SHORTCUT_CODES.iter().any(|code| held_input.is_key_held(code))
+}
+
+impl TextInput {
+    pub fn new() -> Self {
+        TextInput { mode: KeyMode::Physical, composition: None, unpressed_codes: HashSet::new() }
+    }
+
+    pub fn keydown(&mut self, code: &str, key: &str, held_input: &HeldInput) -> KeyAction {
+        let action = if self.composition.is_some() || key == "Process" {
+            KeyAction::Skip
+        } else if self.mode != KeyMode::Character {
+            KeyAction::Press
+        } else if key == "Dead" {
+            KeyAction::Skip
+        } else if is_character(key) && !is_shortcut(held_input) {
+            KeyAction::Type(key.to_string())
+        } else {
+            KeyAction::Press
+        };
+
+        if !matches!(action, KeyAction::Press) {
+            self.unpressed_codes.insert(code.to_string());
+        }
+        action
+    }
+
+    // True if the keydown was not pressed
+    pub fn keyup(&mut self, code: &str) -> bool {
+        self.unpressed_codes.remove(code)
+    }
+
+    pub fn composition_start(&mut self) {
+        self.composition = Some(String::new());
+    }
+
+    pub fn composition_update(&mut self, text: &str) {
+        self.composition = Some(text.to_string());
+    }
+
+    // The committed text, if any
+    pub fn composition_end(&mut self, text: &str) -> Option<String> {
+        self.composition = None;
+        match text.is_empty() {
+            true => None, // Cancelled
+            false => Some(text.to_string()),
+        }
+    }
+
+    // The browser lost focus, nothing more will come for the composition
+    pub fn cancel_composition(&mut self) {
+        if let Some(text) = self.composition.take() {
+            // Not the text itself, like textinput
+            println!("Composition cancelled, {} characters", text.chars().count());
+        }
+    }
+}
+
+pub fn check_length(text: &str) -> Result<(), String> {
+    let length = text.chars().count();
+    match length > TEXT_INPUT_MAX_CHARS {
+        true => Err(format!("textinput: {} characters is over {}", length, TEXT_INPUT_MAX_CHARS)),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(codes: &[&str]) -> HeldInput {
+        let mut held_input = HeldInput::new();
+        for code in codes {
+            held_input.key_pressed(code);
+        }
+        held_input
+    }
+
+    fn character_mode() -> TextInput {
+        let mut text_input = TextInput::new();
+        text_input.mode = KeyMode::Character;
+        text_input
+    }
+
+    #[test]
+    fn physical_mode_presses_keys() {
+        let mut text_input = TextInput::new();
+        assert_eq!(text_input.keydown("KeyA", "a", &held(&[])), KeyAction::Press);
+        assert_eq!(text_input.keydown("Quote", "Dead", &held(&[])), KeyAction::Press);
+        assert!(!text_input.keyup("KeyA"));
+        assert!(!text_input.keyup("Quote"));
+    }
+
+    #[test]
+    fn character_mode_types_printable_keys() {
+        let mut text_input = character_mode();
+        assert_eq!(text_input.keydown("KeyQ", "a", &held(&[])), KeyAction::Type("a".to_string()));
+        assert_eq!(text_input.keydown("KeyE", "€", &held(&["AltRight"])), KeyAction::Type("€".to_string()));
+        assert_eq!(text_input.keydown("Enter", "Enter", &held(&[])), KeyAction::Press);
+        assert_eq!(text_input.keydown("KeyC", "c", &held(&["ControlLeft"])), KeyAction::Press);
+        assert!(text_input.keyup("KeyQ"));
+        assert!(text_input.keyup("KeyE"));
+        assert!(!text_input.keyup("Enter"));
+        assert!(!text_input.keyup("KeyC"));
+    }
+
+    #[test]
+    fn dead_keys_are_not_pressed() {
+        let mut text_input = character_mode();
+        assert_eq!(text_input.keydown("Quote", "Dead", &held(&[])), KeyAction::Skip);
+        assert!(text_input.keyup("Quote"));
+        assert_eq!(text_input.keydown("KeyE", "é", &held(&[])), KeyAction::Type("é".to_string()));
+        assert!(text_input.keyup("KeyE"));
+        // Only once
+        assert!(!text_input.keyup("Quote"));
+    }
+
+    #[test]
+    fn keys_the_ime_takes_are_not_pressed() {
+        for mut text_input in [TextInput::new(), character_mode()] {
+            assert_eq!(text_input.keydown("KeyK", "Process", &held(&[])), KeyAction::Skip);
+            assert!(text_input.keyup("KeyK"));
+
+            text_input.composition_start();
+            assert_eq!(text_input.keydown("Enter", "Enter", &held(&[])), KeyAction::Skip);
+            text_input.composition_update("かな");
+            assert_eq!(text_input.composition_end("仮名"), Some("仮名".to_string()));
+            // The keyup after compositionend
+            assert!(text_input.keyup("Enter"));
+            assert_eq!(text_input.keydown("Enter", "Enter", &held(&[])), KeyAction::Press);
+        }
+    }
+
+    #[test]
+    fn empty_or_lost_compositions_are_dropped() {
+        let mut text_input = TextInput::new();
+        text_input.composition_start();
+        text_input.composition_update("´");
+        assert_eq!(text_input.composition_end(""), None);
+        assert_eq!(text_input.keydown("KeyA", "a", &held(&[])), KeyAction::Press);
+
+        text_input.composition_start();
+        text_input.composition_update("にほ");
+        text_input.cancel_composition();
+        assert_eq!(text_input.keydown("KeyB", "b", &held(&[])), KeyAction::Press);
+    }
+
+    #[test]
+    fn text_length_is_limited() {
+        assert!(check_length(&"あ".repeat(TEXT_INPUT_MAX_CHARS)).is_ok());
+        assert!(check_length(&"a".repeat(TEXT_INPUT_MAX_CHARS + 1)).is_err());
+    }
+}
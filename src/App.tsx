@@ -5,37 +5,190 @@ import { appWindow } from '@tauri-apps/api/window'
 import { writeText } from '@tauri-apps/api/clipboard';
 
 import Pop from "./components/Pop";
-import { CONNECTING_SERVER, CONTROLLING_STARTED, CONTROLLING_STOPPED, SERVER_CONNECTED_WAITING_USER, SERVER_DISCONNECTED, USER_CONNECTED, USER_CONNECTING } from "./MessagesToFe";
+import type { BrowserInfo } from "./bindings/BrowserInfo";
+import type { ConnectionStats } from "./bindings/ConnectionStats";
+import type { FrontendEvent } from "./bindings/FrontendEvent";
+import type { IceConnectionType } from "./bindings/IceConnectionType";
 
-interface MyEvent {
-  name: string,
+const CONNECTION_TYPE_LABELS: Record<IceConnectionType, string> = {
+  host: "direct, same network",
+  prflx: "direct",
+  srflx: "direct through NAT",
+  relay: "relayed through TURN",
+};
+
+const formatDuration = (ms: number) => {
+  const minutes = Math.floor(ms / 60000);
+  const seconds = Math.floor(ms / 1000) % 60;
+  return minutes > 0 ? `${minutes} min ${seconds} s` : `${seconds} s`;
+};
+
+interface Config {
+  signalingUrl: string,
+  desktopPrefix: string,
+  browserPrefix: string,
+  linkBase: string,
+}
+
+interface Permissions {
+  mouse: boolean,
+  keyboard: boolean,
+  clipboardRead: boolean,
+  clipboardWrite: boolean,
+}
+
+const PERMISSION_LABELS: [keyof Permissions, string][] = [
+  ["mouse", "Mouse"],
+  ["keyboard", "Keyboard"],
+  ["clipboardRead", "Read clipboard"],
+  ["clipboardWrite", "Write clipboard"],
+];
+
+type AccelerationProfile =
+  | { type: "flat" }
+  | { type: "linear", gain: number, limit: number }
+  | { type: "adaptive" }
+  | { type: "custom", points: [number, number][] };
+
+// Speed (browser pixels per ms) to factor, written as "0:1, 1:1.5, 3:2.5"
+const formatPoints = (points: [number, number][]) => points.map(([speed, factor]) => `${speed}:${factor}`).join(", ");
+const parsePoints = (text: string): [number, number][] => text.split(",").filter((point) => point.trim()).map((point) => {
+  const [speed, factor] = point.split(":").map(Number);
+  return [speed, factor];
+});
+
+const DEFAULT_ACCELERATION_PROFILES: Record<AccelerationProfile["type"], AccelerationProfile> = {
+  flat: { type: "flat" },
+  linear: { type: "linear", gain: 0.5, limit: 3 },
+  adaptive: { type: "adaptive" },
+  custom: { type: "custom", points: [[0, 1], [1, 1.5], [3, 2.5]] },
+};
+
+type MotionPredictor =
+  | { type: "halfStep" }
+  | { type: "none" }
+  | { type: "linear" }
+  | { type: "oneEuro", minCutoff: number, beta: number }
+  | { type: "interpolate", rateHz: number };
+
+const DEFAULT_MOTION_PREDICTORS: Record<MotionPredictor["type"], MotionPredictor> = {
+  halfStep: { type: "halfStep" },
+  none: { type: "none" },
+  linear: { type: "linear" },
+  oneEuro: { type: "oneEuro", minCutoff: 3, beta: 0.01 },
+  interpolate: { type: "interpolate", rateHz: 240 },
+};
+
+interface StoredSettings {
+  keepId: boolean,
+  desktopId: string | null,
+  signalingUrl: string | null,
+  permissions: Permissions | null,
+  browserSettings: Record<string, unknown>,
 }
 
 function App() {
   const [name, setName] = createSignal("");
-  const [status, setStatus] = createSignal(CONNECTING_SERVER);
+  const [status, setStatus] = createSignal<FrontendEvent["name"]>("CONNECTING_SERVER");
+  const [browserInfo, setBrowserInfo] = createSignal<BrowserInfo | undefined>(undefined);
+  const [connectionType, setConnectionType] = createSignal<IceConnectionType | null>(null);
+  const [lastSessionMs, setLastSessionMs] = createSignal<number | null>(null);
+  const [connectionStats, setConnectionStats] = createSignal<ConnectionStats | null>(null);
+  const [autoAccept, setAutoAccept] = createSignal(false);
+  const [clipboardSync, setClipboardSync] = createSignal(false);
+  const [pin, setPin] = createSignal<string | null>(null);
+  const [permissions, setPermissions] = createSignal<Permissions | undefined>(undefined);
+  const [config, setConfig] = createSignal<Config | undefined>(undefined);
+  const [storedSettings, setStoredSettings] = createSignal<StoredSettings | undefined>(undefined);
+  const [settingsError, setSettingsError] = createSignal("");
+  const [acceleration, setAcceleration] = createSignal<AccelerationProfile | undefined>(undefined);
+  const [accelerationError, setAccelerationError] = createSignal("");
+  const [predictor, setPredictor] = createSignal<MotionPredictor | undefined>(undefined);
+  const [blockedKeys, setBlockedKeys] = createSignal("");
+  const [blockedKeysError, setBlockedKeysError] = createSignal("");
+  const [signalingError, setSignalingError] = createSignal<string | undefined>(undefined);
   const [unlisten, setUnlisten] = createSignal<UnlistenFn | undefined>(undefined)
 
   onMount(async () => {
+    setConfig(await invoke("get_current_config"));
     setName(await invoke("get_random_id"));
-    const unlisten_events = await listen('my_event', (event) => {
+    setAutoAccept(await invoke("get_auto_accept"));
+    setClipboardSync(await invoke("get_clipboard_sync"));
+    setPin(await invoke("get_current_pin"));
+    setBlockedKeys(await invoke("get_blocked_keys"));
+    setStoredSettings(await invoke("get_stored_settings"));
+    setAcceleration(await invoke("get_mouse_acceleration"));
+    setPredictor(await invoke("get_mouse_predictor"));
+    setPermissions(await invoke("get_session_permissions"));
+    const unlisten_events = await listen('my_event', async (event) => {
       console.log(event);
-      const payload = event.payload as MyEvent;
+      const payload = event.payload as FrontendEvent;
       console.log(payload.name);
+      if (payload.name === "SIGNALING_ERROR") {
+        // Reconnecting right away, keep the reason visible until connected
+        setSignalingError(payload.reason);
+        return;
+      }
       setStatus(payload.name);
+      if (payload.name === "USER_APPROVAL_PENDING") {
+        setBrowserInfo(payload.browserInfo);
+      }
+      if (payload.name === "USER_CONNECTED") {
+        setSignalingError(undefined);
+        setBrowserInfo(payload.browserInfo);
+        setConnectionType(payload.connectionType);
+      }
+      if (payload.name === "USER_DISCONNECTED") {
+        setLastSessionMs(payload.sessionDurationMs);
+      }
+      if (payload.name === "SESSION_LOCKED") {
+        // The link and the PIN have been changed
+        setName(await invoke("get_random_id"));
+        setPin(await invoke("get_current_pin"));
+      }
     })
     await invoke("get_latest_my_event"); // Previous might've been missed
     setUnlisten(() => unlisten_events);
   });
 
+  // Collected every 2 s by the connection
+  const statsInterval = setInterval(async () => {
+    setConnectionStats(await invoke("get_current_connection_stats"));
+  }, 2000);
+
   onCleanup(() => {
     const u = unlisten();
     if (u) { u() }
+    clearInterval(statsInterval);
   });
 
+  const changeStoredSettings = async (changes: Partial<StoredSettings>) => {
+    try {
+      await invoke("change_stored_settings", { settings: { ...storedSettings()!, ...changes } });
+      setSettingsError("");
+    } catch (error) {
+      setSettingsError(String(error));
+    }
+    setStoredSettings(await invoke("get_stored_settings"));
+    setConfig(await invoke("get_current_config"));
+    setAcceleration(await invoke("get_mouse_acceleration"));
+  };
+
+  const changeAcceleration = async (profile: AccelerationProfile) => {
+    try {
+      await invoke("change_mouse_acceleration", { profile });
+      setAccelerationError("");
+    } catch (error) {
+      setAccelerationError(String(error));
+    }
+    setAcceleration(await invoke("get_mouse_acceleration"));
+  };
+
+  const link = () => `${config()?.linkBase ?? "https://linkmou.se/"}${name()}`;
+
   return (
     <div class="container">
-      {[CONNECTING_SERVER, SERVER_CONNECTED_WAITING_USER, USER_CONNECTING].includes(status()) &&
+      {["CONNECTING_SERVER", "SERVER_CONNECTED_WAITING_USER", "USER_CONNECTING"].includes(status()) &&
         <>
           <div style={{
             "margin": "0.5rem",
@@ -44,7 +197,7 @@ function App() {
           </div>
           <Pop>
             <p onClick={() => {
-            writeText(`https://linkmou.se/${name()}`).then(
+            writeText(link()).then(
               () => {
                 /* clipboard successfully set */
               },
@@ -52,7 +205,7 @@ function App() {
                 console.log("Copy fail")
               }
             );
-          }}>{`linkmou.se/${name()}`}</p>
+          }}>{link().replace(/^https?:\/\//, "")}</p>
           </Pop>
           <button type="button" onClick={async () => {
             await invoke("change_random_id");
@@ -60,10 +213,73 @@ function App() {
           }}>
             Change link
           </button>
+          <label style={{
+            color: "grey",
+            "font-size": "12px",
+            "margin-top": "0.5rem",
+          }}>
+            <input type="checkbox" checked={autoAccept()} onChange={async (e) => {
+              await invoke("change_auto_accept", { autoAccept: e.currentTarget.checked });
+              setAutoAccept(await invoke("get_auto_accept"));
+            }} />
+            Accept connections automatically
+          </label>
+          <label style={{
+            color: "grey",
+            "font-size": "12px",
+          }}>
+            <input type="checkbox" checked={pin() !== null} onChange={async (e) => {
+              await invoke("change_pin_enabled", { enabled: e.currentTarget.checked });
+              setPin(await invoke("get_current_pin"));
+            }} />
+            Require PIN
+          </label>
+          {pin() !== null &&
+            <div style={{
+              "margin": "0.5rem",
+            }}>
+              {`PIN: ${pin()}`}
+            </div>
+          }
+          {lastSessionMs() !== null &&
+            <div style={{
+              color: "grey",
+              "font-size": "12px",
+            }}>
+              {`Last session lasted ${formatDuration(lastSessionMs()!)}`}
+            </div>
+          }
         </>
       }
 
-      {[USER_CONNECTED, CONTROLLING_STARTED, CONTROLLING_STOPPED].includes(status()) &&
+      {status() === "USER_APPROVAL_PENDING" &&
+        <>
+          <div style={{
+            "margin": "0.5rem",
+          }}>
+            {"A browser wants to control your mouse and keyboard"}
+          </div>
+          <div style={{
+            color: "grey",
+            "font-size": "12px",
+          }}>
+            {browserInfo() && browserInfo()!.browserName !== "unknown"
+            ? `${browserInfo()!.browserName} (${browserInfo()!.engineName}) on ${browserInfo()!.osName}`
+            : "Unknown browser"
+            }
+          </div>
+          <div>
+            <button type="button" onClick={() => invoke("answer_connection", { accept: true })}>
+              Accept
+            </button>
+            <button type="button" onClick={() => invoke("answer_connection", { accept: false })}>
+              Reject
+            </button>
+          </div>
+        </>
+      }
+
+      {["USER_CONNECTED", "CONTROLLING_STARTED", "CONTROLLING_STOPPED"].includes(status()) &&
         <>
           <div style={{
             "margin": "0.5rem",
@@ -74,15 +290,55 @@ function App() {
             color: "grey",
             "font-size": "12px",
           }}>
-            {status() === CONTROLLING_STARTED
+            {status() === "CONTROLLING_STARTED"
             ? "Controlling"
             : "Not controlling"
             }
+            {connectionType() && `, ${CONNECTION_TYPE_LABELS[connectionType()!]}`}
           </div>
+          {connectionStats() &&
+            <details style={{
+              color: "grey",
+              "font-size": "12px",
+            }}>
+              <summary>Connection</summary>
+              <div>
+                {`Round trip: ${connectionStats()!.webrtc.roundTripTimeMs?.toFixed(0) ?? "?"} ms`}
+                {connectionStats()!.webrtc.connectionType && `, ${CONNECTION_TYPE_LABELS[connectionStats()!.webrtc.connectionType!]}`}
+              </div>
+              {connectionStats()!.webrtc.selectedPair && <div>{connectionStats()!.webrtc.selectedPair}</div>}
+              <div>
+                {`Received ${(connectionStats()!.webrtc.bytesReceived / 1024).toFixed(0)} KiB, sent ${(connectionStats()!.webrtc.bytesSent / 1024).toFixed(0)} KiB, buffered ${connectionStats()!.webrtc.bufferedAmount} B`}
+              </div>
+              <div>
+                {`${connectionStats()!.input.eventsPerSecond.toFixed(0)} events/s (${connectionStats()!.input.mouseMovesPerSecond.toFixed(0)} moves/s), too fast ${connectionStats()!.input.tooFasts}, too slow ${connectionStats()!.input.tooSlows}`}
+              </div>
+              <div>
+                {`Queue delay ${connectionStats()!.input.queueDelayMs.toFixed(1)} ms, max ${connectionStats()!.input.maxQueueDelayMs.toFixed(1)} ms`}
+              </div>
+            </details>
+          }
         </>
       }
 
-      {status() === SERVER_DISCONNECTED &&
+      {status() === "SESSION_LOCKED" &&
+        <div style={{
+          "margin": "0.5rem",
+        }}>
+          {"Too many wrong PINs, the link and the PIN have been changed"}
+        </div>
+      }
+
+      {signalingError() &&
+        <div style={{
+          color: "grey",
+          "font-size": "12px",
+        }}>
+          {`Previous connection attempt failed: ${signalingError()}`}
+        </div>
+      }
+
+      {status() === "SERVER_DISCONNECTED" &&
         <button type="button" onClick={async () => {
           await invoke("restart_connection");
           setName(await invoke("get_random_id"));
@@ -91,12 +347,124 @@ function App() {
         </button>
       }
 
+      {permissions() &&
+        <div style={{
+          color: "grey",
+          "font-size": "12px",
+          "margin-top": "1rem",
+        }}>
+          {PERMISSION_LABELS.map(([permission, label]) =>
+            <label>
+              <input type="checkbox" checked={permissions()![permission]} onChange={async (e) => {
+                await invoke("change_session_permissions", { permissions: { ...permissions()!, [permission]: e.currentTarget.checked } });
+                setPermissions(await invoke("get_session_permissions"));
+              }} />
+              {label}
+            </label>
+          )}
+          <label>
+            <input type="checkbox" checked={clipboardSync()} onChange={async (e) => {
+              await invoke("change_clipboard_sync", { enabled: e.currentTarget.checked });
+              setClipboardSync(await invoke("get_clipboard_sync"));
+            }} />
+            Sync clipboard automatically
+          </label>
+        </div>
+      }
+
+      <details style={{
+        color: "grey",
+        "font-size": "12px",
+        "margin-top": "1rem",
+      }}>
+        <summary>Blocked keys</summary>
+        <textarea rows={6} cols={40} value={blockedKeys()} onChange={async (e) => {
+          try {
+            await invoke("change_blocked_keys", { rules: e.currentTarget.value });
+            setBlockedKeysError("");
+          } catch (error) {
+            setBlockedKeysError(String(error));
+          }
+          setBlockedKeys(await invoke("get_blocked_keys"));
+        }} />
+        <div>One chord per line, e.g. Alt+F4, or Meta+KeyL =&gt; Control+KeyL to remap</div>
+        {blockedKeysError() && <div style={{ color: "red" }}>{blockedKeysError()}</div>}
+      </details>
+
+      {acceleration() &&
+        <details style={{
+          color: "grey",
+          "font-size": "12px",
+          "margin-top": "1rem",
+        }}>
+          <summary>Mouse</summary>
+          <div>Used when acceleration is on in the browser</div>
+          <select value={acceleration()!.type} onChange={(e) => changeAcceleration(DEFAULT_ACCELERATION_PROFILES[e.currentTarget.value as AccelerationProfile["type"]])}>
+            <option value="flat">Flat</option>
+            <option value="linear">Linear</option>
+            <option value="adaptive">Adaptive</option>
+            <option value="custom">Custom</option>
+          </select>
+          {acceleration()!.type === "linear" && (() => {
+            const profile = acceleration() as { type: "linear", gain: number, limit: number };
+            return <div>
+              Gain <input type="number" step="0.1" value={profile.gain} onChange={(e) => changeAcceleration({ ...profile, gain: Number(e.currentTarget.value) })} />
+              Limit <input type="number" step="0.1" value={profile.limit} onChange={(e) => changeAcceleration({ ...profile, limit: Number(e.currentTarget.value) })} />
+            </div>;
+          })()}
+          {acceleration()!.type === "custom" &&
+            <div>
+              Speed:factor points <input type="text" value={formatPoints((acceleration() as { points: [number, number][] }).points)} onChange={(e) => changeAcceleration({ type: "custom", points: parsePoints(e.currentTarget.value) })} />
+            </div>
+          }
+          {accelerationError() && <div style={{ color: "red" }}>{accelerationError()}</div>}
+          {predictor() &&
+            <div>
+              Smoothing (this session){" "}
+              <select value={predictor()!.type} onChange={async (e) => {
+                await invoke("change_mouse_predictor", { predictor: DEFAULT_MOTION_PREDICTORS[e.currentTarget.value as MotionPredictor["type"]] });
+                setPredictor(await invoke("get_mouse_predictor"));
+              }}>
+                <option value="halfStep">Half step forecast</option>
+                <option value="none">None</option>
+                <option value="linear">Linear forecast</option>
+                <option value="oneEuro">One-euro filter</option>
+                <option value="interpolate">Interpolate</option>
+              </select>
+            </div>
+          }
+        </details>
+      }
+
+      {storedSettings() &&
+        <details style={{
+          color: "grey",
+          "font-size": "12px",
+          "margin-top": "1rem",
+        }}>
+          <summary>Saved settings</summary>
+          <label>
+            <input type="checkbox" checked={storedSettings()!.keepId} onChange={(e) => changeStoredSettings({ keepId: e.currentTarget.checked })} />
+            Keep the same link after restart
+          </label>
+          <div>
+            Signaling server (empty for default, reconnects):
+            <input type="text" value={storedSettings()!.signalingUrl ?? ""} onChange={(e) => changeStoredSettings({ signalingUrl: e.currentTarget.value.trim() || null })} />
+          </div>
+          <button type="button" onClick={() => changeStoredSettings({ permissions: permissions() ?? null })}>
+            Use the current permissions on startup
+          </button>
+          {settingsError() && <div style={{ color: "red" }}>{settingsError()}</div>}
+        </details>
+      }
+
       <div style={{
         color: "grey",
         "font-size": "10px",
         "margin-top": "1rem",
       }}>
         {status()}
+        {config() && <div>Signaling server: {config()!.signalingUrl}</div>}
       </div>
     </div>
   );
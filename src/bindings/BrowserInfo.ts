@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface BrowserInfo { linkmouseVersion: string, osName: string, browserName: string, engineName: string, protocolVersion: number, binaryProtocol: boolean, richClipboard: boolean, }
\ No newline at end of file
@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { InputStats } from "./InputStats";
+import type { WebRtcStats } from "./WebRtcStats";
+
+export interface ConnectionStats { webrtc: WebRtcStats, input: InputStats, }
\ No newline at end of file
@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { BrowserInfo } from "./BrowserInfo";
+import type { IceConnectionType } from "./IceConnectionType";
+
+export type FrontendEvent = { name: "CONNECTING_SERVER" } | { name: "SERVER_CONNECTED_WAITING_USER" } | { name: "USER_CONNECTING" } | { name: "USER_APPROVAL_PENDING", browserInfo: BrowserInfo, } | { name: "USER_CONNECTED", browserInfo: BrowserInfo, connectionType: IceConnectionType | null, } | { name: "CONTROLLING_STARTED" } | { name: "CONTROLLING_STOPPED" } | { name: "USER_DISCONNECTED", sessionDurationMs: number | null, } | { name: "SESSION_LOCKED" } | { name: "SERVER_DISCONNECTED" } | { name: "SIGNALING_ERROR", reason: string, };
\ No newline at end of file
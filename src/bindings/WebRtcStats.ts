@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { IceConnectionType } from "./IceConnectionType";
+
+export interface WebRtcStats { roundTripTimeMs: number | null, selectedPair: string | null, connectionType: IceConnectionType | null, bytesSent: number, bytesReceived: number, bufferedAmount: number, }
\ No newline at end of file